@@ -13,14 +13,17 @@
 // limitations under the License.
 
 use crate::errors::Result;
+use tremor_pipeline::ConfigMap;
 use tremor_script::Value;
 pub(crate) mod binary;
 pub(crate) mod binflux;
 pub(crate) mod csv;
 pub(crate) mod influx;
 pub(crate) mod json;
+pub(crate) mod logfmt;
 pub(crate) mod msgpack;
 pub(crate) mod null;
+pub(crate) mod prometheus;
 pub(crate) mod statsd;
 pub(crate) mod string;
 pub(crate) mod syslog;
@@ -70,6 +73,28 @@ pub trait Codec: Send + Sync {
         data: &'input mut [u8],
         ingest_ns: u64,
     ) -> Result<Option<Value<'input>>>;
+
+    /// Decode a binary into zero or more Values, this is used by sources
+    ///
+    /// The `stream` the data was received on is passed in so that codecs
+    /// can keep state across multiple buffers of the same stream, e.g. a
+    /// header row. The default implementation delegates to `decode`.
+    ///
+    /// # Errors
+    ///  * if we can't decode the data
+    fn decode_stream<'input>(
+        &mut self,
+        data: &'input mut [u8],
+        ingest_ns: u64,
+        _stream: usize,
+    ) -> Result<Vec<Value<'input>>> {
+        Ok(self.decode(data, ingest_ns)?.into_iter().collect())
+    }
+
+    /// Signals that a stream has ended, any state kept for it
+    /// can be discarded.
+    fn end_stream(&mut self, _stream: usize) {}
+
     /// Encodes a Value into a binary
     ///
     /// # Errors
@@ -99,6 +124,16 @@ pub trait Codec: Send + Sync {
 /// # Errors
 ///  * if the codec doesn't exist
 pub fn lookup(name: &str) -> Result<Box<dyn Codec>> {
+    resolve(name, &None)
+}
+
+/// Codec lookup function, passing the `codec_config` of the ramp
+/// on to codecs that are configurable
+///
+/// # Errors
+///  * if the codec doesn't exist
+///  * if the codec config is invalid
+pub fn resolve(name: &str, config: &ConfigMap) -> Result<Box<dyn Codec>> {
     match name {
         "json" => Ok(Box::new(json::Json::<json::Unsorted>::default())),
         "json-sorted" => Ok(Box::new(json::Json::<json::Sorted>::default())),
@@ -111,7 +146,9 @@ pub fn lookup(name: &str) -> Result<Box<dyn Codec>> {
         "yaml" => Ok(Box::new(yaml::Yaml {})),
         "binary" => Ok(Box::new(binary::Binary {})),
         "syslog" => Ok(Box::new(syslog::Syslog::utcnow())),
-        "csv" => Ok(Box::new(csv::Csv {})),
+        "csv" => Ok(Box::new(csv::Csv::from_config(config)?)),
+        "logfmt" => Ok(Box::new(logfmt::Logfmt {})),
+        "prometheus" => Ok(Box::new(prometheus::Prometheus {})),
         _ => Err(format!("Codec '{}' not found.", name).into()),
     }
 }
@@ -161,12 +198,23 @@ mod test {
         assert!(super::lookup("statsd").is_ok());
         assert!(super::lookup("yaml").is_ok());
         assert!(super::lookup("syslog").is_ok());
+        assert!(super::lookup("logfmt").is_ok());
+        assert!(super::lookup("prometheus").is_ok());
         assert_eq!(
             super::lookup("snot").err().unwrap().to_string(),
             "Codec 'snot' not found."
         )
     }
 
+    #[test]
+    fn decode_stream_default() -> crate::Result<()> {
+        let mut codec = super::lookup("string")?;
+        let mut data = b"snot".to_vec();
+        let decoded = codec.decode_stream(&mut data, 0, 0)?;
+        assert_eq!(decoded, vec![super::Value::from("snot")]);
+        Ok(())
+    }
+
     #[test]
     fn builtin_codec_map() {
         let map = super::builtin_codec_map();
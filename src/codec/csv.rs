@@ -11,10 +11,170 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
+
+//! The `csv` codec
+//!
+//! Without configuration every row is decoded into an array of strings.
+//! If `headers` is set the first row of each stream provides the column names,
+//! alternatively the names can be given via `columns`. In both cases rows are
+//! decoded into records.
+//!
+//! Column values can be converted to `int`, `float` or `bool` by declaring
+//! their type in `types` or by enabling `infer_types`.
+//!
+//! Records are encoded in the order given by `columns`, or ordered by key if
+//! no columns are configured. Encoding never writes a header row.
+
 use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use tremor_pipeline::DEFAULT_STREAM_ID;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl ColumnType {
+    fn convert<'input>(self, column: &str, field: &str) -> Result<Value<'input>> {
+        let value = match self {
+            Self::String => Some(Value::from(field.to_string())),
+            Self::Int => field.parse::<i64>().ok().map(Value::from),
+            Self::Float => field.parse::<f64>().ok().map(Value::from),
+            Self::Bool => field.parse::<bool>().ok().map(Value::from),
+        };
+        value.ok_or_else(|| {
+            format!(
+                "[Codec::CSV] Invalid value {:?} for column {} of type {:?}",
+                field, column, self
+            )
+            .into()
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// treat the first row of each stream as header with the column names
+    #[serde(default = "Default::default")]
+    pub headers: bool,
+    /// explicit column names, used if `headers` is not set
+    #[serde(default = "Default::default")]
+    pub columns: Vec<String>,
+    /// declared types of the columns by name
+    #[serde(default = "Default::default")]
+    pub types: HashMap<String, ColumnType>,
+    /// infer `int`, `float` and `bool` values for columns without declared type
+    #[serde(default = "Default::default")]
+    pub infer_types: bool,
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+    #[serde(default = "default_quote")]
+    pub quote: char,
+}
+
+fn default_delimiter() -> char {
+    ','
+}
+
+fn default_quote() -> char {
+    '"'
+}
+
+impl ConfigImpl for Config {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            headers: false,
+            columns: vec![],
+            types: HashMap::new(),
+            infer_types: false,
+            delimiter: default_delimiter(),
+            quote: default_quote(),
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct Csv {}
+pub struct Csv {
+    config: Config,
+    delimiter: u8,
+    quote: u8,
+    /// headers read from the first row of each stream
+    stream_headers: HashMap<usize, Vec<String>>,
+}
+
+impl Default for Csv {
+    fn default() -> Self {
+        Self {
+            config: Config::default(),
+            delimiter: b',',
+            quote: b'"',
+            stream_headers: HashMap::new(),
+        }
+    }
+}
+
+fn ascii_byte(name: &str, c: char) -> Result<u8> {
+    if c.is_ascii() {
+        Ok(c as u8)
+    } else {
+        Err(format!(
+            "[Codec::CSV] `{}` must be an ascii character, got {:?}",
+            name, c
+        )
+        .into())
+    }
+}
+
+fn infer_type<'input>(field: &str) -> Value<'input> {
+    if let Ok(i) = field.parse::<i64>() {
+        Value::from(i)
+    } else if let Some(f) = field.parse::<f64>().ok().filter(|f| f.is_finite()) {
+        Value::from(f)
+    } else if let Ok(b) = field.parse::<bool>() {
+        Value::from(b)
+    } else {
+        Value::from(field.to_string())
+    }
+}
+
+impl Csv {
+    pub fn from_config(config: &Option<serde_yaml::Value>) -> Result<Self> {
+        let config = if let Some(config) = config {
+            Config::new(config)?
+        } else {
+            Config::default()
+        };
+        Ok(Self {
+            delimiter: ascii_byte("delimiter", config.delimiter)?,
+            quote: ascii_byte("quote", config.quote)?,
+            config,
+            stream_headers: HashMap::new(),
+        })
+    }
+
+    fn convert<'input>(&self, column: Option<&str>, field: &str) -> Result<Value<'input>> {
+        match column.and_then(|c| self.config.types.get(c).map(|t| (c, t))) {
+            Some((column, t)) => t.convert(column, field),
+            None if self.config.infer_types => Ok(infer_type(field)),
+            None => Ok(Value::from(field.to_string())),
+        }
+    }
+
+    fn encode_field(value: &Value) -> String {
+        match value.as_str() {
+            Some(s) => s.to_string(),
+            None if value.is_null() => String::new(),
+            None => value.to_string(),
+        }
+    }
+}
 
 impl Codec for Csv {
     fn name(&self) -> &str {
@@ -24,47 +184,111 @@ impl Codec for Csv {
     fn decode<'input>(
         &mut self,
         data: &'input mut [u8],
-        _ingest_ns: u64,
+        ingest_ns: u64,
     ) -> Result<Option<Value<'input>>> {
+        Ok(self
+            .decode_stream(data, ingest_ns, DEFAULT_STREAM_ID as usize)?
+            .into_iter()
+            .next())
+    }
+
+    fn decode_stream<'input>(
+        &mut self,
+        data: &'input mut [u8],
+        _ingest_ns: u64,
+        stream: usize,
+    ) -> Result<Vec<Value<'input>>> {
         let mut reader = csv::ReaderBuilder::new()
             .has_headers(false)
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
             .from_reader(&*data); // the reborrow here is needed because std::io::Read is implemented only for &[u8], not &mut [u8]
 
-        let record = match reader.records().next() {
-            Some(Ok(x)) => Ok(x),
-            Some(Err(e)) => Err(e),
-            None => return Ok(None),
-        }?;
+        let mut values = vec![];
+        for record in reader.records() {
+            let record = record?;
+            if self.config.headers && !self.stream_headers.contains_key(&stream) {
+                self.stream_headers
+                    .insert(stream, record.iter().map(ToString::to_string).collect());
+                continue;
+            }
+            let names = if self.config.headers {
+                self.stream_headers.get(&stream).map(Vec::as_slice)
+            } else if self.config.columns.is_empty() {
+                None
+            } else {
+                Some(self.config.columns.as_slice())
+            };
 
-        let mut fields = vec![];
-        for field in record.iter() {
-            fields.push(Value::String(Cow::from(field.to_string())));
+            if let Some(names) = names {
+                let mut fields = Object::with_capacity(record.len());
+                for (i, field) in record.iter().enumerate() {
+                    let name = names.get(i).cloned().unwrap_or_else(|| i.to_string());
+                    let value = self.convert(Some(&name), field)?;
+                    fields.insert(Cow::from(name), value);
+                }
+                values.push(Value::from(fields));
+            } else {
+                let mut fields = Vec::with_capacity(record.len());
+                for field in record.iter() {
+                    fields.push(self.convert(None, field)?);
+                }
+                values.push(Value::Array(fields));
+            }
         }
 
-        Ok(Some(Value::Array(fields)))
+        Ok(values)
     }
 
-    fn encode(&self, data: &Value) -> Result<Vec<u8>> {
-        if let Some(values) = data.as_array() {
-            let fields: Vec<String> = values.iter().map(ToString::to_string).collect();
-
-            let mut result = vec![];
-            let mut writer = csv::Writer::from_writer(&mut result);
-            writer.write_record(&fields)?;
-            writer.flush()?;
-            drop(writer);
+    fn end_stream(&mut self, stream: usize) {
+        self.stream_headers.remove(&stream);
+    }
 
-            while result.last() == Some(&b'\n') || result.last() == Some(&b'\r') {
-                result.pop();
+    fn encode(&self, data: &Value) -> Result<Vec<u8>> {
+        let fields: Vec<String> = if let Some(values) = data.as_array() {
+            values.iter().map(Self::encode_field).collect()
+        } else if let Some(record) = data.as_object() {
+            if self.config.columns.is_empty() {
+                let mut columns: Vec<(&str, &Value)> =
+                    record.iter().map(|(k, v)| (&**k, v)).collect();
+                columns.sort_by_key(|(k, _)| *k);
+                columns
+                    .into_iter()
+                    .map(|(_, v)| Self::encode_field(v))
+                    .collect()
+            } else {
+                self.config
+                    .columns
+                    .iter()
+                    .map(|c| {
+                        data.get(c.as_str())
+                            .map_or_else(String::new, Self::encode_field)
+                    })
+                    .collect()
             }
+        } else {
+            return Err(crate::errors::ErrorKind::NotCSVSerializableValue(format!(
+                "{:?}",
+                data.value_type()
+            ))
+            .into());
+        };
+
+        let mut result = vec![];
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .from_writer(&mut result);
+        writer.write_record(&fields)?;
+        writer.flush()?;
+        drop(writer);
 
-            return Ok(result);
+        while result.last() == Some(&b'\n') || result.last() == Some(&b'\r') {
+            result.pop();
         }
 
-        Err(
-            crate::errors::ErrorKind::NotCSVSerializableValue(format!("{:?}", data.value_type()))
-                .into(),
-        )
+        Ok(result)
     }
 
     fn boxed_clone(&self) -> Box<dyn Codec> {
@@ -76,9 +300,13 @@ impl Codec for Csv {
 mod tests {
     use super::*;
 
+    fn with_config(config: &str) -> Result<Csv> {
+        Csv::from_config(&Some(serde_yaml::from_str(config)?))
+    }
+
     #[test]
     fn test_can_decode_csv() {
-        let mut codec = Csv {};
+        let mut codec = Csv::default();
         let mut data = b"a,b,c,123".to_vec();
         let result = codec.decode(&mut data, 0);
 
@@ -87,11 +315,78 @@ mod tests {
 
     #[test]
     fn test_can_encode_csv() {
-        let codec = Csv {};
+        let codec = Csv::default();
         let data = literal!(["a", "b", "c", 123]);
 
         let result = codec.encode(&data).unwrap();
 
         assert_eq!(b"a,b,c,123".to_vec(), result);
     }
+
+    #[test]
+    fn test_headers_per_stream() -> Result<()> {
+        let mut codec = with_config("headers: true\ninfer_types: true")?;
+
+        let mut data = b"name,age".to_vec();
+        assert!(codec.decode_stream(&mut data, 0, 1)?.is_empty());
+        let mut data = b"snot,42\nbadger,1.5".to_vec();
+        assert_eq!(
+            vec![
+                literal!({"name": "snot", "age": 42}),
+                literal!({"name": "badger", "age": 1.5})
+            ],
+            codec.decode_stream(&mut data, 0, 1)?
+        );
+
+        // a different stream has its own header
+        let mut data = b"a,b\ntrue,x".to_vec();
+        assert_eq!(
+            vec![literal!({"a": true, "b": "x"})],
+            codec.decode_stream(&mut data, 0, 2)?
+        );
+
+        // after the stream ended the header is read again
+        codec.end_stream(1);
+        let mut data = b"x,y".to_vec();
+        assert!(codec.decode_stream(&mut data, 0, 1)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_columns_and_types() -> Result<()> {
+        let mut codec = with_config(
+            r#"
+columns: [id, flag, value]
+types:
+  id: int
+  flag: bool
+delimiter: ";"
+"#,
+        )?;
+        let mut data = b"1;false;2".to_vec();
+        assert_eq!(
+            Some(literal!({"id": 1, "flag": false, "value": "2"})),
+            codec.decode(&mut data, 0)?
+        );
+        let mut data = b"one;false;2".to_vec();
+        assert!(codec.decode(&mut data, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_record() -> Result<()> {
+        let codec = Csv::default();
+        let data = literal!({"b": "x,y", "a": 1, "c": null});
+        assert_eq!(b"1,\"x,y\",".to_vec(), codec.encode(&data)?);
+
+        let codec = with_config("columns: [c, b, missing]\ndelimiter: \"\\t\"")?;
+        let data = literal!({"b": "snot", "c": true});
+        assert_eq!(b"true\tsnot\t".to_vec(), codec.encode(&data)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_delimiter() {
+        assert!(with_config("delimiter: ü").is_err());
+    }
 }
@@ -0,0 +1,276 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `logfmt` codec
+//!
+//! Decodes `key=value key2="quoted value" flag` into a record of strings,
+//! keys without a value are decoded as `true`.
+//!
+//! Records are encoded with their keys in sorted order, values are quoted
+//! when needed. Arrays and records nested inside the record are encoded as
+//! json strings.
+
+use super::prelude::*;
+use std::str;
+
+#[derive(Clone)]
+pub struct Logfmt {}
+
+impl Codec for Logfmt {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "logfmt"
+    }
+
+    fn decode<'input>(
+        &mut self,
+        data: &'input mut [u8],
+        _ingest_ns: u64,
+    ) -> Result<Option<Value<'input>>> {
+        decode(str::from_utf8(data)?).map(Some)
+    }
+
+    fn encode(&self, data: &Value) -> Result<Vec<u8>> {
+        encode(data).map(String::into_bytes)
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(self.clone())
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::from(ErrorKind::InvalidLogfmt(msg.to_string()))
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\r' | b'\n')
+}
+
+fn decode(data: &str) -> Result<Value> {
+    let bytes = data.as_bytes();
+    let mut m = Object::with_capacity(8);
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_space(bytes[i]) {
+            i += 1;
+            continue;
+        }
+        let key_start = i;
+        while i < bytes.len() && !is_space(bytes[i]) && bytes[i] != b'=' {
+            if bytes[i] == b'"' {
+                return Err(invalid("unexpected '\"' in key"));
+            }
+            i += 1;
+        }
+        if i == key_start {
+            return Err(invalid("missing key"));
+        }
+        let key = &data[key_start..i];
+
+        if bytes.get(i) != Some(&b'=') {
+            m.insert(key.into(), Value::from(true));
+            continue;
+        }
+        // skip the `=`
+        i += 1;
+
+        let value = if bytes.get(i) == Some(&b'"') {
+            let (value, end) = decode_quoted(data, i + 1)?;
+            i = end;
+            if i < bytes.len() && !is_space(bytes[i]) {
+                return Err(invalid("missing whitespace after quoted value"));
+            }
+            value
+        } else {
+            let value_start = i;
+            while i < bytes.len() && !is_space(bytes[i]) {
+                if bytes[i] == b'"' {
+                    return Err(invalid("unexpected '\"' in unquoted value"));
+                }
+                i += 1;
+            }
+            Value::from(&data[value_start..i])
+        };
+        m.insert(key.into(), value);
+    }
+    Ok(Value::from(m))
+}
+
+/// decodes a quoted value starting after the opening quote and
+/// returns it together with the position after the closing quote
+fn decode_quoted(data: &str, start: usize) -> Result<(Value, usize)> {
+    let bytes = data.as_bytes();
+    let mut i = start;
+    let mut unescaped: Option<String> = None;
+    let mut chunk_start = start;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let value = if let Some(mut s) = unescaped {
+                    s.push_str(&data[chunk_start..i]);
+                    Value::from(s)
+                } else {
+                    Value::from(&data[start..i])
+                };
+                return Ok((value, i + 1));
+            }
+            b'\\' => {
+                let s = unescaped.get_or_insert_with(String::new);
+                s.push_str(&data[chunk_start..i]);
+                match bytes.get(i + 1) {
+                    Some(b'"') => s.push('"'),
+                    Some(b'\\') => s.push('\\'),
+                    Some(b'n') => s.push('\n'),
+                    Some(b'r') => s.push('\r'),
+                    Some(b't') => s.push('\t'),
+                    _ => return Err(invalid("invalid escape sequence")),
+                }
+                i += 2;
+                chunk_start = i;
+            }
+            _ => i += 1,
+        }
+    }
+    Err(invalid("unterminated quoted value"))
+}
+
+fn needs_quotes(s: &str) -> bool {
+    s.is_empty()
+        || s.bytes()
+            .any(|c| c <= b' ' || c == b'=' || c == b'"' || c == b'\\')
+}
+
+fn encode_str(s: &str, r: &mut String) {
+    if !needs_quotes(s) {
+        r.push_str(s);
+        return;
+    }
+    r.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+}
+
+fn encode(value: &Value) -> Result<String> {
+    let m = value
+        .as_object()
+        .ok_or_else(|| invalid("only records can be encoded"))?;
+    let mut keys: Vec<&str> = m.keys().map(|k| &**k).collect();
+    keys.sort_unstable();
+
+    let mut r = String::new();
+    for key in keys {
+        if needs_quotes(key) {
+            return Err(invalid("keys must not contain whitespace, '=' or '\"'"));
+        }
+        if !r.is_empty() {
+            r.push(' ');
+        }
+        r.push_str(key);
+        r.push('=');
+        if let Some(v) = value.get(key) {
+            if let Some(s) = v.as_str() {
+                encode_str(s, &mut r);
+            } else if !v.is_null() {
+                encode_str(&v.encode(), &mut r);
+            }
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_value::literal;
+
+    #[test]
+    fn decode_simple() -> Result<()> {
+        let mut codec = Logfmt {};
+        let mut data = br#"level=info msg="hello \"world\"" took=1.5ms empty= debug"#.to_vec();
+        assert_eq!(
+            Some(literal!({
+                "level": "info",
+                "msg": "hello \"world\"",
+                "took": "1.5ms",
+                "empty": "",
+                "debug": true
+            })),
+            codec.decode(&mut data, 0)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut codec = Logfmt {};
+        for data in &[
+            &br#"a="unterminated"#[..],
+            br#"=value"#,
+            br#"a="b"c"#,
+            br#"a=b"c"#,
+            br#"a="\x""#,
+        ] {
+            let mut data = data.to_vec();
+            assert!(codec.decode(&mut data, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<()> {
+        let mut codec = Logfmt {};
+        let value = literal!({
+            "msg": "multi\nline \"text\"",
+            "count": 3,
+            "ok": true,
+            "nothing": null,
+            "path": "/a=b",
+            "list": [1, 2]
+        });
+        let mut encoded = codec.encode(&value)?;
+        assert_eq!(
+            r#"count=3 list=[1,2] msg="multi\nline \"text\"" nothing= ok=true path="/a=b""#,
+            str::from_utf8(&encoded)?
+        );
+        assert_eq!(
+            Some(literal!({
+                "msg": "multi\nline \"text\"",
+                "count": "3",
+                "ok": "true",
+                "nothing": "",
+                "path": "/a=b",
+                "list": "[1,2]"
+            })),
+            codec.decode(&mut encoded, 0)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn encode_invalid() {
+        let codec = Logfmt {};
+        assert!(codec.encode(&literal!("snot")).is_err());
+        assert!(codec.encode(&literal!({"snot badger": 1})).is_err());
+    }
+}
@@ -0,0 +1,435 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Prometheus text exposition format
+//!
+//! Decodes the prometheus text format into the same structure the `influx`
+//! codec uses, with one event per sample.
+//!
+//! The sample
+//!
+//! ```text
+//! http_requests_total{method="post",code="200"} 1027 1395066363000
+//! ```
+//! will be translated to the nested structure:
+//!
+//! ```json
+//! {
+//!     "measurement": "http_requests_total",
+//!     "tags": {"method": "post", "code": "200"},
+//!     "fields": {"value": 1027.0},
+//!     "timestamp": 1395066363000000000
+//! }
+//! ```
+//!
+//! Comments, `HELP` and `TYPE` lines are skipped. Samples without timestamp
+//! get the ingest time as timestamp. Timestamps before the unix epoch or too
+//! large to be represented in nanoseconds are rejected.
+//!
+//! When encoding, every field becomes a sample, the `value` field uses the
+//! measurement as name, all other fields are appended to it as `<measurement>_<field>`.
+//!
+//! ## Configuration
+//!
+//! This codec takes no configuration
+
+use super::prelude::*;
+use std::str;
+
+const VALUE: &str = "value";
+
+#[derive(Clone)]
+pub struct Prometheus {}
+
+impl Codec for Prometheus {
+    #[cfg(not(tarpaulin_include))]
+    fn name(&self) -> &str {
+        "prometheus"
+    }
+
+    fn decode<'input>(
+        &mut self,
+        data: &'input mut [u8],
+        ingest_ns: u64,
+    ) -> Result<Option<Value<'input>>> {
+        Ok(self.decode_stream(data, ingest_ns, 0)?.into_iter().next())
+    }
+
+    fn decode_stream<'input>(
+        &mut self,
+        data: &'input mut [u8],
+        ingest_ns: u64,
+        _stream: usize,
+    ) -> Result<Vec<Value<'input>>> {
+        let s: &'input str = str::from_utf8(data)?;
+        s.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| decode_sample(l, ingest_ns))
+            .collect()
+    }
+
+    fn encode(&self, data: &Value) -> Result<Vec<u8>> {
+        encode(data).map(String::into_bytes)
+    }
+
+    #[cfg(not(tarpaulin_include))]
+    fn boxed_clone(&self) -> Box<dyn Codec> {
+        Box::new(self.clone())
+    }
+}
+
+fn invalid(msg: &str, line: &str) -> Error {
+    Error::from(ErrorKind::InvalidPrometheusData(format!(
+        "{}: {}",
+        msg, line
+    )))
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(is_name_char)
+}
+
+fn parse_value(s: &str) -> Option<f64> {
+    match s {
+        "+Inf" | "Inf" => Some(f64::INFINITY),
+        "-Inf" => Some(f64::NEG_INFINITY),
+        "NaN" => Some(f64::NAN),
+        s => s.parse().ok(),
+    }
+}
+
+/// Parses the labels after the opening `{` and returns them
+/// with the remainder of the line after the closing `}`
+fn decode_labels<'input>(
+    mut rest: &'input str,
+    line: &str,
+) -> Result<(Object<'input>, &'input str)> {
+    let mut tags = Object::with_capacity(4);
+    loop {
+        rest = rest.trim_start();
+        if let Some(r) = rest.strip_prefix('}') {
+            return Ok((tags, r));
+        }
+        let name_end = rest
+            .find(|c: char| !is_name_char(c))
+            .ok_or_else(|| invalid("unterminated labels", line))?;
+        let (name, r) = rest.split_at(name_end);
+        let r = r
+            .trim_start()
+            .strip_prefix('=')
+            .and_then(|r| r.trim_start().strip_prefix('"'))
+            .ok_or_else(|| invalid("expected `=\"` after label name", line))?;
+        if name.is_empty() {
+            return Err(invalid("missing label name", line));
+        }
+        let (value, r) = decode_label_value(r, line)?;
+        tags.insert(name.into(), value);
+        rest = r.trim_start();
+        if let Some(r) = rest.strip_prefix(',') {
+            rest = r;
+        } else if !rest.starts_with('}') {
+            return Err(invalid("expected `,` or `}` after label value", line));
+        }
+    }
+}
+
+/// Parses a label value after the opening `"` and returns it
+/// with the remainder of the line after the closing `"`
+fn decode_label_value<'input>(s: &'input str, line: &str) -> Result<(Value<'input>, &'input str)> {
+    let mut unescaped: Option<String> = None;
+    let mut chars = s.char_indices();
+    let mut chunk_start = 0;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                let value = if let Some(mut u) = unescaped {
+                    u.push_str(&s[chunk_start..i]);
+                    Value::from(u)
+                } else {
+                    Value::from(&s[..i])
+                };
+                return Ok((value, &s[i + 1..]));
+            }
+            '\\' => {
+                let u = unescaped.get_or_insert_with(String::new);
+                u.push_str(&s[chunk_start..i]);
+                match chars.next() {
+                    Some((_, '\\')) => u.push('\\'),
+                    Some((_, '"')) => u.push('"'),
+                    Some((_, 'n')) => u.push('\n'),
+                    _ => return Err(invalid("invalid escape sequence in label value", line)),
+                }
+                chunk_start = i + 2;
+            }
+            _ => (),
+        }
+    }
+    Err(invalid("unterminated label value", line))
+}
+
+fn decode_sample(line: &str, ingest_ns: u64) -> Result<Value> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_ascii_whitespace())
+        .ok_or_else(|| invalid("missing value", line))?;
+    let (name, rest) = line.split_at(name_end);
+    if !is_valid_name(name) {
+        return Err(invalid("invalid metric name", line));
+    }
+
+    let (tags, rest) = if let Some(rest) = rest.strip_prefix('{') {
+        decode_labels(rest, line)?
+    } else {
+        (Object::with_capacity(0), rest)
+    };
+
+    let mut parts = rest.split_ascii_whitespace();
+    let value = parts
+        .next()
+        .and_then(parse_value)
+        .ok_or_else(|| invalid("invalid value", line))?;
+    let timestamp = if let Some(ts) = parts.next() {
+        // prometheus timestamps are in milliseconds, negative ones are
+        // rejected as tremor timestamps are unsigned nanoseconds
+        let ms: u64 = ts.parse().map_err(|_| invalid("invalid timestamp", line))?;
+        ms.checked_mul(1_000_000)
+            .ok_or_else(|| invalid("timestamp out of range", line))?
+    } else {
+        ingest_ns
+    };
+    if parts.next().is_some() {
+        return Err(invalid("trailing data", line));
+    }
+
+    let mut fields = Object::with_capacity(1);
+    fields.insert(VALUE.into(), Value::from(value));
+
+    let mut m = Object::with_capacity(4);
+    m.insert("measurement".into(), Value::from(name));
+    m.insert("tags".into(), Value::from(tags));
+    m.insert("fields".into(), Value::from(fields));
+    m.insert("timestamp".into(), Value::from(timestamp));
+    Ok(Value::from(m))
+}
+
+fn encode_value(value: &Value, r: &mut String) -> bool {
+    if let Some(v) = value.as_i64() {
+        r.push_str(&v.to_string());
+    } else if let Some(v) = value.as_f64() {
+        if v.is_nan() {
+            r.push_str("NaN");
+        } else if v.is_infinite() {
+            r.push_str(if v > 0.0 { "+Inf" } else { "-Inf" });
+        } else {
+            r.push_str(&v.to_string());
+        }
+    } else if let Some(v) = value.as_bool() {
+        r.push(if v { '1' } else { '0' });
+    } else {
+        return false;
+    }
+    true
+}
+
+fn encode_label_value(value: &str, r: &mut String) {
+    r.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => r.push_str("\\\\"),
+            '"' => r.push_str("\\\""),
+            '\n' => r.push_str("\\n"),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+}
+
+fn encode(data: &Value) -> Result<String> {
+    let measurement = data
+        .get_str("measurement")
+        .ok_or_else(|| invalid("missing measurement", ""))?;
+    let fields = data
+        .get_object("fields")
+        .ok_or_else(|| invalid("missing fields", measurement))?;
+
+    let mut labels = String::new();
+    if let Some(tags) = data.get_object("tags") {
+        let mut tags: Vec<(&str, &Value)> = tags.iter().map(|(k, v)| (&**k, v)).collect();
+        tags.sort_by_key(|(k, _)| *k);
+        for (k, v) in tags {
+            if !is_valid_name(k) {
+                return Err(invalid("invalid label name", k));
+            }
+            labels.push(if labels.is_empty() { '{' } else { ',' });
+            labels.push_str(k);
+            labels.push('=');
+            if let Some(s) = v.as_str() {
+                encode_label_value(s, &mut labels);
+            } else {
+                encode_label_value(&v.encode(), &mut labels);
+            }
+        }
+        if !labels.is_empty() {
+            labels.push('}');
+        }
+    }
+
+    // prometheus timestamps are in milliseconds
+    let timestamp = data.get_u64("timestamp").map(|ts| ts / 1_000_000);
+
+    let mut fields: Vec<(&str, &Value)> = fields.iter().map(|(k, v)| (&**k, v)).collect();
+    fields.sort_by_key(|(k, _)| *k);
+    let mut r = String::new();
+    for (field, value) in fields {
+        if !r.is_empty() {
+            r.push('\n');
+        }
+        let name = if field == VALUE {
+            measurement.to_string()
+        } else {
+            format!("{}_{}", measurement, field)
+        };
+        if !is_valid_name(&name) {
+            return Err(invalid("invalid metric name", &name));
+        }
+        r.push_str(&name);
+        r.push_str(&labels);
+        r.push(' ');
+        if !encode_value(value, &mut r) {
+            return Err(invalid("field values must be numeric", field));
+        }
+        if let Some(ts) = timestamp {
+            r.push(' ');
+            r.push_str(&ts.to_string());
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tremor_value::literal;
+
+    #[test]
+    fn decode_exposition() -> Result<()> {
+        let mut codec = Prometheus {};
+        let mut data = br#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{ method = "post" , code="400", } 3 1395066363000
+
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+rpc_duration_seconds{quantile="0.5"} +Inf
+"#
+        .to_vec();
+        let decoded = codec.decode_stream(&mut data, 42, 0)?;
+        assert_eq!(
+            vec![
+                literal!({
+                    "measurement": "http_requests_total",
+                    "tags": {"method": "post", "code": "200"},
+                    "fields": {"value": 1027.0},
+                    "timestamp": 1_395_066_363_000_000_000_u64
+                }),
+                literal!({
+                    "measurement": "http_requests_total",
+                    "tags": {"method": "post", "code": "400"},
+                    "fields": {"value": 3.0},
+                    "timestamp": 1_395_066_363_000_000_000_u64
+                }),
+                literal!({
+                    "measurement": "msdos_file_access_time_seconds",
+                    "tags": {"path": "C:\\DIR\\FILE.TXT", "error": "Cannot find file:\n\"FILE.TXT\""},
+                    "fields": {"value": 1.458_255_915e9},
+                    "timestamp": 42_u64
+                }),
+                literal!({
+                    "measurement": "rpc_duration_seconds",
+                    "tags": {"quantile": "0.5"},
+                    "fields": {"value": f64::INFINITY},
+                    "timestamp": 42_u64
+                }),
+            ],
+            decoded
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut codec = Prometheus {};
+        for data in &[
+            &b"metric"[..],
+            b"metric{a=\"b\" 1",
+            b"metric{a=b} 1",
+            b"metric{a=\"b\"} one",
+            b"metric 1 ts",
+            b"metric 1 2 3",
+            b"metric 1 -1395066363000",
+            b"metric 1 18446744073709552",
+            b"1metric 1",
+        ] {
+            let mut data = data.to_vec();
+            assert!(codec.decode_stream(&mut data, 0, 0).is_err());
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip() -> Result<()> {
+        let mut codec = Prometheus {};
+        let value = literal!({
+            "measurement": "http_requests",
+            "tags": {"method": "post", "path": "/a\"b\""},
+            "fields": {"value": 1027, "seconds": 0.5},
+            "timestamp": 1_395_066_363_000_000_000_u64
+        });
+        let mut encoded = codec.encode(&value)?;
+        assert_eq!(
+            "http_requests_seconds{method=\"post\",path=\"/a\\\"b\\\"\"} 0.5 1395066363000\n\
+             http_requests{method=\"post\",path=\"/a\\\"b\\\"\"} 1027 1395066363000",
+            str::from_utf8(&encoded)?
+        );
+        let decoded = codec.decode_stream(&mut encoded, 0, 0)?;
+        assert_eq!(2, decoded.len());
+        assert_eq!(Some("http_requests"), decoded[1].get_str("measurement"));
+        assert_eq!(
+            Some(&Value::from(1027.0)),
+            decoded[1].get("fields").and_then(|f| f.get("value"))
+        );
+        assert_eq!(
+            Some("/a\"b\""),
+            decoded[1].get("tags").and_then(|t| t.get_str("path"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn encode_invalid() {
+        let codec = Prometheus {};
+        assert!(codec.encode(&literal!("snot")).is_err());
+        assert!(codec
+            .encode(&literal!({"measurement": "m", "fields": {"value": "badger"}}))
+            .is_err());
+        assert!(codec
+            .encode(&literal!({"measurement": "m m", "fields": {"value": 1}}))
+            .is_err());
+    }
+}
@@ -54,6 +54,14 @@ pub struct OnRamp {
     pub(crate) err_required: bool,
     #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
     pub(crate) codec: Option<String>,
+    /// configuration for the codec, only used by configurable codecs
+    ///
+    /// e.g.:
+    ///       codec: csv
+    ///       codec_config:
+    ///         headers: true
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub(crate) codec_config: tremor_pipeline::ConfigMap,
     /// mapping from mime-type to codec used to handle requests/responses
     /// with this mime-type
     ///
@@ -92,6 +100,14 @@ pub struct OffRamp {
     pub(crate) is_linked: bool,
     #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
     pub(crate) codec: Option<String>,
+    /// configuration for the codec, only used by configurable codecs
+    ///
+    /// e.g.:
+    ///       codec: csv
+    ///       codec_config:
+    ///         headers: true
+    #[serde(default = "Default::default", skip_serializing_if = "Option::is_none")]
+    pub(crate) codec_config: tremor_pipeline::ConfigMap,
     /// mapping from mime-type to codec used to handle requests/responses
     /// with this mime-type
     ///
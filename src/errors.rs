@@ -159,6 +159,7 @@ error_chain! {
         Postgres(postgres::Error);
         Common(tremor_common::Error);
         Sled(sled::Error);
+        Sqlite(rusqlite::Error);
         DnsError(async_std_resolver::ResolveError);
         GoogleAuthError(gouth::Error);
         ReqwestError(reqwest::Error);
@@ -168,6 +169,8 @@ error_chain! {
         RustlsError(rustls::TLSError);
         Hex(hex::FromHexError);
         CsvError(csv::Error);
+        MqttClientError(rumqttc::ClientError);
+        MqttV5ClientError(rumqttc::v5::ClientError);
     }
 
     errors {
@@ -245,6 +248,14 @@ error_chain! {
             description("Invalid BInflux Line Protocol data")
                 display("Invalid BInflux Line Protocol data: {}", s)
         }
+        InvalidLogfmt(s: String) {
+            description("Invalid logfmt data")
+                display("Invalid logfmt data: {}", s)
+        }
+        InvalidPrometheusData(s: String) {
+            description("Invalid Prometheus text format data")
+                display("Invalid Prometheus text format data: {}", s)
+        }
         InvalidSyslogData(s: &'static str) {
             description("Invalid Syslog Protocol data")
                 display("Invalid Syslog Protocol data: {}", s)
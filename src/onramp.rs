@@ -19,15 +19,16 @@ use crate::source::prelude::*;
 #[cfg(unix)]
 use crate::source::unix_socket;
 use crate::source::{
-    amqp, blaster, cb, crononome, discord, env, file, gsub, kafka, metronome, nats, otel, postgres,
-    rest, sse, stdin, tcp, udp, ws,
+    amqp, blaster, cb, crononome, discord, elastic, elastic_bulk, env, exec, file, fluent, gsub,
+    kafka, metronome, mqtt, nats, otel, postgres, postgres_cdc, prometheus,
+    prometheus_remote_write, rest, splunk_hec, sse, stdin, syslog, tcp, udp, ws, ws_client,
 };
 use crate::url::TremorUrl;
 use async_std::task::{self, JoinHandle};
 use serde_yaml::Value;
 use std::fmt;
 use tremor_common::ids::OnrampIdGen;
-use tremor_pipeline::EventId;
+use tremor_pipeline::{ConfigMap, EventId};
 
 pub(crate) type Sender = async_channel::Sender<ManagerMsg>;
 
@@ -52,6 +53,7 @@ pub type Addr = async_channel::Sender<Msg>;
 pub(crate) struct OnrampConfig<'cfg> {
     pub onramp_uid: u64,
     pub codec: &'cfg str,
+    pub codec_config: ConfigMap,
     pub codec_map: halfbrown::HashMap<String, String>,
     pub processors: Processors<'cfg>,
     pub metrics_reporter: RampReporter,
@@ -75,21 +77,34 @@ pub(crate) fn lookup(
         "amqp" => amqp::Amqp::from_config(id, config),
         "blaster" => blaster::Blaster::from_config(id, config),
         "cb" => cb::Cb::from_config(id, config),
+        "elastic" => elastic::Elastic::from_config(id, config),
+        "elastic-bulk" => elastic_bulk::ElasticBulk::from_config(id, config),
         "env" => env::Env::from_config(id, config),
+        "exec" => exec::Exec::from_config(id, config),
         "file" => file::File::from_config(id, config),
+        "fluent" => fluent::Fluent::from_config(id, config),
         "kafka" => kafka::Kafka::from_config(id, config),
         "postgres" => postgres::Postgres::from_config(id, config),
+        "postgres-cdc" => postgres_cdc::PostgresCdc::from_config(id, config),
+        "prometheus" => prometheus::Prometheus::from_config(id, config),
+        "prometheus-remote-write" => {
+            prometheus_remote_write::PrometheusRemoteWrite::from_config(id, config)
+        }
         "metronome" => metronome::Metronome::from_config(id, config),
         "crononome" => crononome::Crononome::from_config(id, config),
         "stdin" => stdin::Stdin::from_config(id, config),
+        "syslog" => syslog::Syslog::from_config(id, config),
         "udp" => udp::Udp::from_config(id, config),
         "tcp" => tcp::Tcp::from_config(id, config),
         "rest" => rest::Rest::from_config(id, config),
+        "splunk-hec" => splunk_hec::SplunkHec::from_config(id, config),
         "sse" => sse::Sse::from_config(id, config),
         "ws" => ws::Ws::from_config(id, config),
+        "ws-client" => ws_client::WsClient::from_config(id, config),
         "discord" => discord::Discord::from_config(id, config),
         "otel" => otel::OpenTelemetry::from_config(id, config),
         "nats" => nats::Nats::from_config(id, config),
+        "mqtt" => mqtt::Mqtt::from_config(id, config),
         "gsub" => gsub::GoogleCloudPubSub::from_config(id, config),
         #[cfg(unix)]
         "unix-socket" => unix_socket::UnixSocket::from_config(id, config),
@@ -101,6 +116,7 @@ pub(crate) struct Create {
     pub id: ServantId,
     pub stream: Box<dyn Onramp>,
     pub codec: String,
+    pub codec_config: ConfigMap,
     pub codec_map: halfbrown::HashMap<String, String>,
     pub preprocessors: Vec<String>,
     pub postprocessors: Vec<String>,
@@ -145,6 +161,7 @@ impl Manager {
                     Ok(ManagerMsg::Create(r, c)) => {
                         let Create {
                             codec,
+                            codec_config,
                             codec_map,
                             mut stream,
                             preprocessors,
@@ -159,6 +176,7 @@ impl Manager {
                             .start(OnrampConfig {
                                 onramp_uid: onramp_id_gen.next_id(),
                                 codec: &codec,
+                                codec_config,
                                 codec_map,
                                 processors: Processors {
                                     pre: &preprocessors,
@@ -22,7 +22,12 @@ use std::path::Path;
 use tremor_pipeline::ConfigImpl;
 use tremor_script::prelude::*;
 
+pub mod exec;
+pub mod fluent;
+pub mod mqtt;
+pub mod nats;
 pub mod postgres;
+pub mod prometheus;
 
 pub trait Kv {
     fn get(&mut self) -> Result<simd_json::OwnedValue>;
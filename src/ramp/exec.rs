@@ -0,0 +1,107 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Shared configuration of the `exec` onramp and offramp
+
+use async_std::process::{Command, Stdio};
+use halfbrown::HashMap;
+
+/// The process to run
+#[derive(Debug, Clone, Deserialize)]
+pub struct Process {
+    /// the program to execute
+    pub command: String,
+    /// arguments passed to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// additional environment variables
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// working directory, defaults to the one of tremor
+    pub cwd: Option<String>,
+}
+
+impl Process {
+    /// Creates the command with piped stdin, stdout and stderr as requested,
+    /// stdin and stdout default to null, stderr is inherited from tremor
+    #[must_use]
+    pub fn command(&self, stdin: bool, stdout: bool, stderr: bool) -> Command {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args).envs(&self.env).kill_on_drop(true);
+        if let Some(cwd) = self.cwd.as_ref() {
+            command.current_dir(cwd);
+        }
+        command
+            .stdin(piped_or_null(stdin))
+            .stdout(piped_or_null(stdout))
+            .stderr(if stderr {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            });
+        command
+    }
+}
+
+fn piped_or_null(piped: bool) -> Stdio {
+    if piped {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    }
+}
+
+/// When to restart a process that exited
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Restart {
+    /// never restart it
+    Never,
+    /// restart it if it exited with an error
+    OnFailure,
+    /// always restart it
+    Always,
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Self::Never
+    }
+}
+
+impl Restart {
+    /// If a process that exited with the given success should be restarted
+    #[must_use]
+    pub fn applies(self, success: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnFailure => !success,
+            Self::Always => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn restart_policy() {
+        assert!(!Restart::Never.applies(false));
+        assert!(Restart::OnFailure.applies(false));
+        assert!(!Restart::OnFailure.applies(true));
+        assert!(Restart::Always.applies(true));
+        assert_eq!(Restart::Never, Restart::default());
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Fluent forward Ramp
+//!
+//! Implements the msgpack messages of the fluentd forward protocol v1 and
+//! their conversion from and to tremor values.
+//!
+//! Messages in `Message`, `Forward`, `PackedForward` and
+//! `CompressedPackedForward` mode are decoded into their tag, the chunk id
+//! to acknowledge and a list of entries, each being the timestamp in
+//! nanoseconds and the record.
+
+use crate::errors::{Error, Result};
+use libflate::gzip::MultiDecoder;
+use rmpv::Value as MsgPack;
+use std::convert::TryFrom;
+use std::io::Read;
+use tremor_script::prelude::*;
+use tremor_value::StaticNode;
+
+/// the msgpack extension type of `EventTime`
+const EVENT_TIME: i8 = 0;
+
+/// A decoded forward protocol message
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub tag: String,
+    /// the timestamp in nanoseconds and the record of every entry
+    pub entries: Vec<(u64, Value<'static>)>,
+    /// the chunk id the client expects to be acknowledged
+    pub chunk: Option<String>,
+}
+
+/// Splits a byte stream into msgpack values. Only the markers and lengths are
+/// scanned until a value is complete, the scan position is kept between reads
+/// so a value spread over many reads isn't parsed over and over again.
+#[derive(Debug)]
+pub struct Framer {
+    data: Vec<u8>,
+    /// start of the current value in `data`
+    start: usize,
+    /// number of bytes of the current value that were scanned
+    scanned: usize,
+    /// number of (nested) values of the current value left to scan
+    pending: u64,
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self {
+            data: Vec::new(),
+            start: 0,
+            scanned: 0,
+            pending: 1,
+        }
+    }
+}
+
+impl Framer {
+    /// Appends the bytes of a read
+    pub fn extend(&mut self, bytes: &[u8]) {
+        // drop the values that were already returned
+        self.data.drain(..self.start);
+        self.start = 0;
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Decodes the next value, `None` if it is not complete yet
+    ///
+    /// # Errors
+    ///  * if the data is not valid msgpack
+    pub fn next_value(&mut self) -> Result<Option<MsgPack>> {
+        while self.pending > 0 {
+            let rest = self
+                .data
+                .get(self.start + self.scanned..)
+                .unwrap_or_default();
+            let (header, body, children) = match header(rest)? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            let size = u64::try_from(header)? + body;
+            if u64::try_from(rest.len())? < size {
+                return Ok(None);
+            }
+            self.scanned += usize::try_from(size)?;
+            self.pending = self.pending - 1 + children;
+        }
+        let end = self.start + self.scanned;
+        let mut value = self.data.get(self.start..end).unwrap_or_default();
+        let value = rmpv::decode::read_value(&mut value)
+            .map_err(|e| Error::from(format!("Invalid msgpack: {}", e)))?;
+        self.start = end;
+        self.scanned = 0;
+        self.pending = 1;
+        Ok(Some(value))
+    }
+}
+
+/// Reads a big endian length of `n` bytes after the marker
+fn read_len(data: &[u8], n: usize) -> Option<u64> {
+    let bytes = data.get(1..=n)?;
+    Some(bytes.iter().fold(0, |len, b| (len << 8) | u64::from(*b)))
+}
+
+/// The size of the header of the msgpack value at the start of `data`, the
+/// number of bytes and of nested values following it. `None` if `data` does
+/// not hold the complete header yet.
+fn header(data: &[u8]) -> Result<Option<(usize, u64, u64)>> {
+    let marker = match data.first() {
+        Some(marker) => *marker,
+        None => return Ok(None),
+    };
+    let fixed = |body: u64| Some((1, body, 0));
+    // a length field of `n` bytes, ext types have their type byte in front of the data
+    let bytes = |n: usize, ext: u64| read_len(data, n).map(|len| (1 + n, len + ext, 0));
+    let items = |n: usize, per_item: u64| read_len(data, n).map(|len| (1 + n, 0, len * per_item));
+    Ok(match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => fixed(0),
+        0x80..=0x8f => Some((1, 0, u64::from(marker & 0x0f) * 2)),
+        0x90..=0x9f => Some((1, 0, u64::from(marker & 0x0f))),
+        0xa0..=0xbf => fixed(u64::from(marker & 0x1f)),
+        0xc4 | 0xd9 => bytes(1, 0),
+        0xc5 | 0xda => bytes(2, 0),
+        0xc6 | 0xdb => bytes(4, 0),
+        0xc7 => bytes(1, 1),
+        0xc8 => bytes(2, 1),
+        0xc9 => bytes(4, 1),
+        0xcc | 0xd0 => fixed(1),
+        0xcd | 0xd1 | 0xd4 => fixed(2),
+        0xd5 => fixed(3),
+        0xca | 0xce | 0xd2 => fixed(4),
+        0xd6 => fixed(5),
+        0xcb | 0xcf | 0xd3 => fixed(8),
+        0xd7 => fixed(9),
+        0xd8 => fixed(17),
+        0xdc => items(2, 1),
+        0xdd => items(4, 1),
+        0xde => items(2, 2),
+        0xdf => items(4, 2),
+        0xc1 => return Err("Invalid msgpack marker 0xc1".into()),
+    })
+}
+
+fn get<'v>(map: &'v MsgPack, key: &str) -> Option<&'v MsgPack> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v)
+}
+
+/// Decodes the payload of an `EventTime`, big endian seconds and nanoseconds
+fn decode_event_time(data: &[u8]) -> Result<u64> {
+    match data {
+        [s0, s1, s2, s3, n0, n1, n2, n3] => {
+            let secs = u32::from_be_bytes([*s0, *s1, *s2, *s3]);
+            let nanos = u32::from_be_bytes([*n0, *n1, *n2, *n3]);
+            Ok(u64::from(secs) * 1_000_000_000 + u64::from(nanos))
+        }
+        _ => Err("Invalid EventTime".into()),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn decode_time(time: &MsgPack) -> Result<u64> {
+    match time {
+        MsgPack::Integer(secs) => Ok(secs
+            .as_u64()
+            .unwrap_or_default()
+            .saturating_mul(1_000_000_000)),
+        MsgPack::F64(secs) => Ok((secs * 1_000_000_000.0) as u64),
+        MsgPack::Ext(EVENT_TIME, data) => decode_event_time(data),
+        _ => Err("Invalid entry time".into()),
+    }
+}
+
+/// Encodes a timestamp in nanoseconds as `EventTime`
+pub fn encode_time(ns: u64) -> MsgPack {
+    let secs = u32::try_from(ns / 1_000_000_000).unwrap_or(u32::MAX);
+    let nanos = u32::try_from(ns % 1_000_000_000).unwrap_or_default();
+    let mut data = Vec::with_capacity(8);
+    data.extend_from_slice(&secs.to_be_bytes());
+    data.extend_from_slice(&nanos.to_be_bytes());
+    MsgPack::Ext(EVENT_TIME, data)
+}
+
+fn decode_entry(entry: MsgPack) -> Result<(u64, Value<'static>)> {
+    if let MsgPack::Array(entry) = entry {
+        let mut entry = entry.into_iter();
+        if let (Some(time), Some(record)) = (entry.next(), entry.next()) {
+            return Ok((decode_time(&time)?, to_value(record)));
+        }
+    }
+    Err("Invalid entry, expected [time, record]".into())
+}
+
+fn decode_packed(data: Vec<u8>, compressed: bool) -> Result<Vec<(u64, Value<'static>)>> {
+    let data = if compressed {
+        let mut decoder = MultiDecoder::new(data.as_slice())?;
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        decompressed
+    } else {
+        data
+    };
+    let mut entries = Vec::new();
+    let mut rest = data.as_slice();
+    while !rest.is_empty() {
+        let entry = rmpv::decode::read_value(&mut rest)
+            .map_err(|e| Error::from(format!("Invalid packed entry: {}", e)))?;
+        entries.push(decode_entry(entry)?);
+    }
+    Ok(entries)
+}
+
+/// Decodes a message in any of the forward protocol modes
+pub fn decode_message(message: MsgPack) -> Result<Message> {
+    let mut parts = match message {
+        MsgPack::Array(parts) => parts.into_iter(),
+        _ => return Err("Invalid message, expected an array".into()),
+    };
+    let tag = parts
+        .next()
+        .and_then(|tag| tag.as_str().map(String::from))
+        .ok_or("Invalid message, missing tag")?;
+    let (entries, option) = match parts.next().ok_or("Invalid message, missing entries")? {
+        // Forward mode
+        MsgPack::Array(entries) => (
+            entries
+                .into_iter()
+                .map(decode_entry)
+                .collect::<Result<Vec<_>>>()?,
+            parts.next(),
+        ),
+        // (Compressed)PackedForward mode
+        packed @ (MsgPack::Binary(_) | MsgPack::String(_)) => {
+            let option = parts.next();
+            let compressed = option
+                .as_ref()
+                .and_then(|o| get(o, "compressed"))
+                .and_then(MsgPack::as_str)
+                == Some("gzip");
+            let data = match packed {
+                MsgPack::String(s) => s.into_bytes(),
+                MsgPack::Binary(b) => b,
+                _ => Vec::new(),
+            };
+            (decode_packed(data, compressed)?, option)
+        }
+        // Message mode
+        time => {
+            let record = parts.next().ok_or("Invalid message, missing record")?;
+            (vec![(decode_time(&time)?, to_value(record))], parts.next())
+        }
+    };
+    let chunk = option
+        .as_ref()
+        .and_then(|o| get(o, "chunk"))
+        .and_then(MsgPack::as_str)
+        .map(String::from);
+    Ok(Message {
+        tag,
+        entries,
+        chunk,
+    })
+}
+
+/// Encodes entries as a message in Forward mode, asking for an ack of `chunk`
+pub fn encode_forward(
+    tag: &str,
+    entries: &[(u64, &Value)],
+    chunk: Option<&str>,
+) -> Result<Vec<u8>> {
+    let entries = entries
+        .iter()
+        .map(|(time, record)| MsgPack::Array(vec![encode_time(*time), to_msgpack(record)]))
+        .collect();
+    let mut message = vec![MsgPack::from(tag), MsgPack::Array(entries)];
+    if let Some(chunk) = chunk {
+        message.push(MsgPack::Map(vec![(
+            MsgPack::from("chunk"),
+            MsgPack::from(chunk),
+        )]));
+    }
+    encode(&MsgPack::Array(message))
+}
+
+/// Encodes the response acknowledging `chunk`
+pub fn encode_ack(chunk: &str) -> Result<Vec<u8>> {
+    encode(&MsgPack::Map(vec![(
+        MsgPack::from("ack"),
+        MsgPack::from(chunk),
+    )]))
+}
+
+/// The chunk id acknowledged by a response
+pub fn decode_ack(response: &MsgPack) -> Option<&str> {
+    get(response, "ack").and_then(MsgPack::as_str)
+}
+
+fn encode(value: &MsgPack) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    rmpv::encode::write_value(&mut data, value)
+        .map_err(|e| Error::from(format!("Unable to encode msgpack: {}", e)))?;
+    Ok(data)
+}
+
+fn key(key: MsgPack) -> String {
+    match key {
+        MsgPack::String(s) => String::from_utf8_lossy(s.as_bytes()).to_string(),
+        MsgPack::Binary(b) => String::from_utf8_lossy(&b).to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Converts a msgpack value, `EventTime`s become timestamps in nanoseconds
+pub fn to_value(value: MsgPack) -> Value<'static> {
+    match value {
+        MsgPack::Nil => Value::null(),
+        MsgPack::Boolean(b) => Value::from(b),
+        MsgPack::Integer(i) => i
+            .as_i64()
+            .map_or_else(|| Value::from(i.as_u64().unwrap_or_default()), Value::from),
+        MsgPack::F32(f) => Value::from(f64::from(f)),
+        MsgPack::F64(f) => Value::from(f),
+        MsgPack::String(s) => Value::from(String::from_utf8_lossy(s.as_bytes()).to_string()),
+        MsgPack::Binary(b) => Value::Bytes(b.into()),
+        MsgPack::Array(a) => Value::Array(a.into_iter().map(to_value).collect()),
+        MsgPack::Map(m) => {
+            let mut o = Value::object_with_capacity(m.len());
+            for (k, v) in m {
+                o.try_insert(key(k), to_value(v));
+            }
+            o
+        }
+        MsgPack::Ext(EVENT_TIME, data) => {
+            decode_event_time(&data).map_or_else(|_| Value::null(), Value::from)
+        }
+        MsgPack::Ext(_, data) => Value::Bytes(data.into()),
+    }
+}
+
+/// Converts a tremor value to msgpack
+pub fn to_msgpack(value: &Value) -> MsgPack {
+    match value {
+        Value::Static(StaticNode::Null) => MsgPack::Nil,
+        Value::Static(StaticNode::Bool(b)) => MsgPack::from(*b),
+        Value::Static(StaticNode::I64(i)) => MsgPack::from(*i),
+        Value::Static(StaticNode::U64(u)) => MsgPack::from(*u),
+        Value::Static(StaticNode::F64(f)) => MsgPack::F64(*f),
+        Value::String(s) => MsgPack::from(s.as_ref()),
+        Value::Bytes(b) => MsgPack::Binary(b.to_vec()),
+        Value::Array(a) => MsgPack::Array(a.iter().map(to_msgpack).collect()),
+        Value::Object(o) => MsgPack::Map(
+            o.iter()
+                .map(|(k, v)| (MsgPack::from(k.as_ref()), to_msgpack(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn entry(time: u64, msg: &str) -> MsgPack {
+        MsgPack::Array(vec![
+            encode_time(time),
+            MsgPack::Map(vec![(MsgPack::from("msg"), MsgPack::from(msg))]),
+        ])
+    }
+
+    #[test]
+    fn modes() -> Result<()> {
+        let expected = vec![
+            (1_000_000_001, literal!({"msg": "snot"})),
+            (2_000_000_002, literal!({"msg": "badger"})),
+        ];
+
+        let forward = MsgPack::Array(vec![
+            MsgPack::from("app"),
+            MsgPack::Array(vec![
+                entry(1_000_000_001, "snot"),
+                entry(2_000_000_002, "badger"),
+            ]),
+            MsgPack::Map(vec![(MsgPack::from("chunk"), MsgPack::from("c1"))]),
+        ]);
+        let message = decode_message(forward)?;
+        assert_eq!("app", message.tag);
+        assert_eq!(Some("c1".to_string()), message.chunk);
+        assert_eq!(expected, message.entries);
+
+        let mut packed = encode(&entry(1_000_000_001, "snot"))?;
+        packed.extend(encode(&entry(2_000_000_002, "badger"))?);
+        let mut encoder = libflate::gzip::Encoder::new(Vec::new())?;
+        encoder.write_all(&packed)?;
+        let compressed = encoder.finish().into_result()?;
+        let compressed = MsgPack::Array(vec![
+            MsgPack::from("app"),
+            MsgPack::Binary(compressed),
+            MsgPack::Map(vec![(MsgPack::from("compressed"), MsgPack::from("gzip"))]),
+        ]);
+        let message = decode_message(compressed)?;
+        assert_eq!(None, message.chunk);
+        assert_eq!(expected, message.entries);
+
+        let single = MsgPack::Array(vec![
+            MsgPack::from("app"),
+            MsgPack::from(1),
+            MsgPack::Map(vec![(MsgPack::from("msg"), MsgPack::from("snot"))]),
+        ]);
+        let message = decode_message(single)?;
+        assert_eq!(
+            vec![(1_000_000_000, literal!({"msg": "snot"}))],
+            message.entries
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn framing() -> Result<()> {
+        let record = literal!({"msg": "snot", "n": 1});
+        let data = encode_forward("app", &[(42, &record)], Some("c1"))?;
+        let ack = encode_ack("c1")?;
+        let mut framer = Framer::default();
+        // a message arriving byte by byte
+        for byte in data.iter().take(data.len() - 1) {
+            framer.extend(&[*byte]);
+            assert!(framer.next_value()?.is_none());
+        }
+        let mut rest = data.last().copied().into_iter().collect::<Vec<_>>();
+        // followed by another value in the same read
+        rest.extend_from_slice(&ack);
+        framer.extend(&rest);
+        let message = decode_message(framer.next_value()?.ok_or("no value")?)?;
+        assert_eq!(vec![(42, record)], message.entries);
+        let value = framer.next_value()?.ok_or("no value")?;
+        assert_eq!(Some("c1"), decode_ack(&value));
+        assert!(framer.next_value()?.is_none());
+
+        let mut framer = Framer::default();
+        framer.extend(&[0xc1]);
+        assert!(framer.next_value().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # MQTT Ramp
+//!
+//! Connection handling shared by the `mqtt` onramp and offramp. Hides the
+//! differences between the MQTT 3.1.1 and MQTT 5 clients behind a single
+//! [`Client`](enum.Client.html) and [`EventLoop`](enum.EventLoop.html).
+
+use crate::errors::{Error, Result};
+use rumqttc::v5;
+use rumqttc::{Key, Outgoing, TlsConfiguration, Transport};
+use serde::Deserialize;
+use std::fs;
+use std::time::Duration;
+
+/// capacity of the request channel of the mqtt client
+const CAPACITY: usize = 64;
+
+/// MQTT protocol version
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub enum Protocol {
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::V311
+    }
+}
+
+/// TLS settings, all files are PEM encoded
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// CA certificate used to verify the broker
+    pub cafile: String,
+    /// client certificate for mutual TLS
+    pub cert: Option<String>,
+    /// RSA private key of the client certificate
+    pub key: Option<String>,
+}
+
+/// Broker connection settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectConfig {
+    /// broker host
+    pub host: String,
+    /// broker port, defaults to 1883
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// client identifier, defaults to `tremor-<hostname>-<uid of the ramp>`
+    pub client_id: Option<String>,
+    /// protocol version, `3.1.1` (default) or `5`
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// keep alive interval in seconds, defaults to 60
+    #[serde(default = "dflt_keep_alive")]
+    pub keep_alive: u64,
+    /// start with a clean session (`clean start` for MQTT 5), defaults to true.
+    /// Set it to false so unacknowledged messages are redelivered after a reconnect.
+    #[serde(default = "dflt_clean_session")]
+    pub clean_session: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// connect via TLS
+    pub tls: Option<TlsConfig>,
+}
+
+fn dflt_port() -> u16 {
+    1883
+}
+
+fn dflt_keep_alive() -> u64 {
+    60
+}
+
+fn dflt_clean_session() -> bool {
+    true
+}
+
+/// Parses a QoS level from the config or event metadata
+///
+/// # Errors
+///  * if the level is not 0, 1 or 2
+pub fn qos(level: u8) -> Result<rumqttc::QoS> {
+    match level {
+        0 => Ok(rumqttc::QoS::AtMostOnce),
+        1 => Ok(rumqttc::QoS::AtLeastOnce),
+        2 => Ok(rumqttc::QoS::ExactlyOnce),
+        other => Err(format!("Invalid MQTT QoS {}, must be 0, 1 or 2", other).into()),
+    }
+}
+
+fn qos_v5(qos: rumqttc::QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        rumqttc::QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        rumqttc::QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        rumqttc::QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+fn qos_level(qos: rumqttc::QoS) -> u8 {
+    match qos {
+        rumqttc::QoS::AtMostOnce => 0,
+        rumqttc::QoS::AtLeastOnce => 1,
+        rumqttc::QoS::ExactlyOnce => 2,
+    }
+}
+
+fn qos_level_v5(qos: v5::mqttbytes::QoS) -> u8 {
+    match qos {
+        v5::mqttbytes::QoS::AtMostOnce => 0,
+        v5::mqttbytes::QoS::AtLeastOnce => 1,
+        v5::mqttbytes::QoS::ExactlyOnce => 2,
+    }
+}
+
+impl TlsConfig {
+    fn transport(&self) -> Result<Transport> {
+        let ca = fs::read(&self.cafile)?;
+        let client_auth = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some((fs::read(cert)?, Key::RSA(fs::read(key)?))),
+            (None, None) => None,
+            _ => return Err("MQTT TLS requires both `cert` and `key` for client auth".into()),
+        };
+        Ok(Transport::tls_with_config(TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth,
+        }))
+    }
+}
+
+impl ConnectConfig {
+    /// Creates a client and its event loop, `manual_acks` leaves acknowledging
+    /// received messages to the caller.
+    ///
+    /// # Errors
+    ///  * if the TLS files can't be read
+    pub fn connect(
+        &self,
+        default_client_id: &str,
+        manual_acks: bool,
+    ) -> Result<(Client, EventLoop)> {
+        let client_id = self
+            .client_id
+            .clone()
+            .unwrap_or_else(|| default_client_id.to_string());
+        let keep_alive = Duration::from_secs(self.keep_alive);
+        let transport = self.tls.as_ref().map(TlsConfig::transport).transpose()?;
+        match self.protocol {
+            Protocol::V311 => {
+                let mut options = rumqttc::MqttOptions::new(client_id, &self.host, self.port);
+                options
+                    .set_keep_alive(keep_alive)
+                    .set_clean_session(self.clean_session)
+                    .set_manual_acks(manual_acks);
+                if let Some((username, password)) =
+                    self.username.as_ref().zip(self.password.as_ref())
+                {
+                    options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                let (client, eventloop) = rumqttc::AsyncClient::new(options, CAPACITY);
+                Ok((Client::V311(client), EventLoop::V311(eventloop)))
+            }
+            Protocol::V5 => {
+                let mut options = v5::MqttOptions::new(client_id, &self.host, self.port);
+                options
+                    .set_keep_alive(keep_alive)
+                    .set_clean_session(self.clean_session)
+                    .set_manual_acks(manual_acks);
+                if let Some((username, password)) =
+                    self.username.as_ref().zip(self.password.as_ref())
+                {
+                    options.set_credentials(username, password);
+                }
+                if let Some(transport) = transport {
+                    options.set_transport(transport);
+                }
+                let (client, eventloop) = v5::AsyncClient::new(options, CAPACITY);
+                Ok((Client::V5(client), EventLoop::V5(eventloop)))
+            }
+        }
+    }
+}
+
+/// A received message
+pub enum Publish {
+    V311(rumqttc::Publish),
+    V5(v5::mqttbytes::Publish),
+}
+
+impl Publish {
+    #[must_use]
+    pub fn topic(&self) -> String {
+        match self {
+            Self::V311(p) => p.topic.clone(),
+            Self::V5(p) => String::from_utf8_lossy(&p.topic).to_string(),
+        }
+    }
+
+    #[must_use]
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            Self::V311(p) => p.payload.to_vec(),
+            Self::V5(p) => p.payload.to_vec(),
+        }
+    }
+
+    #[must_use]
+    pub fn qos(&self) -> u8 {
+        match self {
+            Self::V311(p) => qos_level(p.qos),
+            Self::V5(p) => qos_level_v5(p.qos),
+        }
+    }
+
+    #[must_use]
+    pub fn retain(&self) -> bool {
+        match self {
+            Self::V311(p) => p.retain,
+            Self::V5(p) => p.retain,
+        }
+    }
+}
+
+/// Notifications from the event loop relevant to the ramps
+pub enum Notification {
+    /// the connection to the broker got (re-)established
+    Connected,
+    /// a message was received
+    Publish(Publish),
+    /// a message was handed to the broker with the given packet id
+    Sent(u16),
+    /// the broker acknowledged (`PUBACK` or `PUBCOMP`) the message with the given packet id
+    Acked(u16),
+    /// anything else, e.g. pings
+    Other,
+}
+
+pub enum EventLoop {
+    V311(rumqttc::EventLoop),
+    V5(v5::EventLoop),
+}
+
+impl EventLoop {
+    /// Drives the connection, reconnecting on the next call after an error
+    ///
+    /// # Errors
+    ///  * if the connection to the broker failed
+    pub async fn poll(&mut self) -> Result<Notification> {
+        match self {
+            Self::V311(eventloop) => {
+                use rumqttc::{Event, Packet};
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        Ok(Notification::Publish(Publish::V311(p)))
+                    }
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => Ok(Notification::Connected),
+                    Ok(Event::Incoming(Packet::PubAck(ack))) => Ok(Notification::Acked(ack.pkid)),
+                    Ok(Event::Incoming(Packet::PubComp(comp))) => {
+                        Ok(Notification::Acked(comp.pkid))
+                    }
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Ok(Notification::Sent(pkid)),
+                    Ok(_) => Ok(Notification::Other),
+                    Err(e) => Err(Error::from(format!("MQTT connection error: {}", e))),
+                }
+            }
+            Self::V5(eventloop) => {
+                use v5::mqttbytes::v5::Packet;
+                use v5::Event;
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(packet)) => match *packet {
+                        Packet::Publish(p, _) => Ok(Notification::Publish(Publish::V5(p))),
+                        Packet::ConnAck(_) => Ok(Notification::Connected),
+                        Packet::PubAck(ack, _) => Ok(Notification::Acked(ack.pkid)),
+                        Packet::PubComp(comp, _) => Ok(Notification::Acked(comp.pkid)),
+                        _ => Ok(Notification::Other),
+                    },
+                    Ok(Event::Outgoing(Outgoing::Publish(pkid))) => Ok(Notification::Sent(pkid)),
+                    Ok(_) => Ok(Notification::Other),
+                    Err(e) => Err(Error::from(format!("MQTT connection error: {}", e))),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    /// Subscribes to topic filters with a single request without waiting for
+    /// room in the request channel, wildcards (`+`, `#`) are allowed
+    ///
+    /// # Errors
+    ///  * if the request channel is full or the event loop stopped
+    pub fn try_subscribe_many(&self, topics: &[String], qos: rumqttc::QoS) -> Result<()> {
+        match self {
+            Self::V311(client) => client.try_subscribe_many(
+                topics
+                    .iter()
+                    .map(|topic| rumqttc::SubscribeFilter::new(topic.clone(), qos)),
+            )?,
+            Self::V5(client) => client.try_subscribe_many(
+                topics
+                    .iter()
+                    .map(|topic| v5::mqttbytes::Filter::new(topic.clone(), qos_v5(qos))),
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Publishes a message
+    ///
+    /// # Errors
+    ///  * if the request can't be handed to the event loop
+    pub async fn publish(
+        &self,
+        topic: &str,
+        qos: rumqttc::QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        match self {
+            Self::V311(client) => client.publish(topic, qos, retain, payload).await?,
+            Self::V5(client) => client.publish(topic, qos_v5(qos), retain, payload).await?,
+        }
+        Ok(())
+    }
+
+    /// Acknowledges a received message, a no-op for QoS 0
+    ///
+    /// # Errors
+    ///  * if the request can't be handed to the event loop
+    pub fn try_ack(&self, publish: &Publish) -> Result<()> {
+        match (self, publish) {
+            (Self::V311(client), Publish::V311(p)) => client.try_ack(p)?,
+            (Self::V5(client), Publish::V5(p)) => client.try_ack(p)?,
+            _ => return Err("MQTT protocol mismatch between client and message".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) {
+        let res = match self {
+            Self::V311(client) => client.disconnect().await.map_err(Error::from),
+            Self::V5(client) => client.disconnect().await.map_err(Error::from),
+        };
+        if let Err(e) = res {
+            warn!("Error disconnecting from MQTT broker: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn qos_levels() -> Result<()> {
+        for level in 0..=2 {
+            assert_eq!(level, qos_level(qos(level)?));
+            assert_eq!(level, qos_level_v5(qos_v5(qos(level)?)));
+        }
+        assert!(qos(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config() -> Result<()> {
+        let config: ConnectConfig = serde_yaml::from_str("host: localhost\nprotocol: \"5\"")?;
+        assert_eq!(1883, config.port);
+        assert_eq!(Protocol::V5, config.protocol);
+        assert!(config.clean_session);
+        let config: ConnectConfig = serde_yaml::from_str("host: localhost")?;
+        assert_eq!(Protocol::V311, config.protocol);
+        Ok(())
+    }
+
+    /// Needs a broker on localhost:1883, e.g. `docker run -p 1883:1883 eclipse-mosquitto:1.6`
+    #[ignore]
+    #[async_std::test]
+    async fn broker_roundtrip() -> Result<()> {
+        for protocol in &["3.1.1", "5"] {
+            let config: ConnectConfig =
+                serde_yaml::from_str(&format!("host: localhost\nprotocol: \"{}\"", protocol))?;
+            let topic = format!("tremor/test/{}", protocol);
+            let (client, mut eventloop) = config.connect("tremor-mqtt-test", true)?;
+            client.try_subscribe_many(&[topic.clone()], qos(1)?)?;
+            client
+                .publish(&topic, qos(1)?, false, b"snot".to_vec())
+                .await?;
+            let received = async_std::future::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Notification::Publish(p) = eventloop.poll().await? {
+                        return Ok::<_, Error>(p);
+                    }
+                }
+            })
+            .await
+            .map_err(|_| Error::from("No message received"))??;
+            assert_eq!(topic, received.topic());
+            assert_eq!(b"snot".to_vec(), received.payload());
+            assert_eq!(1, received.qos());
+            client.try_ack(&received)?;
+            client.disconnect().await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The parts of the NATS JetStream API used by the `nats` onramp and offramp.
+//!
+//! JetStream is spoken over core NATS: consumers are managed with requests to
+//! `$JS.API.*` subjects, messages are acknowledged by publishing to their
+//! `$JS.ACK.*` reply subject and publishes are confirmed with a `PubAck`.
+
+use crate::errors::{Error, Result};
+use async_channel::{bounded, Receiver};
+use async_nats::{Connection, Message, Subscription};
+use async_std::future::timeout;
+use async_std::task;
+use futures::{stream, Stream, StreamExt};
+use rand::RngCore;
+use simd_json::OwnedValue;
+use std::time::Duration;
+use tremor_script::prelude::*;
+use value_trait::ValueAccess;
+
+/// Time to wait for responses of the JetStream API
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A new unique inbox subject
+pub fn inbox() -> String {
+    let mut id = [0_u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut id);
+    let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("_INBOX.{}", id)
+}
+
+/// Turns an error response of the JetStream API into an error
+fn api_error(response: &OwnedValue) -> Option<Error> {
+    response.get("error").map(|e| {
+        Error::from(format!(
+            "JetStream error {}: {}",
+            e.get_u64("code").unwrap_or_default(),
+            e.get_str("description").unwrap_or_default()
+        ))
+    })
+}
+
+/// Sends a request to the JetStream API
+async fn api_request(nc: &Connection, subject: &str, payload: &Value<'_>) -> Result<OwnedValue> {
+    let response = timeout(API_TIMEOUT, nc.request(subject, payload.encode()))
+        .await
+        .map_err(|_| Error::from(format!("Timed out waiting for {}", subject)))??;
+    let mut data = response.data;
+    if data.is_empty() {
+        // no responders, JetStream isn't enabled
+        return Err(format!("No JetStream response for {}", subject).into());
+    }
+    let response = simd_json::to_owned_value(&mut data)?;
+    api_error(&response).map_or(Ok(response), Err)
+}
+
+/// A durable consumer of a stream
+#[derive(Debug, Clone, Deserialize)]
+pub struct Consumer {
+    /// the stream to consume
+    pub stream: String,
+    /// name of the durable consumer, it is created if it doesn't exist
+    pub durable: String,
+    /// use a pull consumer instead of a push consumer
+    #[serde(default)]
+    pub pull: bool,
+    /// subject push consumers deliver to, defaults to `tremor.<stream>.<durable>`
+    pub deliver_subject: Option<String>,
+    /// number of messages to fetch at once with a pull consumer, defaults to 100
+    #[serde(default = "dflt_batch")]
+    pub batch: usize,
+    /// time a message may stay unacknowledged before it is redelivered, defaults to 30s
+    #[serde(default = "dflt_ack_wait_ms")]
+    pub ack_wait_ms: u64,
+    /// delay before failed messages are redelivered, defaults to immediately
+    #[serde(default)]
+    pub nak_delay_ms: u64,
+    /// maximum number of messages pending an ack
+    pub max_ack_pending: Option<u64>,
+}
+
+fn dflt_batch() -> usize {
+    100
+}
+
+fn dflt_ack_wait_ms() -> u64 {
+    30_000
+}
+
+impl Consumer {
+    /// The subject a push consumer delivers to
+    pub fn deliver_subject(&self) -> String {
+        self.deliver_subject
+            .clone()
+            .unwrap_or_else(|| format!("tremor.{}.{}", self.stream, self.durable))
+    }
+
+    /// Creates the consumer, or updates it if it already exists
+    pub async fn create(&self, nc: &Connection, subject: &str, queue: Option<&str>) -> Result<()> {
+        let mut config = literal!({
+            "durable_name": self.durable.clone(),
+            "ack_policy": "explicit",
+            "ack_wait": self.ack_wait_ms * 1_000_000,
+            "filter_subject": subject.to_string()
+        });
+        if !self.pull {
+            config.try_insert("deliver_subject", self.deliver_subject());
+            if let Some(queue) = queue {
+                config.try_insert("deliver_group", queue.to_string());
+            }
+        }
+        if let Some(max_ack_pending) = self.max_ack_pending {
+            config.try_insert("max_ack_pending", max_ack_pending);
+        }
+        let request = literal!({
+            "stream_name": self.stream.clone(),
+            "config": config
+        });
+        let subject = format!(
+            "$JS.API.CONSUMER.DURABLE.CREATE.{}.{}",
+            self.stream, self.durable
+        );
+        api_request(nc, &subject, &request).await?;
+        Ok(())
+    }
+
+    /// Asks a pull consumer to deliver the next batch of messages to `inbox`,
+    /// the request expires after `expires`
+    pub async fn fetch(&self, nc: &Connection, inbox: &str, expires: Duration) -> Result<()> {
+        #[allow(clippy::cast_possible_truncation)]
+        let request = literal!({
+            "batch": self.batch,
+            "expires": expires.as_nanos() as u64
+        });
+        let subject = format!("$JS.API.CONSUMER.MSG.NEXT.{}.{}", self.stream, self.durable);
+        nc.publish_with_reply_or_headers(&subject, Some(inbox), None, request.encode())
+            .await?;
+        Ok(())
+    }
+
+    /// The response to negatively acknowledge a message
+    pub fn nak(&self) -> String {
+        if self.nak_delay_ms == 0 {
+            "-NAK".to_string()
+        } else {
+            format!("-NAK {{\"delay\":{}}}", self.nak_delay_ms * 1_000_000)
+        }
+    }
+}
+
+/// Delivery information of a message, taken from its `$JS.ACK` reply subject
+#[derive(Debug, PartialEq)]
+pub struct Delivery<'a> {
+    pub stream: &'a str,
+    pub consumer: &'a str,
+    pub delivered: u64,
+    pub stream_seq: u64,
+    pub consumer_seq: u64,
+}
+
+impl<'a> Delivery<'a> {
+    /// Parses `$JS.ACK.<stream>.<consumer>.<delivered>.<sseq>.<cseq>.<ts>.<pending>`,
+    /// returns `None` for anything else, e.g. status messages
+    pub fn parse(reply: &'a str) -> Option<Self> {
+        let mut tokens = reply.split('.');
+        if tokens.next()? != "$JS" || tokens.next()? != "ACK" {
+            return None;
+        }
+        Some(Self {
+            stream: tokens.next()?,
+            consumer: tokens.next()?,
+            delivered: tokens.next()?.parse().ok()?,
+            stream_seq: tokens.next()?.parse().ok()?,
+            consumer_seq: tokens.next()?.parse().ok()?,
+        })
+    }
+
+    /// The delivery information as event metadata
+    pub fn meta(&self) -> Value<'static> {
+        literal!({
+            "stream": self.stream.to_string(),
+            "consumer": self.consumer.to_string(),
+            "delivered": self.delivered,
+            "stream_seq": self.stream_seq,
+            "consumer_seq": self.consumer_seq
+        })
+    }
+}
+
+/// Checks the `PubAck` response of a publish, returns the stream sequence
+/// Receives the messages of a subscription from a single task.
+///
+/// `Subscription::next` waits on a blocking thread that keeps running when its
+/// future is dropped, the message it receives then is lost. So it must never be
+/// raced against a timeout, the returned channel is read instead. It is closed
+/// once the subscription ends.
+pub fn messages(sub: Subscription) -> Receiver<Message> {
+    forward(stream::unfold(sub, |sub| async move {
+        let msg = sub.next().await?;
+        Some((msg, sub))
+    }))
+}
+
+fn forward<T, S>(stream: S) -> Receiver<T>
+where
+    T: Send + 'static,
+    S: Stream<Item = T> + Send + 'static,
+{
+    let (tx, rx) = bounded(crate::QSIZE);
+    task::spawn(async move {
+        let mut stream = Box::pin(stream);
+        while let Some(item) = stream.next().await {
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+pub fn pub_ack(data: &mut [u8]) -> Result<u64> {
+    if data.is_empty() {
+        // no responders, no stream matches the subject
+        return Err("No stream matches the subject".into());
+    }
+    let response = simd_json::to_owned_value(data)?;
+    if let Some(e) = api_error(&response) {
+        return Err(e);
+    }
+    response
+        .get_u64("seq")
+        .ok_or_else(|| Error::from("Invalid PubAck"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_channel::{RecvError, TryRecvError};
+
+    #[test]
+    fn delivery() {
+        let d = Delivery::parse("$JS.ACK.orders.tremor.2.1042.17.1633024800000000000.5");
+        assert_eq!(
+            Some(Delivery {
+                stream: "orders",
+                consumer: "tremor",
+                delivered: 2,
+                stream_seq: 1042,
+                consumer_seq: 17,
+            }),
+            d
+        );
+        assert_eq!(None, Delivery::parse("_INBOX.abc"));
+        assert_eq!(None, Delivery::parse("$JS.ACK.orders.tremor.x.1.2"));
+    }
+
+    #[async_std::test]
+    async fn idle_then_message() {
+        let messages = forward(stream::once(async {
+            task::sleep(Duration::from_millis(200)).await;
+            42
+        }));
+        // polling while nothing arrives doesn't lose the message
+        let mut received = Err(TryRecvError::Empty);
+        for _ in 0..500 {
+            received = messages.try_recv();
+            if received != Err(TryRecvError::Empty) {
+                break;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(Ok(42), received);
+        assert_eq!(Err(RecvError), messages.recv().await);
+    }
+
+    #[test]
+    fn pub_acks() {
+        let mut ok = br#"{"stream":"orders","seq":42}"#.to_vec();
+        assert_eq!(42, pub_ack(&mut ok).unwrap_or_default());
+        let mut err = br#"{"error":{"code":503,"description":"unavailable"}}"#.to_vec();
+        assert!(pub_ack(&mut err).is_err());
+        assert!(pub_ack(&mut []).is_err());
+    }
+
+    #[test]
+    fn naks() {
+        let mut consumer = Consumer {
+            stream: "orders".to_string(),
+            durable: "tremor".to_string(),
+            pull: false,
+            deliver_subject: None,
+            batch: dflt_batch(),
+            ack_wait_ms: dflt_ack_wait_ms(),
+            nak_delay_ms: 0,
+            max_ack_pending: None,
+        };
+        assert_eq!("-NAK", consumer.nak());
+        consumer.nak_delay_ms = 5;
+        assert_eq!("-NAK {\"delay\":5000000}", consumer.nak());
+        assert_eq!("tremor.orders.tremor", consumer.deliver_subject());
+    }
+}
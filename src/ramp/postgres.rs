@@ -118,14 +118,19 @@ impl postgres::types::ToSql for Record<'_> {
                 postgres_protocol::types::float8_to_sql(val, w);
             }
             postgres::types::Type::JSON => {
-                let val = self.value.as_str().unwrap_or_default();
-                simd_json::to_writer(w.writer(), &val)?;
+                if let Some(val) = self.value.as_str() {
+                    simd_json::to_writer(w.writer(), &val)?;
+                } else {
+                    self.value.write(&mut w.writer())?;
+                }
             }
             postgres::types::Type::JSONB => {
-                let val = self.value.as_str().unwrap_or_default();
                 w.put_u8(1);
-
-                simd_json::to_writer(w.writer(), &val)?;
+                if let Some(val) = self.value.as_str() {
+                    simd_json::to_writer(w.writer(), &val)?;
+                } else {
+                    self.value.write(&mut w.writer())?;
+                }
             }
             postgres::types::Type::TIMESTAMPTZ => {
                 let val = self.value.as_str().unwrap_or_default();
@@ -183,13 +188,12 @@ impl postgres::types::ToSql for Record<'_> {
     to_sql_checked!();
 }
 
-pub fn json_to_record<'a>(json: &'a Value<'a>) -> Result<Record> {
-    let field_type = match json.get_str("fieldType") {
-        Some(v) => v,
-        None => return Err("error getting fieldType".into()),
-    };
-
-    let t: postgres::types::Type = match field_type {
+/// Parses the name of a supported field type
+///
+/// # Errors
+///  * if the type is not supported
+pub fn field_type(name: &str) -> Result<postgres::types::Type> {
+    Ok(match name {
         "VARCHAR" => postgres::types::Type::VARCHAR,
         "UNKNOWN" => postgres::types::Type::UNKNOWN,
         "BOOL" => postgres::types::Type::BOOL,
@@ -206,8 +210,72 @@ pub fn json_to_record<'a>(json: &'a Value<'a>) -> Result<Record> {
         "TIMESTAMPTZ" => postgres::types::Type::TIMESTAMPTZ,
         "TIMESTAMP" => postgres::types::Type::TIMESTAMP,
         _ => return Err("intermediate representation does not support field type".into()),
+    })
+}
+
+/// Converts `value` into the representation `Record` expects for the type `t`:
+/// numbers and booleans are parsed from strings, scalars are turned into strings
+/// for text columns and integer timestamps (nanoseconds since epoch) are formatted
+/// for timestamp columns.
+///
+/// # Errors
+///  * if the value can't be converted
+pub fn coerce(t: &postgres::types::Type, value: &Value) -> Result<Value<'static>> {
+    use postgres::types::Type;
+    let err = || Error::from(format!("Could not convert {} into {}", value.encode(), t));
+    if value.is_null() {
+        return Ok(Value::null());
+    }
+    Ok(match *t {
+        Type::BOOL => match value.as_str() {
+            Some("true") => Value::from(true),
+            Some("false") => Value::from(false),
+            Some(_) => return Err(err()),
+            None => Value::from(value.as_bool().ok_or_else(err)?),
+        },
+        Type::INT2 | Type::INT4 | Type::INT8 => {
+            if let Some(s) = value.as_str() {
+                Value::from(s.trim().parse::<i64>().map_err(|_| err())?)
+            } else if let Some(b) = value.as_bool() {
+                Value::from(i64::from(b))
+            } else {
+                Value::from(value.as_i64().ok_or_else(err)?)
+            }
+        }
+        Type::FLOAT4 | Type::FLOAT8 => {
+            if let Some(s) = value.as_str() {
+                Value::from(s.trim().parse::<f64>().map_err(|_| err())?)
+            } else {
+                Value::from(value.cast_f64().ok_or_else(err)?)
+            }
+        }
+        Type::BPCHAR | Type::CHAR | Type::NAME | Type::TEXT | Type::UNKNOWN | Type::VARCHAR => {
+            value.as_str().map_or_else(
+                || Value::from(value.encode()),
+                |s| Value::from(s.to_string()),
+            )
+        }
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => {
+            if let Some(s) = value.as_str() {
+                Value::from(s.to_string())
+            } else {
+                let ns = value.as_i64().ok_or_else(err)?;
+                let ts = Utc.timestamp_nanos(ns);
+                Value::from(ts.format("%Y-%m-%d %H:%M:%S%.6f %:z").to_string())
+            }
+        }
+        _ => value.clone_static(),
+    })
+}
+
+pub fn json_to_record<'a>(json: &'a Value<'a>) -> Result<Record> {
+    let field_type = match json.get_str("fieldType") {
+        Some(v) => v,
+        None => return Err("error getting fieldType".into()),
     };
 
+    let t = self::field_type(field_type)?;
+
     let name = json
         .get_str("name")
         .ok_or_else(|| Error::from("Missing field `name`"))?;
@@ -264,3 +332,36 @@ pub fn row_to_json(
 
     Ok(json)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use postgres::types::Type;
+
+    #[test]
+    fn coerce_values() -> Result<()> {
+        assert_eq!(
+            Value::from(42_i64),
+            coerce(&Type::INT8, &Value::from("42"))?
+        );
+        assert_eq!(Value::from(1_i64), coerce(&Type::INT4, &Value::from(true))?);
+        assert_eq!(
+            Value::from(1.5),
+            coerce(&Type::FLOAT8, &Value::from("1.5"))?
+        );
+        assert_eq!(Value::from(2.0), coerce(&Type::FLOAT4, &Value::from(2))?);
+        assert_eq!(
+            Value::from(true),
+            coerce(&Type::BOOL, &Value::from("true"))?
+        );
+        assert_eq!(Value::from("42"), coerce(&Type::TEXT, &Value::from(42))?);
+        assert_eq!(
+            Value::from("1970-01-01 00:00:01.000000 +00:00"),
+            coerce(&Type::TIMESTAMPTZ, &Value::from(1_000_000_000_i64))?
+        );
+        assert_eq!(Value::null(), coerce(&Type::INT8, &Value::null())?);
+        assert!(coerce(&Type::INT8, &Value::from("snot")).is_err());
+        assert!(coerce(&Type::BOOL, &Value::from("badger")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! # Prometheus remote write Ramp
+//!
+//! Implements the remote write protobuf messages and their conversion
+//! from and to the influx style metric records used throughout tremor.
+//!
+//! A sample is represented as:
+//!
+//! ```json
+//! {
+//!     "measurement": "<value of the __name__ label>",
+//!     "tags": {"<label>": "<value>"},
+//!     "fields": {"value": 1.0},
+//!     "timestamp": <timestamp in nanoseconds>
+//! }
+//! ```
+
+use crate::errors::{Error, Result};
+use prost::Message;
+use std::convert::TryFrom;
+use tremor_common::time::nanotime;
+use tremor_script::prelude::*;
+
+/// the content type of remote write requests
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+/// the encoding of remote write requests
+pub const CONTENT_ENCODING: &str = "snappy";
+/// the protocol version header and its value
+pub const VERSION_HEADER: (&str, &str) = ("X-Prometheus-Remote-Write-Version", "0.1.0");
+
+const NAME_LABEL: &str = "__name__";
+const VALUE: &str = "value";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+impl WriteRequest {
+    /// Encodes the request as snappy compressed protobuf
+    pub fn to_body(&self) -> Result<Vec<u8>> {
+        snap::raw::Encoder::new()
+            .compress_vec(&self.encode_to_vec())
+            .map_err(|e| Error::from(format!("Snappy compression error: {}", e)))
+    }
+
+    /// Decodes a snappy compressed protobuf request
+    pub fn from_body(body: &[u8]) -> Result<Self> {
+        let data = snap::raw::Decoder::new()
+            .decompress_vec(body)
+            .map_err(|e| Error::from(format!("Snappy decompression error: {}", e)))?;
+        Self::decode(data.as_slice())
+            .map_err(|e| Error::from(format!("Invalid remote write request: {}", e)))
+    }
+}
+
+impl TimeSeries {
+    /// Turns every sample into an influx style record
+    pub fn to_values(&self) -> Result<Vec<Value<'static>>> {
+        let mut measurement = String::new();
+        let mut tags = Object::with_capacity(self.labels.len());
+        for label in &self.labels {
+            if label.name == NAME_LABEL {
+                measurement = label.value.clone();
+            } else {
+                tags.insert(label.name.clone().into(), Value::from(label.value.clone()));
+            }
+        }
+        let tags = Value::from(tags);
+        self.samples
+            .iter()
+            .map(|sample| {
+                // remote write timestamps are in milliseconds, like the prometheus
+                // codec we reject ones before the epoch or too large for nanoseconds
+                let timestamp = u64::try_from(sample.timestamp)
+                    .ok()
+                    .and_then(|ms| ms.checked_mul(1_000_000))
+                    .ok_or_else(|| {
+                        Error::from(format!("Timestamp {} out of range", sample.timestamp))
+                    })?;
+                Ok(literal!({
+                    "measurement": measurement.clone(),
+                    "tags": tags.clone(),
+                    "fields": {
+                        VALUE: sample.value
+                    },
+                    "timestamp": timestamp
+                }))
+            })
+            .collect()
+    }
+
+    /// Creates one time series per field of an influx style record, the `value` field
+    /// is named like the measurement, all other fields as `<measurement>_<field>`.
+    pub fn from_value(value: &Value) -> Result<Vec<Self>> {
+        let measurement = value
+            .get_str("measurement")
+            .ok_or_else(|| Error::from("Metric is missing a `measurement`"))?;
+        let fields = value
+            .get_object("fields")
+            .ok_or_else(|| Error::from("Metric is missing `fields`"))?;
+        // remote write timestamps are in milliseconds
+        let timestamp = value.get_u64("timestamp").unwrap_or_else(nanotime) / 1_000_000;
+        let timestamp = i64::try_from(timestamp)?;
+
+        let mut labels: Vec<Label> = value
+            .get_object("tags")
+            .map(|tags| {
+                tags.iter()
+                    .map(|(name, value)| Label {
+                        name: String::from(&**name),
+                        value: value
+                            .as_str()
+                            .map_or_else(|| value.encode(), ToString::to_string),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // labels have to be sorted by name
+        labels.sort_by(|l1, l2| l1.name.cmp(&l2.name));
+
+        fields
+            .iter()
+            .map(|(field, v)| {
+                let field: &str = field;
+                let name = if field == VALUE {
+                    measurement.to_string()
+                } else {
+                    format!("{}_{}", measurement, field)
+                };
+                let value = v
+                    .cast_f64()
+                    .ok_or_else(|| Error::from(format!("Field `{}` is not numeric", field)))?;
+                let mut labels = labels.clone();
+                // keep the labels sorted, `__name__` is not always the first one
+                match labels.binary_search_by(|l| l.name.as_str().cmp(NAME_LABEL)) {
+                    Ok(i) => {
+                        if let Some(label) = labels.get_mut(i) {
+                            label.value = name;
+                        }
+                    }
+                    Err(i) => labels.insert(
+                        i,
+                        Label {
+                            name: NAME_LABEL.to_string(),
+                            value: name,
+                        },
+                    ),
+                }
+                Ok(Self {
+                    labels,
+                    samples: vec![Sample { value, timestamp }],
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let metric = literal!({
+            "measurement": "http_requests",
+            "tags": {"method": "post", "code": 200},
+            "fields": {"value": 3, "seconds": 0.5},
+            "timestamp": 1_395_066_363_000_000_000_u64
+        });
+        let mut timeseries = TimeSeries::from_value(&metric)?;
+        timeseries.sort_by(|t1, t2| t1.labels[0].value.cmp(&t2.labels[0].value));
+        assert_eq!(2, timeseries.len());
+        assert_eq!(
+            vec!["__name__", "code", "method"],
+            timeseries[0]
+                .labels
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let request = WriteRequest { timeseries };
+        let decoded = WriteRequest::from_body(&request.to_body()?)?;
+        assert_eq!(request, decoded);
+
+        assert_eq!(
+            vec![literal!({
+                "measurement": "http_requests",
+                "tags": {"method": "post", "code": "200"},
+                "fields": {"value": 3.0},
+                "timestamp": 1_395_066_363_000_000_000_u64
+            })],
+            decoded.timeseries[0].to_values()?
+        );
+        assert_eq!(
+            vec![literal!({
+                "measurement": "http_requests_seconds",
+                "tags": {"method": "post", "code": "200"},
+                "fields": {"value": 0.5},
+                "timestamp": 1_395_066_363_000_000_000_u64
+            })],
+            decoded.timeseries[1].to_values()?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(TimeSeries::from_value(&literal!({"fields": {"value": 1}})).is_err());
+        assert!(TimeSeries::from_value(&literal!({"measurement": "m"})).is_err());
+        assert!(TimeSeries::from_value(
+            &literal!({"measurement": "m", "fields": {"value": "snot"}})
+        )
+        .is_err());
+        assert!(WriteRequest::from_body(b"snot").is_err());
+    }
+
+    #[test]
+    fn sorted_labels() -> Result<()> {
+        let metric = literal!({
+            "measurement": "up",
+            "tags": {"job": "tremor", "Host": "snot"},
+            "fields": {"value": 1}
+        });
+        let timeseries = TimeSeries::from_value(&metric)?;
+        assert_eq!(
+            vec!["Host", "__name__", "job"],
+            timeseries[0]
+                .labels
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn timestamp_bounds() {
+        let timeseries = |timestamp| TimeSeries {
+            labels: vec![],
+            samples: vec![Sample {
+                value: 1.0,
+                timestamp,
+            }],
+        };
+        assert!(timeseries(-1).to_values().is_err());
+        assert!(timeseries(i64::MAX).to_values().is_err());
+        assert!(timeseries(1_000).to_values().is_ok());
+    }
+}
@@ -196,9 +196,9 @@ impl Artefact for OfframpArtefact {
         // lookup codecs already here
         // this will bail out early if something is mistyped or so
         let codec = if let Some(codec) = &self.codec {
-            codec::lookup(codec)?
+            codec::resolve(codec, &self.codec_config)?
         } else {
-            codec::lookup(offramp.default_codec())?
+            codec::resolve(offramp.default_codec(), &self.codec_config)?
         };
         let mut resolved_codec_map = codec::builtin_codec_map();
         // override the builtin map
@@ -356,6 +356,7 @@ impl Artefact for OnrampArtefact {
                     preprocessors,
                     postprocessors,
                     codec,
+                    codec_config: self.codec_config.clone(),
                     codec_map,
                     stream,
                     metrics_reporter,
@@ -25,22 +25,31 @@ pub(crate) mod cb;
 pub(crate) mod debug;
 pub(crate) mod dns;
 pub(crate) mod elastic;
+pub(crate) mod exec;
 pub(crate) mod exit;
 pub(crate) mod file;
+pub(crate) mod fluent;
 pub(crate) mod gcs;
 pub(crate) mod gpub;
 pub(crate) mod kafka;
 pub(crate) mod kv;
+pub(crate) mod loki;
+pub(crate) mod mqtt;
 pub(crate) mod nats;
 pub(crate) mod newrelic;
 pub(crate) mod otel;
 pub(crate) mod postgres;
 pub(crate) mod prelude;
+pub(crate) mod prometheus_remote_write;
 pub(crate) mod rest;
+pub(crate) mod splunk_hec;
+pub(crate) mod sqlite;
 pub(crate) mod stderr;
 pub(crate) mod stdout;
 pub(crate) mod tcp;
 pub(crate) mod udp;
+#[cfg(unix)]
+pub(crate) mod unix_socket;
 pub(crate) mod ws;
 
 #[derive(Debug)]
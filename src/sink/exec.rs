@@ -0,0 +1,425 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Exec Offramp
+//!
+//! Runs a command and writes the encoded events to its stdin. If the offramp
+//! is linked, what the process writes to stdout is split up by the configured
+//! preprocessors, decoded with the configured codec and sent to the `out` port.
+//!
+//! If the process exits, it is started again on the next signal.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::exec::Process;
+use crate::sink::prelude::*;
+use async_std::future::timeout;
+use async_std::process::{Child, ChildStdin, ChildStdout};
+use halfbrown::HashMap;
+use std::time::Duration;
+use tremor_pipeline::EventOriginUri;
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(flatten)]
+    pub process: Process,
+}
+
+impl ConfigImpl for Config {}
+
+/// An offramp that pipes events into a process
+pub struct Exec {
+    config: Config,
+    child: Option<Child>,
+    stdin: Option<ChildStdin>,
+    postprocessors: Postprocessors,
+    preprocessors: Vec<String>,
+    sink_url: TremorUrl,
+    codec: Option<Box<dyn Codec>>,
+    reply_tx: Option<Sender<sink::Reply>>,
+    origin_uri: EventOriginUri,
+}
+
+impl offramp::Impl for Exec {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                config,
+                child: None,
+                stdin: None,
+                postprocessors: vec![],
+                preprocessors: vec![],
+                sink_url: TremorUrl::from_offramp_id("exec")?, // dummy, overwritten in init
+                codec: None,
+                reply_tx: None,
+                origin_uri: EventOriginUri::default(),
+            }))
+        } else {
+            Err("Missing config for exec offramp".into())
+        }
+    }
+}
+
+/// Sends everything the process writes to stdout through the preprocessors
+/// and decodes the results as responses
+async fn read_responses(
+    mut stdout: ChildStdout,
+    mut codec: Box<dyn Codec>,
+    mut preprocessors: Preprocessors,
+    sink_url: TremorUrl,
+    reply_tx: Sender<sink::Reply>,
+    origin_uri: EventOriginUri,
+) {
+    let mut buffer = [0; 8192];
+    while let Ok(n) = stdout.read(&mut buffer).await {
+        if n == 0 {
+            break;
+        }
+        let mut ingest_ns = nanotime();
+        // ALLOW: we define n as part of the read
+        let data = buffer[0..n].to_vec();
+        let responses = match preprocess(&mut preprocessors, &mut ingest_ns, data, &sink_url) {
+            Ok(data) => data
+                .into_iter()
+                .map(|data| decode(codec.as_mut(), data, ingest_ns))
+                .collect(),
+            Err(e) => vec![invalid(&e)],
+        };
+        for (port, data) in responses {
+            let event = Event {
+                data,
+                ingest_ns,
+                origin_uri: Some(origin_uri.clone()),
+                ..Event::default()
+            };
+            if reply_tx
+                .send(sink::Reply::Response(port, event))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+fn decode(
+    codec: &mut dyn Codec,
+    data: Vec<u8>,
+    ingest_ns: u64,
+) -> (Cow<'static, str>, EventPayload) {
+    let decoded = EventPayload::try_new::<crate::Error, _>(data, |data| {
+        let value = codec.decode(data, ingest_ns)?.unwrap_or_else(Value::object);
+        Ok(ValueAndMeta::from_parts(value, Value::object()))
+    });
+    match decoded {
+        Ok(data) => (OUT, data),
+        Err(e) => invalid(&e),
+    }
+}
+
+fn invalid(e: &Error) -> (Cow<'static, str>, EventPayload) {
+    let error = literal!({ "error": format!("Invalid response: {}", e) });
+    (ERR, (error, Value::object()).into())
+}
+
+impl Exec {
+    fn spawn(&mut self) -> Result<()> {
+        let linked = self.codec.is_some();
+        let mut child = self.config.process.command(true, linked, false).spawn()?;
+        self.stdin = child.stdin.take();
+        if let Some((codec, reply_tx)) = self.codec.as_ref().zip(self.reply_tx.as_ref()) {
+            let stdout = child.stdout.take().ok_or("Unable to capture stdout")?;
+            task::spawn(read_responses(
+                stdout,
+                codec.boxed_clone(),
+                make_preprocessors(&self.preprocessors)?,
+                self.sink_url.clone(),
+                reply_tx.clone(),
+                self.origin_uri.clone(),
+            ));
+        }
+        self.child = Some(child);
+        Ok(())
+    }
+
+    async fn send_event(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        for value in event.value_iter() {
+            let raw = codec.encode(value)?;
+            for packet in postprocess(&mut self.postprocessors, event.ingest_ns, raw)? {
+                stdin.write_all(&packet).await?;
+            }
+        }
+        stdin.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Exec {
+    /// We acknowledge ourself
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let replies = match self.send_event(codec, &event).await {
+            Ok(()) if event.transactional => Some(vec![sink::Reply::Insight(event.insight_ack())]),
+            Ok(()) => None,
+            // the process is gone, restart it on the next signal
+            Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                debug!("[Sink::Exec] Error sending event: {}", e);
+                self.stdin = None;
+                self.child = None;
+                let mut replies = vec![sink::Reply::Insight(event.insight_trigger())];
+                if event.transactional {
+                    replies.push(sink::Reply::Insight(event.to_fail()));
+                }
+                Some(replies)
+            }
+            Err(e) => {
+                debug!("[Sink::Exec] Error sending event: {}", e);
+                event
+                    .transactional
+                    .then(|| vec![sink::Reply::Insight(event.to_fail())])
+            }
+        };
+        Ok(replies)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        sink_uid: u64,
+        sink_url: &TremorUrl,
+        codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        is_linked: bool,
+        reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        self.preprocessors = processors.pre.to_vec();
+        self.sink_url = sink_url.clone();
+        self.origin_uri = EventOriginUri {
+            uid: sink_uid,
+            scheme: "tremor-exec".to_string(),
+            host: hostname(),
+            port: None,
+            path: vec![self.config.process.command.clone()],
+        };
+        if is_linked {
+            self.codec = Some(codec.boxed_clone());
+            self.reply_tx = Some(reply_channel);
+        }
+        self.spawn()
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.child.is_none() {
+            match self.spawn() {
+                Ok(()) => Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                    signal.ingest_ns,
+                ))])),
+                Err(e) => {
+                    debug!("[Sink::Exec] Unable to restart process: {}", e);
+                    Ok(None)
+                }
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn terminate(&mut self) {
+        // closing stdin lets the process finish gracefully, it is killed
+        // once dropped if it doesn't exit in time
+        self.stdin = None;
+        if let Some(mut child) = self.child.take() {
+            match timeout(Duration::from_secs(5), child.status()).await {
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => error!("[Sink::Exec] Error waiting for process: {}", e),
+                Err(_) => warn!("[Sink::Exec] Process did not exit, killing it"),
+            }
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.child.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_channel::{bounded, Receiver};
+
+    async fn sink(
+        command: &str,
+        processors: Processors<'_>,
+        is_linked: bool,
+    ) -> Result<(Exec, Receiver<sink::Reply>)> {
+        let mut sink = Exec {
+            config: serde_yaml::from_str(command)?,
+            child: None,
+            stdin: None,
+            postprocessors: vec![],
+            preprocessors: vec![],
+            sink_url: TremorUrl::from_offramp_id("exec")?,
+            codec: None,
+            reply_tx: None,
+            origin_uri: EventOriginUri::default(),
+        };
+        let (tx, rx) = bounded(crate::QSIZE);
+        let codec = crate::codec::lookup("json")?;
+        let url = TremorUrl::from_offramp_id("exec")?;
+        sink.init(
+            0,
+            &url,
+            codec.as_ref(),
+            &HashMap::new(),
+            processors,
+            is_linked,
+            tx,
+        )
+        .await?;
+        Ok((sink, rx))
+    }
+
+    /// Sends a transactional event, returns the actions of the insights
+    async fn send(sink: &mut Exec, value: Value<'static>) -> Result<Vec<CbAction>> {
+        let mut codec = crate::codec::lookup("json")?;
+        let event = Event {
+            data: (value, Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        };
+        let replies = sink
+            .on_event("in", codec.as_mut(), &HashMap::new(), event)
+            .await?;
+        Ok(replies
+            .into_iter()
+            .flatten()
+            .filter_map(|reply| match reply {
+                sink::Reply::Insight(insight) => Some(insight.cb),
+                sink::Reply::Response(..) => None,
+            })
+            .collect())
+    }
+
+    async fn response(rx: &Receiver<sink::Reply>) -> Result<(String, Value<'static>)> {
+        match timeout(Duration::from_secs(5), rx.recv()).await {
+            Ok(Ok(sink::Reply::Response(port, event))) => {
+                Ok((port.to_string(), event.data.suffix().value().clone_static()))
+            }
+            Ok(Ok(sink::Reply::Insight(_))) => Err("Expected a response".into()),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err("Timed out waiting for a response".into()),
+        }
+    }
+
+    #[async_std::test]
+    async fn linked() -> Result<()> {
+        let lines = vec!["lines".to_string()];
+        let processors = Processors {
+            pre: &lines,
+            post: &lines,
+        };
+        let (mut sink, rx) = sink("command: cat", processors, true).await?;
+        assert_eq!(
+            vec![CbAction::Ack],
+            send(&mut sink, literal!({"snot": "badger"})).await?
+        );
+        assert_eq!(
+            vec![CbAction::Ack],
+            send(&mut sink, literal!([1, 2, 3])).await?
+        );
+        // what cat echoes is split into lines again and decoded
+        assert_eq!(
+            ("out".to_string(), literal!({"snot": "badger"})),
+            response(&rx).await?
+        );
+        assert_eq!(
+            ("out".to_string(), literal!([1, 2, 3])),
+            response(&rx).await?
+        );
+
+        assert_eq!(
+            vec![CbAction::Ack],
+            send(&mut sink, literal!("snot")).await?
+        );
+        sink.stdin
+            .as_mut()
+            .ok_or("no stdin")?
+            .write_all(b"{badger\n")
+            .await?;
+        assert_eq!(("out".to_string(), literal!("snot")), response(&rx).await?);
+        let (port, error) = response(&rx).await?;
+        assert_eq!("err", port);
+        assert!(error
+            .get_str("error")
+            .map_or(false, |e| e.starts_with("Invalid response")));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn restart() -> Result<()> {
+        let (mut sink, rx) = sink("command: cat", Processors::default(), false).await?;
+        assert!(sink.is_active());
+        // the process is gone, events fail until the next signal restarts it
+        if let Some(child) = sink.child.as_mut() {
+            child.kill()?;
+            child.status().await?;
+        }
+        assert_eq!(
+            vec![CbAction::Close, CbAction::Fail],
+            send(&mut sink, literal!({"snot": "badger"})).await?
+        );
+        assert!(!sink.is_active());
+        let replies = sink.on_signal(Event::default()).await?;
+        assert!(matches!(
+            replies.as_deref(),
+            Some([sink::Reply::Insight(Event {
+                cb: CbAction::Open,
+                ..
+            })])
+        ));
+        assert_eq!(
+            vec![CbAction::Ack],
+            send(&mut sink, literal!({"snot": "badger"})).await?
+        );
+        // not linked, so nothing is read back
+        assert!(rx.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Fluent Forward Offramp
+//!
+//! Forwards events to fluentd or Fluent Bit using the forward protocol in
+//! `Forward` mode. The event values are sent as records, the codec is not
+//! used. The tag is taken from `$fluent.tag` or the configured `tag`, the
+//! timestamp from `$fluent.time` (in nanoseconds) or the ingest time of the
+//! event.
+//!
+//! With `ack` enabled, every message carries a `chunk` id and events are only
+//! acked once the server acknowledged it.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::fluent::{decode_ack, encode_forward, Framer};
+use crate::sink::prelude::*;
+use crate::sink::tcp::{tls_connect, Conn, TLSConfig};
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use either::Either;
+use halfbrown::HashMap;
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub host: String,
+    /// port to connect to, defaults to 24224
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// tag of events without `$fluent.tag`, defaults to "tremor"
+    #[serde(default = "dflt_tag")]
+    pub tag: String,
+    /// wait for the server to acknowledge every message
+    #[serde(default)]
+    pub ack: bool,
+    /// time to wait for an acknowledgement, defaults to 30s
+    #[serde(default = "dflt_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+    #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
+    pub tls: Option<Either<TLSConfig, bool>>,
+}
+
+fn dflt_port() -> u16 {
+    24224
+}
+
+fn dflt_tag() -> String {
+    String::from("tremor")
+}
+
+fn dflt_ack_timeout_ms() -> u64 {
+    30_000
+}
+
+impl ConfigImpl for Config {}
+
+/// An offramp that forwards events to fluentd
+pub struct Fluent {
+    stream: Option<Box<dyn Conn>>,
+    config: Config,
+}
+
+impl offramp::Impl for Fluent {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                config,
+                stream: None,
+            }))
+        } else {
+            Err("Missing config for fluent offramp".into())
+        }
+    }
+}
+
+fn random_chunk() -> String {
+    let mut chunk = [0_u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut chunk);
+    base64::encode(chunk)
+}
+
+/// Reads the servers response, expecting it to acknowledge `chunk`
+async fn read_ack(stream: &mut dyn Conn, chunk: &str) -> Result<()> {
+    let mut buffer = [0; 1024];
+    let mut framer = Framer::default();
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        // ALLOW: we define n as part of the read
+        framer.extend(&buffer[0..n]);
+        if let Some(response) = framer.next_value()? {
+            return match decode_ack(&response) {
+                Some(ack) if ack == chunk => Ok(()),
+                _ => Err(format!("Unexpected response: {}", response).into()),
+            };
+        }
+    }
+}
+
+impl Fluent {
+    async fn send_event(&mut self, event: &Event) -> Result<()> {
+        let mut tags: BTreeMap<&str, Vec<(u64, &Value)>> = BTreeMap::new();
+        for (value, meta) in event.value_meta_iter() {
+            let fluent = meta.get("fluent");
+            let tag = fluent
+                .and_then(|m| m.get_str("tag"))
+                .unwrap_or_else(|| self.config.tag.as_str());
+            let time = fluent
+                .and_then(|m| m.get_u64("time"))
+                .unwrap_or(event.ingest_ns);
+            tags.entry(tag).or_default().push((time, value));
+        }
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        let ack_timeout = Duration::from_millis(self.config.ack_timeout_ms);
+        for (tag, entries) in &tags {
+            let chunk = if self.config.ack {
+                Some(random_chunk())
+            } else {
+                None
+            };
+            stream
+                .write_all(&encode_forward(tag, entries, chunk.as_deref())?)
+                .await?;
+            if let Some(chunk) = chunk {
+                timeout(ack_timeout, read_ack(stream.as_mut(), &chunk))
+                    .await
+                    .map_err(|_| {
+                        Error::from(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "Timed out waiting for ack",
+                        ))
+                    })??;
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(config: &Config) -> Result<Box<dyn Conn>> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        tls_connect(stream, config.host.as_str(), config.tls.as_ref()).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Fluent {
+    /// We acknowledge ourself
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let replies = match self.send_event(&event).await {
+            Ok(()) if event.transactional => Some(vec![sink::Reply::Insight(event.insight_ack())]),
+            Ok(()) => None,
+            // the connection is in an unknown state, reconnect on the next signal
+            Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                debug!("[Sink::Fluent] Error sending event: {}", e);
+                self.stream = None;
+                let mut replies = vec![sink::Reply::Insight(event.insight_trigger())];
+                if event.transactional {
+                    replies.push(sink::Reply::Insight(event.to_fail()));
+                }
+                Some(replies)
+            }
+            Err(e) => {
+                debug!("[Sink::Fluent] Error sending event: {}", e);
+                self.stream = None;
+                event
+                    .transactional
+                    .then(|| vec![sink::Reply::Insight(event.to_fail())])
+            }
+        };
+        Ok(replies)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.stream = Some(Self::connect(&self.config).await?);
+        Ok(())
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.stream.is_none() {
+            match Self::connect(&self.config).await {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                        signal.ingest_ns,
+                    ))]))
+                }
+                Err(e) => {
+                    debug!("[Sink::Fluent] Unable to reconnect: {}", e);
+                    Ok(None)
+                }
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.stream.is_some()
+    }
+}
@@ -18,26 +18,56 @@
 //!
 //! The `kafka` offramp allows persisting events to a kafka queue.
 //!
+//! Key and headers can be set per event via `$kafka.key` and `$kafka.headers`.
+//! Topic, partition and timestamp (in milliseconds since epoch) can be set via
+//! `$kafka.produce.topic`, `$kafka.produce.partition` and `$kafka.produce.timestamp`,
+//! so the metadata of the `kafka` onramp (`$kafka.topic`, ...) never reroutes
+//! the messages of a kafka to kafka pipeline.
+//!
+//! ## Transactions
+//!
+//! With `transactional_id` set all messages of an event (or batch) are written
+//! inside a single kafka transaction, the event is acked once the transaction
+//! got committed and failed if it got aborted.
+//!
+//! Setting `consumer_group_id` to the group of an upstream `kafka` onramp
+//! commits the consumed offsets from `$kafka.topic`, `$kafka.partition` and
+//! `$kafka.offset` inside the same transaction, so produced messages and
+//! consumed offsets become visible together. The onramp should be configured
+//! with `enable.auto.commit: "false"`.
+//!
+//! This is **not** exactly-once delivery: the group metadata sent with the
+//! offsets is not the one of the onramps consumer, which is not reachable from
+//! here, so the brokers can't fence a zombie instance after a rebalance and
+//! messages might still be produced more than once.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
 
 use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver, Sender};
-use halfbrown::HashMap;
+use halfbrown::{Entry, HashMap};
 use rdkafka::config::ClientConfig;
-use rdkafka::error::RDKafkaError;
-use rdkafka::producer::Producer;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::{KafkaResult, RDKafkaError};
+use rdkafka::producer::{DeliveryFuture, Producer};
 use rdkafka::{
     error::KafkaError,
     message::OwnedHeaders,
     producer::{FutureProducer, FutureRecord},
+    Offset, TopicPartitionList,
 };
 use std::{
+    collections::HashMap as StdMap,
+    convert::TryFrom,
     fmt,
     time::{Duration, Instant},
 };
 
+/// timeout for initializing, committing and aborting transactions
+const TXN_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Deserialize)]
 pub struct Config {
     /// list of brokers
@@ -52,6 +82,7 @@ pub struct Config {
     /// * `bootstrap.servers` - `brokers` from the config concatinated by `,`
     /// * `message.timeout.ms` - `"5000"`
     /// * `queue.buffering.max.ms` - `"0"` - don't buffer for lower latency (high)
+    /// * `partitioner` - derived from `partitioner`
     #[serde(default = "Default::default")]
     pub rdkafka_options: HashMap<String, String>,
     /// hostname to use, defaults to the hostname of the system
@@ -60,6 +91,49 @@ pub struct Config {
     /// key to use for messages, defaults to none
     #[serde(default = "Default::default")]
     pub key: Option<String>,
+    /// how to pick the partition for messages without `$kafka.produce.partition`,
+    /// defaults to `default`
+    #[serde(default = "Default::default")]
+    pub partitioner: Partitioner,
+    /// `transactional.id` of the producer, enables transactions
+    #[serde(default = "Default::default")]
+    pub transactional_id: Option<String>,
+    /// consumer group of the upstream kafka onramp, its offsets get committed
+    /// inside the transaction (without zombie fencing, see the module docs).
+    /// Requires `transactional_id`.
+    #[serde(default = "Default::default")]
+    pub consumer_group_id: Option<String>,
+}
+
+/// Partitioning strategy for messages without an explicit partition
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Partitioner {
+    /// librdkafkas default, a hash of the key or a random partition for messages without key
+    Default,
+    /// murmur2 hash of the key (compatible with the java client), random without key
+    Murmur2,
+    /// fnv1a hash of the key (compatible with sarama), random without key
+    Fnv1a,
+    /// cycle through all partitions of the topic
+    RoundRobin,
+}
+
+impl Default for Partitioner {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
+impl Partitioner {
+    /// the librdkafka `partitioner` setting implementing this strategy
+    fn rdkafka_partitioner(self) -> &'static str {
+        match self {
+            Self::Default | Self::RoundRobin => "consistent_random",
+            Self::Murmur2 => "murmur2_random",
+            Self::Fnv1a => "fnv1a_random",
+        }
+    }
 }
 
 impl Config {
@@ -73,13 +147,41 @@ impl Config {
             .set("client.id", &format!("tremor-{}-{}", self.hostname, 0))
             .set("bootstrap.servers", &self.brokers.join(","))
             .set("message.timeout.ms", "5000")
-            .set("queue.buffering.max.ms", "0"); // set to 0 for sending each message out immediately without kafka client internal batching --> low latency, busy network
+            .set("queue.buffering.max.ms", "0") // set to 0 for sending each message out immediately without kafka client internal batching --> low latency, busy network
+            .set("partitioner", self.partitioner.rdkafka_partitioner());
+        if let Some(transactional_id) = &self.transactional_id {
+            producer_config.set("transactional.id", transactional_id);
+        }
 
-        Ok(self
+        let producer: FutureProducer = self
             .rdkafka_options
             .iter()
             .fold(producer_config, |c: &mut ClientConfig, (k, v)| c.set(k, v))
-            .create()?)
+            .create()?;
+        Ok(producer)
+    }
+
+    /// a consumer that is only used to get the group metadata required
+    /// to commit offsets inside a transaction. It never joins the group,
+    /// so its metadata carries no member or generation to fence zombies with.
+    fn offsets_consumer(&self) -> Result<Option<BaseConsumer>> {
+        if let Some(group_id) = &self.consumer_group_id {
+            let mut consumer_config = ClientConfig::new();
+            consumer_config
+                .set("group.id", group_id)
+                .set("bootstrap.servers", &self.brokers.join(","))
+                .set("enable.auto.commit", "false");
+            let consumer: BaseConsumer = self
+                .rdkafka_options
+                .iter()
+                .fold(&mut consumer_config, |c: &mut ClientConfig, (k, v)| {
+                    c.set(k, v)
+                })
+                .create()?;
+            Ok(Some(consumer))
+        } else {
+            Ok(None)
+        }
     }
 }
 
@@ -98,6 +200,9 @@ pub struct Kafka {
     reply_tx: Sender<sink::Reply>,
     error_rx: Receiver<RDKafkaError>,
     error_tx: Sender<RDKafkaError>,
+    /// number of partitions and the next partition per topic for round robin partitioning
+    round_robin: HashMap<String, (i32, i32)>,
+    offsets_consumer: Option<BaseConsumer>,
 }
 
 impl fmt::Debug for Kafka {
@@ -110,21 +215,7 @@ impl offramp::Impl for Kafka {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
-            let producer = config.producer()?;
-            // Create the thread pool where the expensive computation will be performed.
-            let (dummy_tx, _) = bounded(1);
-
-            // TODO: does this need to be unbounded?
-            let (error_tx, error_rx) = bounded(crate::QSIZE);
-            Ok(SinkManager::new_box(Self {
-                sink_url: TremorUrl::from_offramp_id("kafka")?, // dummy
-                config,
-                producer,
-                postprocessors: vec![],
-                reply_tx: dummy_tx,
-                error_rx,
-                error_tx,
-            }))
+            Ok(SinkManager::new_box(Self::new(config)?))
         } else {
             Err("Kafka offramp requires a config".into())
         }
@@ -194,20 +285,290 @@ async fn wait_for_delivery(
     Ok(())
 }
 
+/// Fetches the number of partitions of `topic` on the blocking thread pool,
+/// the metadata request blocks for up to a second
+async fn partition_count(producer: FutureProducer, topic: String) -> Result<i32> {
+    task::spawn_blocking(move || {
+        let metadata = producer
+            .client()
+            .fetch_metadata(Some(&topic), Duration::from_secs(1))?;
+        let partitions = metadata
+            .topics()
+            .iter()
+            .find(|t| t.name() == topic)
+            .map_or(0, |t| t.partitions().len());
+        let partitions = i32::try_from(partitions)?;
+        if partitions == 0 {
+            return Err(format!("Kafka topic {} has no partitions", topic).into());
+        }
+        Ok(partitions)
+    })
+    .await
+}
+
+/// Per message settings taken from `$kafka`, routing from `$kafka.produce`
+#[derive(Default)]
+struct MessageMeta<'m, 'v> {
+    key: Option<&'m Value<'v>>,
+    headers: Option<&'m Value<'v>>,
+    topic: Option<&'m str>,
+    partition: Option<i32>,
+    timestamp: Option<i64>,
+    /// the consumed message as described by the `kafka` onramp
+    consumed: Option<(&'m str, i32, i64)>,
+}
+
+impl<'m, 'v> MessageMeta<'m, 'v> {
+    fn new(meta: &'m Value<'v>) -> Self {
+        meta.get_object("kafka")
+            .map_or_else(Self::default, |kafka| {
+                let produce = kafka.get("produce");
+                Self {
+                    key: kafka.get("key"),
+                    headers: kafka.get("headers"),
+                    topic: produce.and_then(|p| p.get_str("topic")),
+                    partition: produce.and_then(|p| p.get_i32("partition")),
+                    timestamp: produce.and_then(|p| p.get_i64("timestamp")),
+                    consumed: kafka
+                        .get("topic")
+                        .and_then(ValueAccess::as_str)
+                        .zip(kafka.get("partition").and_then(ValueAccess::as_i32))
+                        .zip(kafka.get("offset").and_then(ValueAccess::as_i64))
+                        .map(|((topic, partition), offset)| (topic, partition, offset)),
+                }
+            })
+    }
+
+    /// The record for `payload`, `$kafka.key` takes precedence over `default_key`
+    fn record<'r>(
+        &'r self,
+        topic: &'r str,
+        partition: Option<i32>,
+        default_key: Option<&'r str>,
+        payload: &'r [u8],
+    ) -> FutureRecord<'r, str, [u8]> {
+        let mut record = FutureRecord::to(topic).payload(payload);
+        if let Some(partition) = partition {
+            record = record.partition(partition);
+        }
+        if let Some(timestamp) = self.timestamp {
+            record = record.timestamp(timestamp);
+        }
+        if let Some(key) = self.key {
+            if let Some(key) = key.as_str() {
+                record = record.key(key);
+            }
+        } else if let Some(key) = default_key {
+            record = record.key(key);
+        }
+        if let Some(headers_obj) = self.headers.and_then(ValueAccess::as_object) {
+            let mut headers = OwnedHeaders::new_with_capacity(headers_obj.len());
+            for (key, val) in headers_obj.iter() {
+                if let Some(val_str) = val.as_str() {
+                    headers = headers.add(key, val_str);
+                }
+            }
+            record = record.headers(headers);
+        }
+        record
+    }
+}
+
+/// Runs a transactional operation of `producer` on the blocking thread pool,
+/// they block for up to `TXN_TIMEOUT`
+async fn blocking<F>(producer: &FutureProducer, op: F) -> Result<()>
+where
+    F: FnOnce(&FutureProducer) -> KafkaResult<()> + Send + 'static,
+{
+    let producer = producer.clone();
+    Ok(task::spawn_blocking(move || op(&producer)).await?)
+}
+
+/// Keeps the offset of the next message to consume per topic and partition
+fn add_offset(
+    offsets: &mut StdMap<(String, i32), Offset>,
+    topic: &str,
+    partition: i32,
+    offset: i64,
+) {
+    let next = Offset::Offset(offset + 1);
+    let committed = offsets
+        .entry((topic.to_string(), partition))
+        .or_insert(next);
+    if let (Offset::Offset(old), Offset::Offset(new)) = (committed, next) {
+        *old = (*old).max(new);
+    }
+}
+
 impl Kafka {
-    fn drain_fatal_errors(&mut self) -> Result<()> {
+    fn new(config: Config) -> Result<Self> {
+        if config.consumer_group_id.is_some() && config.transactional_id.is_none() {
+            return Err("Kafka offramp requires `transactional_id` for `consumer_group_id`".into());
+        }
+        let producer = config.producer()?;
+        let offsets_consumer = config.offsets_consumer()?;
+        // Create the thread pool where the expensive computation will be performed.
+        let (dummy_tx, _) = bounded(1);
+
+        // TODO: does this need to be unbounded?
+        let (error_tx, error_rx) = bounded(crate::QSIZE);
+        Ok(Self {
+            sink_url: TremorUrl::from_offramp_id("kafka")?, // dummy
+            config,
+            producer,
+            postprocessors: vec![],
+            reply_tx: dummy_tx,
+            error_rx,
+            error_tx,
+            round_robin: HashMap::new(),
+            offsets_consumer,
+        })
+    }
+
+    async fn drain_fatal_errors(&mut self) -> Result<()> {
         let mut handled = false;
         while let Ok(e) = self.error_rx.try_recv() {
             if !handled {
                 // only handle on first fatal error
-                self.handle_fatal_error(&e)?;
+                self.handle_fatal_error(&e).await?;
                 handled = true;
             }
         }
         Ok(())
     }
 
-    fn handle_fatal_error(&mut self, fatal_error: &RDKafkaError) -> Result<()> {
+    /// picks the next partition of `topic` in round robin fashion, the
+    /// number of partitions is fetched once per topic
+    async fn next_partition(&mut self, topic: &str) -> Result<i32> {
+        let (partitions, next) = match self.round_robin.entry(topic.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let partitions = partition_count(self.producer.clone(), topic.to_string()).await?;
+                entry.insert((partitions, 0))
+            }
+        };
+        let partition = *next;
+        *next = (*next + 1) % *partitions;
+        Ok(partition)
+    }
+
+    /// encodes all values of the event and enqueues the messages, the
+    /// consumed offsets are collected if they are committed with a transaction
+    async fn enqueue(
+        &mut self,
+        codec: &mut dyn Codec,
+        event: &Event,
+        offsets: &mut StdMap<(String, i32), Offset>,
+    ) -> Result<Vec<DeliveryFuture>> {
+        let mut delivery_futures = Vec::with_capacity(event.len()); // might not be enough
+        for (value, meta) in event.value_meta_iter() {
+            let encoded = codec.encode(value)?;
+            let processed =
+                postprocess(self.postprocessors.as_mut_slice(), event.ingest_ns, encoded)?;
+            let kafka_meta = MessageMeta::new(meta);
+            if self.offsets_consumer.is_some() {
+                if let Some((topic, partition, offset)) = kafka_meta.consumed {
+                    add_offset(offsets, topic, partition, offset);
+                }
+            }
+            let topic = kafka_meta
+                .topic
+                .unwrap_or_else(|| self.config.topic.as_str())
+                .to_string();
+            for payload in processed {
+                let partition = match kafka_meta.partition {
+                    Some(partition) => Some(partition),
+                    None if self.config.partitioner == Partitioner::RoundRobin => {
+                        Some(self.next_partition(&topic).await.map_err(|e| {
+                            Error::from(format!(
+                                "failed to determine partition for topic {}: {}",
+                                topic, e
+                            ))
+                        })?)
+                    }
+                    None => None,
+                };
+                let record =
+                    kafka_meta.record(&topic, partition, self.config.key.as_deref(), &payload);
+                // send out without blocking on delivery
+                let delivery_future = self.producer.send_result(record).map_err(|(e, _)| e)?;
+                delivery_futures.push(delivery_future);
+            }
+        }
+        Ok(delivery_futures)
+    }
+
+    /// writes all messages of the event inside a transaction, waits for them
+    /// to be delivered, adds the consumed offsets and commits the transaction
+    async fn send_transaction(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        self.producer.begin_transaction()?;
+        let mut offsets = StdMap::new();
+        let delivery_futures = self.enqueue(codec, event, &mut offsets).await?;
+        let results = futures::future::try_join_all(delivery_futures)
+            .await
+            .map_err(|_| Error::from("DeliveryFuture cancelled"))?;
+        if let Some((e, _)) = results.into_iter().find_map(std::result::Result::err) {
+            return Err(e.into());
+        }
+        let group_metadata = match &self.offsets_consumer {
+            Some(consumer) if !offsets.is_empty() => Some(
+                consumer
+                    .group_metadata()
+                    .ok_or_else(|| Error::from("Kafka consumer group metadata unavailable"))?,
+            ),
+            _ => None,
+        };
+        if let Some(group_metadata) = group_metadata {
+            let offsets = TopicPartitionList::from_topic_map(&offsets)?;
+            blocking(&self.producer, move |producer| {
+                producer.send_offsets_to_transaction(&offsets, &group_metadata, TXN_TIMEOUT)
+            })
+            .await?;
+        }
+        blocking(&self.producer, |producer| {
+            producer.commit_transaction(TXN_TIMEOUT)
+        })
+        .await
+    }
+
+    async fn abort_transaction(&mut self, e: &Error) {
+        error!("[Sink::{}] Aborting transaction: {}", &self.sink_url, e);
+        if let ErrorKind::KafkaError(KafkaError::Transaction(rd_error)) = e.kind() {
+            if rd_error.is_fatal() {
+                // the producer is unusable, e.g. it got fenced by another
+                // producer with the same transactional id
+                if let Err(e) = self.handle_fatal_error(rd_error).await {
+                    error!(
+                        "[Sink::{}] Failed to reinitiate client: {}",
+                        &self.sink_url, e
+                    );
+                }
+                return;
+            }
+        }
+        if let Err(e) = blocking(&self.producer, |producer| {
+            producer.abort_transaction(TXN_TIMEOUT)
+        })
+        .await
+        {
+            error!(
+                "[Sink::{}] Failed to abort transaction: {}",
+                &self.sink_url, e
+            );
+        }
+    }
+
+    async fn init_transactions(&self) -> Result<()> {
+        if self.config.transactional_id.is_some() {
+            blocking(&self.producer, |producer| {
+                producer.init_transactions(TXN_TIMEOUT)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_fatal_error(&mut self, fatal_error: &RDKafkaError) -> Result<()> {
         error!(
             "[Sink::{}] Fatal Error({:?}): {}",
             &self.sink_url,
@@ -217,6 +578,8 @@ impl Kafka {
 
         error!("[Sink::{}] Reinitiating client...", &self.sink_url);
         self.producer = self.config.producer()?;
+        self.round_robin.clear();
+        self.init_transactions().await?;
         error!("[Sink::{}] Client reinitiated.", &self.sink_url);
 
         Ok(())
@@ -233,68 +596,46 @@ impl Sink for Kafka {
         mut event: Event,
     ) -> ResultVec {
         // ensure we handle any fatal errors occured during last on_event invocation
-        self.drain_fatal_errors()?;
+        self.drain_fatal_errors().await?;
 
-        let ingest_ns = event.ingest_ns;
-        let mut delivery_futures = Vec::with_capacity(event.len()); // might not be enough
         let processing_start = Instant::now();
-        for (value, meta) in event.value_meta_iter() {
-            let encoded = codec.encode(value)?;
-            let processed = postprocess(self.postprocessors.as_mut_slice(), ingest_ns, encoded)?;
-            let meta_kafka_data = meta.get_object("kafka");
-            let mut meta_kafka_key = None;
-            let mut meta_kafka_headers = None;
-            if let Some(meta_data) = meta_kafka_data {
-                meta_kafka_key = meta_data.get("key");
-                meta_kafka_headers = meta_data.get("headers");
+        if self.config.transactional_id.is_some() {
+            // we need to wait for the transaction to complete before handling the next event
+            let res = self.send_transaction(codec, &event).await;
+            if let Err(e) = &res {
+                self.abort_transaction(e).await;
             }
-            for payload in processed {
-                // TODO: allow defining partition and timestamp in meta
-                let mut record = FutureRecord::to(self.config.topic.as_str());
-                record = record.payload(&payload);
-                if let Some(kafka_key) = meta_kafka_key {
-                    if let Some(kafka_key_str) = kafka_key.as_str() {
-                        record = record.key(kafka_key_str);
-                    }
-                } else if let Some(kafka_key) = &self.config.key {
-                    record = record.key(kafka_key.as_str());
-                }
-                if let Some(kafka_headers) = meta_kafka_headers {
-                    if let Some(headers_obj) = kafka_headers.as_object() {
-                        let mut headers = OwnedHeaders::new_with_capacity(headers_obj.len());
-                        for (key, val) in headers_obj.iter() {
-                            if let Some(val_str) = val.as_str() {
-                                headers = headers.add(key, val_str);
-                            }
-                        }
-                        record = record.headers(headers);
+            if event.transactional {
+                let insight = if res.is_ok() {
+                    let time = u64::try_from(processing_start.elapsed().as_millis())?;
+                    event.insight_ack_with_timing(time)
+                } else {
+                    event.to_fail()
+                };
+                return Ok(Some(vec![sink::Reply::Insight(insight)]));
+            }
+            return Ok(None);
+        }
+        let delivery_futures = match self.enqueue(codec, &event, &mut StdMap::new()).await {
+            Ok(delivery_futures) => delivery_futures,
+            Err(e) => {
+                error!(
+                    "[Sink::{}] failed to enqueue message: {}",
+                    &self.sink_url, e
+                );
+                if let ErrorKind::KafkaError(KafkaError::Transaction(e)) = e.kind() {
+                    if e.is_fatal() {
+                        // handle fatal errors right here, without enqueueing
+                        self.handle_fatal_error(e).await?;
                     }
                 }
-                // send out without blocking on delivery
-                match self.producer.send_result(record) {
-                    Ok(delivery_future) => {
-                        delivery_futures.push(delivery_future);
-                    }
-                    Err((e, _)) => {
-                        error!(
-                            "[Sink::{}] failed to enqueue message: {}",
-                            &self.sink_url, e
-                        );
-                        if let KafkaError::Transaction(e) = e {
-                            if e.is_fatal() {
-                                // handle fatal errors right here, without enqueueing
-                                self.handle_fatal_error(&e)?;
-                            }
-                        }
-                        // bail out with a CB fail on enqueue error
-                        if event.transactional {
-                            return Ok(Some(vec![sink::Reply::Insight(event.to_fail())]));
-                        }
-                        return Ok(None);
-                    }
+                // bail out with a CB fail on enqueue error
+                if event.transactional {
+                    return Ok(Some(vec![sink::Reply::Insight(event.to_fail())]));
                 }
+                return Ok(None);
             }
-        }
+        };
         let insight_event = if event.transactional {
             // we gonna change the success status later, if need be
             Some(event.insight_ack())
@@ -330,10 +671,10 @@ impl Sink for Kafka {
         self.postprocessors = make_postprocessors(processors.post)?;
         self.reply_tx = reply_channel;
         self.sink_url = sink_url.clone();
-        Ok(())
+        self.init_transactions().await
     }
     async fn on_signal(&mut self, _signal: Event) -> ResultVec {
-        self.drain_fatal_errors()?;
+        self.drain_fatal_errors().await?;
         Ok(None)
     }
     fn is_active(&self) -> bool {
@@ -355,3 +696,137 @@ impl Sink for Kafka {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rdkafka::message::Headers;
+
+    #[test]
+    fn per_event_settings() {
+        let meta = literal!({
+            "kafka": {
+                "produce": {
+                    "topic": "snot",
+                    "partition": 3,
+                    "timestamp": 1_633_024_800_000_i64
+                },
+                "key": "badger",
+                "headers": {"h": "v", "ignored": 1}
+            }
+        });
+        let kafka_meta = MessageMeta::new(&meta);
+        assert_eq!(Some("snot"), kafka_meta.topic);
+        let record = kafka_meta.record("snot", kafka_meta.partition, Some("default"), b"data");
+        assert_eq!("snot", record.topic);
+        assert_eq!(Some(3), record.partition);
+        assert_eq!(Some(1_633_024_800_000), record.timestamp);
+        assert_eq!(Some("badger"), record.key);
+        assert_eq!(Some(&b"data"[..]), record.payload);
+        assert_eq!(Some(1), record.headers.as_ref().map(Headers::count));
+    }
+
+    #[test]
+    fn defaults() {
+        let meta = literal!({});
+        let kafka_meta = MessageMeta::new(&meta);
+        assert_eq!(None, kafka_meta.topic);
+        let record = kafka_meta.record("topic", None, Some("default"), b"data");
+        assert_eq!("topic", record.topic);
+        assert_eq!(None, record.partition);
+        assert_eq!(None, record.timestamp);
+        assert_eq!(Some("default"), record.key);
+        assert!(record.headers.is_none());
+    }
+
+    #[test]
+    fn onramp_meta_does_not_route() {
+        // metadata as emitted by the kafka onramp
+        let meta = literal!({
+            "kafka": {
+                "topic": "consumed",
+                "partition": 2,
+                "offset": 41,
+                "timestamp": 1_633_024_800_000_i64,
+                "key": "badger"
+            }
+        });
+        let kafka_meta = MessageMeta::new(&meta);
+        assert_eq!(None, kafka_meta.topic);
+        assert_eq!(None, kafka_meta.partition);
+        assert_eq!(Some(("consumed", 2, 41)), kafka_meta.consumed);
+        let record = kafka_meta.record("topic", kafka_meta.partition, None, b"data");
+        assert_eq!("topic", record.topic);
+        assert_eq!(None, record.partition);
+        assert_eq!(None, record.timestamp);
+        assert_eq!(Some("badger"), record.key);
+    }
+
+    #[test]
+    fn consumed_offsets() {
+        let mut offsets = StdMap::new();
+        add_offset(&mut offsets, "snot", 0, 41);
+        add_offset(&mut offsets, "snot", 0, 3);
+        add_offset(&mut offsets, "snot", 1, 7);
+        assert_eq!(
+            Some(&Offset::Offset(42)),
+            offsets.get(&("snot".to_string(), 0))
+        );
+        assert_eq!(
+            Some(&Offset::Offset(8)),
+            offsets.get(&("snot".to_string(), 1))
+        );
+    }
+
+    fn transactional_sink(brokers: &str) -> Result<Kafka> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "brokers: [\"{}\"]\ntopic: tremor_test\ntransactional_id: tremor-test",
+            brokers
+        ))?;
+        Kafka::new(config)
+    }
+
+    fn transactional_event() -> Event {
+        Event {
+            data: (literal!({"snot": "badger"}), Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        }
+    }
+
+    async fn send(sink: &mut Kafka, codec: &mut dyn Codec) -> Result<CbAction> {
+        let replies = sink
+            .on_event("in", codec, &HashMap::new(), transactional_event())
+            .await?;
+        match replies.as_deref() {
+            Some([sink::Reply::Insight(insight)]) => Ok(insight.cb),
+            other => Err(format!("Expected a single insight, got {:?}", other).into()),
+        }
+    }
+
+    #[async_std::test]
+    async fn failed_transaction_fails_event() -> Result<()> {
+        // transactions never got initialized, so beginning one fails
+        let mut sink = transactional_sink("127.0.0.1:1")?;
+        let mut codec = crate::codec::lookup("json")?;
+        assert_eq!(CbAction::Fail, send(&mut sink, codec.as_mut()).await?);
+        Ok(())
+    }
+
+    /// Needs a single node broker on localhost:9092 that auto creates topics and
+    /// has `transaction.state.log.replication.factor` and `transaction.state.log.min.isr` set to 1
+    #[ignore]
+    #[async_std::test]
+    async fn transactions() -> Result<()> {
+        let mut sink = transactional_sink("localhost:9092")?;
+        sink.init_transactions().await?;
+        let mut json = crate::codec::lookup("json")?;
+        let mut influx = crate::codec::lookup("influx")?;
+        assert_eq!(CbAction::Ack, send(&mut sink, json.as_mut()).await?);
+        // the event is no influx metric, encoding it aborts the transaction
+        assert_eq!(CbAction::Fail, send(&mut sink, influx.as_mut()).await?);
+        // and the producer is ready for the next one
+        assert_eq!(CbAction::Ack, send(&mut sink, json.as_mut()).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,533 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Grafana Loki Offramp
+//!
+//! Pushes events as log lines to Loki. The line is the event encoded with the
+//! configured codec, its timestamp `$loki.timestamp` (in nanoseconds) or the
+//! ingest time of the event.
+//!
+//! Events are grouped into streams by their labels: the configured static
+//! `labels`, the values at the event paths of `label_paths` and the record in
+//! `$loki.labels`, later ones overwriting earlier ones. Events without any
+//! label are failed.
+//!
+//! Entries are batched until `batch_size` entries are collected or the first
+//! entry is older than `linger_ms`. Transactional events are acked once their
+//! batch got accepted and failed if it got rejected, e.g. for being out of
+//! order or due to rate limiting.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::sink::prelude::*;
+use async_channel::bounded;
+use halfbrown::HashMap;
+use http_types::headers;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PushRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub streams: Vec<StreamAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamAdapter {
+    #[prost(string, tag = "1")]
+    pub labels: String,
+    #[prost(message, repeated, tag = "2")]
+    pub entries: Vec<EntryAdapter>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EntryAdapter {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub line: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Protobuf,
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::Protobuf
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// url of the push endpoint, e.g. `http://loki:3100/loki/api/v1/push`
+    pub url: String,
+    /// static labels of all streams
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// labels taken from the event, label name to dot separated event path
+    #[serde(default)]
+    pub label_paths: HashMap<String, String>,
+    /// `protobuf` (snappy compressed) or `json`, defaults to `protobuf`
+    #[serde(default)]
+    pub format: Format,
+    /// maximum number of entries per push request, defaults to 1000
+    #[serde(default = "dflt_batch_size")]
+    pub batch_size: usize,
+    /// maximum time to wait before pushing a batch, defaults to 1000ms
+    #[serde(default = "dflt_linger_ms")]
+    pub linger_ms: u64,
+    /// tenant id sent as `X-Scope-OrgID`
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// additional headers sent with every request, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn dflt_batch_size() -> usize {
+    1000
+}
+
+fn dflt_linger_ms() -> u64 {
+    1000
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Debug, Default, PartialEq)]
+struct Stream {
+    labels: BTreeMap<String, String>,
+    /// timestamp and line of every entry
+    entries: Vec<(u64, String)>,
+}
+
+pub struct Loki {
+    sink_url: TremorUrl,
+    config: Config,
+    /// streams by their rendered labels
+    batch: BTreeMap<String, Stream>,
+    entries: usize,
+    /// when the first entry of the current batch was added
+    first: Option<Instant>,
+    /// events to ack or fail once the batch is pushed
+    causes: Vec<Event>,
+    reply_tx: Sender<sink::Reply>,
+}
+
+impl offramp::Impl for Loki {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self::new(config)?))
+        } else {
+            Err("Missing config for loki offramp".into())
+        }
+    }
+}
+
+fn label_value(value: &Value) -> String {
+    value.as_str().map_or_else(|| value.encode(), String::from)
+}
+
+/// Renders labels in the `{name="value"}` format
+fn render_labels(labels: &BTreeMap<String, String>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(", "))
+}
+
+/// Sorts the entries of every stream, Loki rejects out of order entries
+fn sorted(batch: &BTreeMap<String, Stream>) -> Vec<(&String, &Stream, Vec<&(u64, String)>)> {
+    batch
+        .iter()
+        .map(|(labels, stream)| {
+            let mut entries: Vec<_> = stream.entries.iter().collect();
+            entries.sort_by_key(|(ts, _)| *ts);
+            (labels, stream, entries)
+        })
+        .collect()
+}
+
+fn to_protobuf(batch: &BTreeMap<String, Stream>) -> Result<Vec<u8>> {
+    let streams = sorted(batch)
+        .into_iter()
+        .map(|(labels, _, entries)| StreamAdapter {
+            labels: labels.clone(),
+            entries: entries
+                .into_iter()
+                .map(|(ts, line)| EntryAdapter {
+                    timestamp: Some(Timestamp {
+                        seconds: i64::try_from(ts / 1_000_000_000).unwrap_or(i64::MAX),
+                        nanos: i32::try_from(ts % 1_000_000_000).unwrap_or_default(),
+                    }),
+                    line: line.clone(),
+                })
+                .collect(),
+        })
+        .collect();
+    snap::raw::Encoder::new()
+        .compress_vec(&PushRequest { streams }.encode_to_vec())
+        .map_err(|e| Error::from(format!("Snappy compression error: {}", e)))
+}
+
+fn to_json(batch: &BTreeMap<String, Stream>) -> Vec<u8> {
+    let streams: Vec<Value> = sorted(batch)
+        .into_iter()
+        .map(|(_, stream, entries)| {
+            let mut labels = Value::object_with_capacity(stream.labels.len());
+            for (k, v) in &stream.labels {
+                labels.try_insert(k.clone(), v.clone());
+            }
+            let values: Vec<Value> = entries
+                .into_iter()
+                .map(|(ts, line)| literal!([ts.to_string(), line.clone()]))
+                .collect();
+            literal!({
+                "stream": labels,
+                "values": values
+            })
+        })
+        .collect();
+    literal!({ "streams": streams }).encode().into_bytes()
+}
+
+impl Loki {
+    fn new(config: Config) -> Result<Self> {
+        let (dummy_tx, _) = bounded(1);
+        Ok(Self {
+            sink_url: TremorUrl::from_offramp_id("loki")?, // dummy
+            config,
+            batch: BTreeMap::new(),
+            entries: 0,
+            first: None,
+            causes: Vec::new(),
+            reply_tx: dummy_tx,
+        })
+    }
+
+    fn labels(&self, value: &Value, meta: &Value) -> BTreeMap<String, String> {
+        let mut labels: BTreeMap<String, String> = self
+            .config
+            .labels
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        for (name, path) in &self.config.label_paths {
+            let mut current = Some(value);
+            for segment in path.split('.') {
+                current = current.and_then(|v| v.get(segment));
+            }
+            if let Some(v) = current {
+                labels.insert(name.clone(), label_value(v));
+            }
+        }
+        if let Some(meta_labels) = meta.get("loki").and_then(|m| m.get_object("labels")) {
+            for (k, v) in meta_labels {
+                labels.insert(k.to_string(), label_value(v));
+            }
+        }
+        labels
+    }
+
+    /// Turns all values of the event into entries
+    fn entries(
+        &self,
+        codec: &mut dyn Codec,
+        event: &Event,
+    ) -> Result<Vec<(BTreeMap<String, String>, u64, String)>> {
+        let mut entries = Vec::new();
+        for (value, meta) in event.value_meta_iter() {
+            let labels = self.labels(value, meta);
+            if labels.is_empty() {
+                return Err("Loki requires at least one label per stream".into());
+            }
+            let ts = meta
+                .get("loki")
+                .and_then(|m| m.get_u64("timestamp"))
+                .unwrap_or(event.ingest_ns);
+            let line = String::from_utf8_lossy(&codec.encode(value)?).to_string();
+            entries.push((labels, ts, line));
+        }
+        Ok(entries)
+    }
+
+    async fn push(&self, batch: &BTreeMap<String, Stream>) -> Result<()> {
+        let mut req = surf::post(&self.config.url);
+        req = match self.config.format {
+            Format::Protobuf => req
+                .header(headers::CONTENT_TYPE, "application/x-protobuf")
+                .body(to_protobuf(batch)?),
+            Format::Json => req
+                .header(headers::CONTENT_TYPE, "application/json")
+                .body(to_json(batch)),
+        };
+        if let Some(tenant) = &self.config.tenant {
+            req = req.header("X-Scope-OrgID", tenant.as_str());
+        }
+        for (name, value) in &self.config.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let mut response = req.await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.body_string().await.unwrap_or_default();
+        let reason = if status == 429 {
+            "rate limited"
+        } else if body.contains("out of order") || body.contains("too far behind") {
+            "out of order entries rejected"
+        } else {
+            "request failed"
+        };
+        Err(format!("Loki push {} ({}): {}", reason, status, body.trim()).into())
+    }
+
+    /// Pushes the current batch and returns the insights for its events
+    async fn flush(&mut self) -> Vec<sink::Reply> {
+        if self.entries == 0 {
+            return self
+                .causes
+                .drain(..)
+                .map(|cause| sink::Reply::Insight(cause.insight(true)))
+                .collect();
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let causes = std::mem::take(&mut self.causes);
+        let entries = self.entries;
+        self.entries = 0;
+        self.first = None;
+        let ok = match self.push(&batch).await {
+            Ok(()) => {
+                debug!("[Sink::{}] Pushed {} entries", self.sink_url, entries);
+                true
+            }
+            Err(e) => {
+                error!("[Sink::{}] {}", self.sink_url, e);
+                false
+            }
+        };
+        causes
+            .into_iter()
+            .map(|cause| sink::Reply::Insight(cause.insight(ok)))
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Loki {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let entries = match self.entries(codec, &event) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("[Sink::{}] Invalid event: {}", self.sink_url, e);
+                return Ok(event
+                    .transactional
+                    .then(|| vec![sink::Reply::Insight(event.to_fail().insight(false))]));
+            }
+        };
+        for (labels, ts, line) in entries {
+            let stream = self
+                .batch
+                .entry(render_labels(&labels))
+                .or_insert_with(|| Stream {
+                    labels,
+                    entries: Vec::new(),
+                });
+            stream.entries.push((ts, line));
+            self.entries += 1;
+        }
+        self.first.get_or_insert_with(Instant::now);
+        if event.transactional {
+            self.causes.push(event.to_fail());
+        }
+        if self.entries >= self.config.batch_size {
+            Ok(Some(self.flush().await))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        let linger = Duration::from_millis(self.config.linger_ms);
+        if self.first.map_or(false, |first| first.elapsed() >= linger) {
+            Ok(Some(self.flush().await))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.sink_url = sink_url.clone();
+        self.reply_tx = reply_channel;
+        Ok(())
+    }
+
+    async fn terminate(&mut self) {
+        // the events of the pending batch still need their ack or fail
+        for reply in self.flush().await {
+            if self.reply_tx.send(reply).await.is_err() {
+                error!("[Sink::{}] Reply channel closed", self.sink_url);
+            }
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn batch() -> BTreeMap<String, Stream> {
+        let mut labels = BTreeMap::new();
+        labels.insert("job".to_string(), "tremor".to_string());
+        labels.insert("msg".to_string(), "say \"hi\"".to_string());
+        let mut batch = BTreeMap::new();
+        batch.insert(
+            render_labels(&labels),
+            Stream {
+                labels,
+                entries: vec![
+                    (2_000_000_001, "second".to_string()),
+                    (1_000_000_000, "first".to_string()),
+                ],
+            },
+        );
+        batch
+    }
+
+    #[test]
+    fn labels() {
+        let batch = batch();
+        assert_eq!(
+            Some(&r#"{job="tremor", msg="say \"hi\""}"#.to_string()),
+            batch.keys().next()
+        );
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let mut body = to_json(&batch());
+        assert_eq!(
+            literal!({"streams": [{
+                "stream": {"job": "tremor", "msg": "say \"hi\""},
+                "values": [["1000000000", "first"], ["2000000001", "second"]]
+            }]}),
+            tremor_value::parse_to_value(&mut body)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn protobuf() -> Result<()> {
+        let body = to_protobuf(&batch())?;
+        let data = snap::raw::Decoder::new()
+            .decompress_vec(&body)
+            .map_err(|e| Error::from(e.to_string()))?;
+        let request =
+            PushRequest::decode(data.as_slice()).map_err(|e| Error::from(e.to_string()))?;
+        assert_eq!(1, request.streams.len());
+        let entries = &request.streams[0].entries;
+        assert_eq!("first", entries[0].line);
+        assert_eq!(
+            Some(Timestamp {
+                seconds: 2,
+                nanos: 1
+            }),
+            entries[1].timestamp
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn terminate_fails_pending_batch() -> Result<()> {
+        // nothing listens on port 1, so pushing the batch fails
+        let config: Config = serde_yaml::from_str(
+            "url: http://127.0.0.1:1/loki/api/v1/push\nlabels:\n  job: tremor",
+        )?;
+        let mut sink = Loki::new(config)?;
+        let (tx, rx) = bounded(crate::QSIZE);
+        sink.reply_tx = tx;
+        let mut codec = crate::codec::lookup("json")?;
+        let event = Event {
+            data: (literal!({"snot": "badger"}), Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        };
+        let replies = sink
+            .on_event("in", codec.as_mut(), &HashMap::new(), event)
+            .await?;
+        assert!(replies.is_none());
+        sink.terminate().await;
+        match rx.try_recv() {
+            Ok(sink::Reply::Insight(insight)) => assert_eq!(CbAction::Fail, insight.cb),
+            other => return Err(format!("Expected an insight, got {:?}", other).into()),
+        }
+        assert!(rx.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # MQTT Offramp
+//!
+//! Publishes events to an MQTT broker. Topic, QoS and retain flag can be
+//! overwritten per event via `$mqtt.topic`, `$mqtt.qos` and `$mqtt.retain`.
+//!
+//! Transactional events are acked once the broker acknowledged all of their
+//! messages (`PUBACK` for QoS 1, `PUBCOMP` for QoS 2), QoS 0 messages are acked
+//! once they are written to the connection. Messages in flight when the
+//! connection breaks are retransmitted by the client after reconnecting, their
+//! events are failed only if they are still unacknowledged when the offramp
+//! terminates.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::mqtt::{self, Client, ConnectConfig, Notification};
+use crate::sink::prelude::*;
+use async_channel::{unbounded, Receiver};
+use halfbrown::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub connect: ConnectConfig,
+    /// topic to publish to
+    pub topic: String,
+    /// QoS to publish with, defaults to 1
+    #[serde(default = "dflt_qos")]
+    pub qos: u8,
+    /// publish retained messages
+    #[serde(default)]
+    pub retain: bool,
+}
+
+fn dflt_qos() -> u8 {
+    1
+}
+
+impl ConfigImpl for Config {}
+
+/// A message handed to the client, carrying the insight to send once it got
+/// acknowledged if it is the last message of a transactional event
+struct InFlight {
+    qos: u8,
+    cause: Option<Event>,
+}
+
+pub struct Mqtt {
+    sink_url: TremorUrl,
+    config: Config,
+    postprocessors: Postprocessors,
+    client: Option<Client>,
+    in_flight: Option<Sender<InFlight>>,
+}
+
+impl offramp::Impl for Mqtt {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            // fail early on invalid QoS
+            mqtt::qos(config.qos)?;
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("mqtt")?,
+                config,
+                postprocessors: vec![],
+                client: None,
+                in_flight: None,
+            }))
+        } else {
+            Err("Missing config for mqtt offramp".into())
+        }
+    }
+}
+
+async fn send_insight(reply_tx: &Sender<sink::Reply>, cause: Event, ack: bool, sink_url: &str) {
+    if let Err(e) = reply_tx
+        .send(sink::Reply::Insight(cause.insight(ack)))
+        .await
+    {
+        error!(
+            "[Sink::{}] Error sending insight via reply channel: {}",
+            sink_url, e
+        );
+    }
+}
+
+impl Mqtt {
+    /// Publishes all values of the event, the `cause` is handed to the event loop
+    /// together with the last message
+    async fn publish(
+        &mut self,
+        codec: &mut dyn Codec,
+        event: &Event,
+        cause: &mut Option<Event>,
+    ) -> Result<()> {
+        let (client, in_flight) = match (&self.client, &self.in_flight) {
+            (Some(client), Some(in_flight)) => (client, in_flight),
+            _ => return Err("mqtt offramp is not connected".into()),
+        };
+        let mut messages = Vec::new();
+        for (value, meta) in event.value_meta_iter() {
+            let mqtt_meta = meta.get("mqtt");
+            let topic = mqtt_meta
+                .and_then(|m| m.get_str("topic"))
+                .unwrap_or(&self.config.topic)
+                .to_string();
+            let qos = match mqtt_meta.and_then(|m| m.get_u64("qos")) {
+                Some(qos) => u8::try_from(qos)?,
+                None => self.config.qos,
+            };
+            let retain = mqtt_meta
+                .and_then(|m| m.get_bool("retain"))
+                .unwrap_or(self.config.retain);
+            let encoded = codec.encode(value)?;
+            for payload in
+                postprocess(self.postprocessors.as_mut_slice(), event.ingest_ns, encoded)?
+            {
+                messages.push((topic.clone(), mqtt::qos(qos)?, qos, retain, payload));
+            }
+        }
+
+        let last = messages.len();
+        for (i, (topic, qos, level, retain, payload)) in messages.into_iter().enumerate() {
+            let cause = if i + 1 == last { cause.take() } else { None };
+            // register the message before handing it to the client so the event loop
+            // sees them in the same order as the outgoing publishes
+            in_flight
+                .send(InFlight { qos: level, cause })
+                .await
+                .map_err(|_| Error::from("mqtt event loop stopped"))?;
+            client.publish(&topic, qos, retain, payload).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Mqtt {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let mut cause = if event.transactional {
+            Some(event.to_fail())
+        } else {
+            None
+        };
+        // a cause that is left over was not handed to the event loop
+        match self.publish(codec, &event, &mut cause).await {
+            Ok(()) => Ok(cause.map(|cause| vec![sink::Reply::Insight(cause.insight(true))])),
+            Err(e) => {
+                error!("[Sink::{}] failed to publish: {}", self.sink_url, e);
+                Ok(cause.map(|cause| vec![sink::Reply::Insight(cause.insight(false))]))
+            }
+        }
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        Ok(None)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        _is_linked: bool,
+        reply_channel: Sender<Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        self.sink_url = sink_url.clone();
+
+        let client_id = format!("tremor-{}-{}", hostname(), sink_uid);
+        let (client, mut eventloop) = self.config.connect.connect(&client_id, false)?;
+        let (tx, rx): (Sender<InFlight>, Receiver<InFlight>) = unbounded();
+        let sink_url = sink_url.to_string();
+        task::spawn(async move {
+            // messages written to the connection, waiting for the broker to acknowledge them
+            let mut sent: HashMap<u16, Option<Event>> = HashMap::new();
+            loop {
+                match eventloop.poll().await {
+                    // the client retransmits unacknowledged messages with their packet id
+                    // after a reconnect, they are already registered
+                    Ok(Notification::Sent(pkid)) if pkid != 0 && sent.contains_key(&pkid) => {
+                        debug!("[Sink::{}] Retransmitted message {}", sink_url, pkid);
+                    }
+                    Ok(Notification::Sent(pkid)) => match rx.try_recv() {
+                        Ok(InFlight {
+                            qos: 0,
+                            cause: Some(cause),
+                        }) => send_insight(&reply_channel, cause, true, &sink_url).await,
+                        Ok(InFlight {
+                            qos: 0,
+                            cause: None,
+                        }) => (),
+                        Ok(InFlight { cause, .. }) => {
+                            sent.insert(pkid, cause);
+                        }
+                        Err(_) => {
+                            error!("[Sink::{}] Unknown outgoing message {}", sink_url, pkid);
+                        }
+                    },
+                    Ok(Notification::Acked(pkid)) => {
+                        if let Some(Some(cause)) = sent.remove(&pkid) {
+                            send_insight(&reply_channel, cause, true, &sink_url).await;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(_) if rx.is_closed() => break,
+                    Err(e) => {
+                        // the next poll reconnects and retransmits the messages in `sent`
+                        warn!("[Sink::{}] {}", sink_url, e);
+                        task::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+            for (_, cause) in sent.drain() {
+                if let Some(cause) = cause {
+                    send_insight(&reply_channel, cause, false, &sink_url).await;
+                }
+            }
+        });
+        self.client = Some(client);
+        self.in_flight = Some(tx);
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    async fn terminate(&mut self) {
+        if let Some(in_flight) = self.in_flight.take() {
+            in_flight.close();
+        }
+        if let Some(client) = self.client.take() {
+            client.disconnect().await;
+        }
+    }
+}
@@ -17,12 +17,16 @@
 use std::iter::FromIterator;
 use std::time::Instant;
 
+use crate::ramp::nats::{inbox, messages, pub_ack};
 use crate::sink::prelude::*;
 use async_channel::{bounded, Receiver};
 use async_nats::Connection as NatsConnection;
 use async_nats::Headers;
+use async_nats::Message;
 use async_nats::Options as NatsOptions;
+use async_std::future::timeout;
 use halfbrown::HashMap;
+use std::time::Duration;
 use tremor_pipeline::OpMeta;
 
 // struct containing connection options
@@ -98,6 +102,16 @@ pub struct Config {
     // headers to use for the messages
     #[serde(default = "Default::default")]
     pub headers: HashMap<String, Vec<String>>,
+    // publish to JetStream, events are acked once the stream confirmed them
+    #[serde(default = "Default::default")]
+    pub jetstream: bool,
+    // time to wait for the confirmation of JetStream, defaults to 5s
+    #[serde(default = "default_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+}
+
+fn default_ack_timeout_ms() -> u64 {
+    5_000
 }
 
 impl Config {
@@ -121,6 +135,10 @@ pub struct Nats {
     error_rx: Receiver<()>,
     error_tx: Sender<()>,
     merged_meta: OpMeta,
+    // inbox receiving the confirmations of JetStream publishes
+    ack_inbox: String,
+    acks: Option<Receiver<Message>>,
+    ack_count: u64,
 }
 
 impl offramp::Impl for Nats {
@@ -138,6 +156,9 @@ impl offramp::Impl for Nats {
                 error_rx,
                 error_tx,
                 merged_meta: OpMeta::default(),
+                ack_inbox: inbox(),
+                acks: None,
+                ack_count: 0,
             }))
         } else {
             Err("Nats offramp requires a configuration.".into())
@@ -149,6 +170,7 @@ impl Nats {
     fn handle_connection(&mut self) -> Result<Option<&NatsConnection>> {
         while let Ok(()) = self.error_rx.try_recv() {
             self.connection = None;
+            self.acks = None;
         }
         if self.connection.is_none() {
             self.connection = Some(self.config.connection()?);
@@ -156,6 +178,78 @@ impl Nats {
         }
         Ok(None)
     }
+
+    /// The configured headers merged with the ones from `$nats.headers`
+    fn headers(&self, meta: Option<&Value>) -> Option<Headers> {
+        let headers = meta.and_then(|v| v.get_object("headers"));
+        let mut key_val: Vec<(&str, &str)> = Vec::with_capacity(
+            self.config.headers.len() + headers.map(HashMap::len).unwrap_or_default(),
+        );
+        for (key, val) in &self.config.headers {
+            for ele in val.iter() {
+                key_val.push((key.as_str(), ele.as_str()));
+            }
+        }
+        if let Some(headers) = headers {
+            for (key, val) in headers.iter().filter_map(|(k, v)| Some((k, v.as_array()?))) {
+                for ele in val.iter().filter_map(value_trait::ValueAccess::as_str) {
+                    key_val.push((key, ele));
+                }
+            }
+        }
+        if key_val.is_empty() {
+            None
+        } else {
+            Some(Headers::from_iter(key_val))
+        }
+    }
+
+    /// Publishes the event to JetStream and waits for every message to be confirmed
+    async fn publish_jetstream(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        if self.acks.is_none() {
+            let subject = format!("{}.*", self.ack_inbox);
+            self.acks = Some(messages(connection.subscribe(&subject).await?));
+        }
+        let acks = self
+            .acks
+            .as_ref()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        let ack_timeout = Duration::from_millis(self.config.ack_timeout_ms);
+        for (value, meta) in event.value_meta_iter() {
+            let encoded = codec.encode(value)?;
+            let processed = postprocess(&mut self.postprocessors, event.ingest_ns, encoded)?;
+            let headers = self.headers(meta.get("nats"));
+            for payload in processed {
+                // drop late confirmations of earlier messages that timed out
+                while acks.try_recv().is_ok() {}
+                self.ack_count += 1;
+                let reply = format!("{}.{}", self.ack_inbox, self.ack_count);
+                connection
+                    .publish_with_reply_or_headers(
+                        self.config.subject.as_str(),
+                        Some(reply.as_str()),
+                        headers.as_ref(),
+                        payload,
+                    )
+                    .await?;
+                // receiving from the channel can time out without losing a confirmation
+                let mut response = loop {
+                    match timeout(ack_timeout, acks.recv()).await {
+                        Ok(Ok(msg)) if msg.subject == reply => break msg,
+                        Ok(Ok(_)) => continue,
+                        Ok(Err(_)) => return Err(ErrorKind::NoSocket.into()),
+                        Err(_) => return Err("Timed out waiting for PubAck".into()),
+                    }
+                };
+                pub_ack(&mut response.data)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -166,50 +260,52 @@ impl Sink for Nats {
         _input: &str,
         codec: &mut dyn Codec,
         _codec_map: &HashMap<String, Box<dyn Codec>>,
-        event: Event,
+        mut event: Event,
     ) -> ResultVec {
         self.handle_connection()?;
+        if self.config.jetstream {
+            let processing_start = Instant::now();
+            let replies = match self.publish_jetstream(codec, &event).await {
+                Ok(()) if event.transactional => Some(vec![sink::Reply::Insight(
+                    event.insight_ack_with_timing(processing_start.elapsed().as_millis() as u64),
+                )]),
+                Ok(()) => None,
+                Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                    error!("[Sink::{}] failed to send message: {}", &self.sink_url, e);
+                    self.connection = None;
+                    self.acks = None;
+                    let mut replies = vec![sink::Reply::Insight(event.insight_trigger())];
+                    if event.transactional {
+                        replies.push(sink::Reply::Insight(event.to_fail()));
+                    }
+                    Some(replies)
+                }
+                Err(e) => {
+                    error!("[Sink::{}] message not confirmed: {}", &self.sink_url, e);
+                    event
+                        .transactional
+                        .then(|| vec![sink::Reply::Insight(event.to_fail())])
+                }
+            };
+            return Ok(replies);
+        }
         let ingest_ns = event.ingest_ns;
         let processing_start = Instant::now();
         // evaluate here to avoid borrowing again while borrowed.
         let config_reply = self.config.reply.as_deref();
         let op_meta = &event.op_meta;
         self.merged_meta.merge(op_meta.clone());
-        if let Some(connection) = &mut self.connection {
+        if let Some(connection) = &self.connection {
             for (value, meta) in event.value_meta_iter() {
                 let encoded = codec.encode(value)?;
                 let processed =
                     postprocess(self.postprocessors.as_mut_slice(), ingest_ns, encoded)?;
                 let nats_meta = meta.get("nats");
-                let headers = nats_meta.and_then(|v| v.get_object("headers"));
                 let reply = nats_meta.and_then(|v| v.get_str("reply"));
+                let message_headers = self.headers(nats_meta);
                 for payload in processed {
                     // prepare message reply
                     let message_reply = reply.or(config_reply);
-                    // prepare message headers
-                    let mut key_val: Vec<(&str, &str)> = Vec::with_capacity(
-                        self.config.headers.len() + headers.map(HashMap::len).unwrap_or_default(),
-                    );
-                    for (key, val) in &self.config.headers {
-                        for ele in val.iter() {
-                            key_val.push((key.as_str(), ele.as_str()));
-                        }
-                    }
-                    if let Some(headers) = headers {
-                        for (key, val) in
-                            headers.iter().filter_map(|(k, v)| Some((k, v.as_array()?)))
-                        {
-                            for ele in val.iter().filter_map(value_trait::ValueAccess::as_str) {
-                                key_val.push((key, ele));
-                            }
-                        }
-                    }
-                    let message_headers = if key_val.is_empty() {
-                        None
-                    } else {
-                        Some(Headers::from_iter(key_val))
-                    };
-
                     let publish_result = connection
                         .publish_with_reply_or_headers(
                             self.config.subject.as_str(),
@@ -18,18 +18,43 @@
 //!
 //! Writes events to a `PostgreSQL` and `TimescaleDB` database
 //!
+//! All rows of an event (or batch) going to the same table are written with a
+//! single multi-row `INSERT`, all statements of an event run in one transaction.
+//! The table can be overwritten per event via `$postgres.table`, a schema can
+//! be given as `<schema>.<table>`. Table and column names are used verbatim,
+//! so unquoted names are folded to lowercase by `PostgreSQL`. With
+//! `quote_identifiers: true` they are quoted, which makes them case sensitive
+//! and keeps untrusted `$postgres.table` values from injecting SQL.
+//!
+//! Upserts that update conflicting rows keep only the last row per conflict key
+//! of a batch, as a single `INSERT .. ON CONFLICT DO UPDATE` can't affect the
+//! same row twice.
+//!
+//! Without `columns` events are expected in the intermediate representation
+//! used by the `postgres` onramp, a record of `{"name", "fieldType", "value"}`
+//! records. With `columns` the values are taken from the given paths of the
+//! event and converted to the column type.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
 
-use crate::ramp::postgres::{json_to_record, Record};
+use crate::ramp::postgres::{coerce, field_type, json_to_record, Record};
 use crate::sink::prelude::*;
+use async_compat::Compat;
 use halfbrown::HashMap;
-use postgres::{Client, NoTls};
+use postgres::types::{ToSql, Type};
+use std::collections::HashSet;
+use tokio_postgres::{Client, NoTls};
+
+/// maximum number of parameters of a single statement
+const MAX_PARAMS: usize = 65_535;
 
 pub struct Postgres {
     pub config: Config,
-    client: Option<postgres::Client>,
+    /// column names, paths and types of the configured `columns`
+    mapping: Vec<(String, Vec<String>, Type)>,
+    client: Option<Client>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -39,18 +64,69 @@ pub struct Config {
     pub user: String,
     pub password: String,
     pub dbname: String,
+    /// table to write to, can be overwritten per event via `$postgres.table`
     pub table: String,
+    /// columns to write and where to find their values in the event
+    #[serde(default = "Default::default")]
+    pub columns: Vec<Column>,
+    /// turns inserts into upserts
+    #[serde(default = "Default::default")]
+    pub upsert: Option<Upsert>,
+    /// quote table and column names, making them case sensitive. Defaults to false
+    #[serde(default = "Default::default")]
+    pub quote_identifiers: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Column {
+    /// column name
+    pub name: String,
+    /// dot separated path of the value in the event, defaults to the column name
+    #[serde(default = "Default::default")]
+    pub path: Option<String>,
+    /// column type as used in the intermediate representation, e.g. `INT8` or `TEXT`
+    #[serde(rename = "type")]
+    pub field_type: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Upsert {
+    /// columns of the unique constraint, used as `ON CONFLICT (..)` target
+    pub conflict_columns: Vec<String>,
+    /// columns to update on conflict, defaults to all other columns.
+    /// If empty conflicting rows are ignored (`DO NOTHING`).
+    #[serde(default = "Default::default")]
+    pub update_columns: Option<Vec<String>>,
 }
 
 impl ConfigImpl for Config {}
 
+impl Config {
+    fn mapping(&self) -> Result<Vec<(String, Vec<String>, Type)>> {
+        self.columns
+            .iter()
+            .map(|c| {
+                let path = c.path.as_deref().unwrap_or(&c.name);
+                let path = path.split('.').map(String::from).collect();
+                Ok((c.name.clone(), path, field_type(&c.field_type)?))
+            })
+            .collect()
+    }
+}
+
 impl offramp::Impl for Postgres {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
-
+            let mapping = config.mapping()?;
+            if let Some(upsert) = &config.upsert {
+                if upsert.conflict_columns.is_empty() {
+                    return Err("postgres offramp requires `conflict_columns` to upsert".into());
+                }
+            }
             Ok(SinkManager::new_box(Self {
                 config,
+                mapping,
                 client: None,
             }))
         } else {
@@ -59,14 +135,196 @@ impl offramp::Impl for Postgres {
     }
 }
 
-fn init_cli(config: &Config) -> std::result::Result<postgres::Client, postgres::Error> {
+async fn init_cli(config: &Config) -> Result<Client> {
     let conn_str = format!(
         "host={} user={} password={} port={} dbname={}",
         config.host, config.user, config.password, config.port, config.dbname
     );
-    let cli = Client::connect(&conn_str, NoTls)?;
+    let (client, connection) = Compat::new(tokio_postgres::connect(&conn_str, NoTls)).await?;
+    task::spawn(async move {
+        if let Err(e) = Compat::new(connection).await {
+            error!("connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+/// Quotes an identifier if `quoted` is set
+fn quote(ident: &str, quoted: bool) -> String {
+    if quoted {
+        format!("\"{}\"", ident.replace('"', "\"\""))
+    } else {
+        ident.to_string()
+    }
+}
+
+/// Quotes a table name, optionally qualified with a schema, if `quoted` is set
+fn quote_table(table: &str, quoted: bool) -> String {
+    table
+        .split('.')
+        .map(|ident| quote(ident, quoted))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// A single row, the values are stored owned so the `Record`s can borrow them
+struct Row {
+    columns: Vec<String>,
+    values: Vec<(Type, Value<'static>)>,
+}
+
+/// Rows for the same table and columns
+struct Batch {
+    table: String,
+    columns: Vec<String>,
+    rows: Vec<Vec<(Type, Value<'static>)>>,
+}
+
+impl Batch {
+    /// the rows to write, only the last row per `conflict_columns` value if given
+    fn rows(&self, conflict_columns: &[String]) -> Vec<&Vec<(Type, Value<'static>)>> {
+        let key_idx: Option<Vec<usize>> = conflict_columns
+            .iter()
+            .map(|c| self.columns.iter().position(|name| name == c))
+            .collect();
+        match key_idx {
+            Some(key_idx) if !key_idx.is_empty() => {
+                let mut seen = HashSet::new();
+                let mut rows: Vec<_> = self
+                    .rows
+                    .iter()
+                    .rev()
+                    .filter(|row| {
+                        let key: Vec<String> = key_idx
+                            .iter()
+                            .filter_map(|i| row.get(*i))
+                            .map(|(_, v)| v.encode())
+                            .collect();
+                        seen.insert(key)
+                    })
+                    .collect();
+                rows.reverse();
+                rows
+            }
+            _ => self.rows.iter().collect(),
+        }
+    }
+
+    fn statements(&self, upsert: Option<&Upsert>, quoted: bool) -> Vec<(String, Vec<Record>)> {
+        // the conflict key of rows that get updated, `DO NOTHING` copes with duplicates
+        let mut dedup_key: &[String] = &[];
+        let conflict = upsert.map_or_else(String::new, |upsert| {
+            let set = |c: &String| {
+                let c = quote(c, quoted);
+                format!("{} = EXCLUDED.{}", c, c)
+            };
+            let update: Vec<String> = upsert.update_columns.as_ref().map_or_else(
+                || {
+                    self.columns
+                        .iter()
+                        .filter(|c| !upsert.conflict_columns.contains(c))
+                        .map(set)
+                        .collect()
+                },
+                |columns| columns.iter().map(set).collect(),
+            );
+            let target: Vec<String> = upsert
+                .conflict_columns
+                .iter()
+                .map(|c| quote(c, quoted))
+                .collect();
+            let target = target.join(",");
+            if update.is_empty() {
+                format!(" ON CONFLICT ({}) DO NOTHING", target)
+            } else {
+                dedup_key = upsert.conflict_columns.as_slice();
+                format!(
+                    " ON CONFLICT ({}) DO UPDATE SET {}",
+                    target,
+                    update.join(",")
+                )
+            }
+        });
+        let table = quote_table(&self.table, quoted);
+        let columns: Vec<String> = self.columns.iter().map(|c| quote(c, quoted)).collect();
+        let columns = columns.join(",");
+        let width = self.columns.len().max(1);
+        self.rows(dedup_key)
+            .chunks(MAX_PARAMS / width)
+            .map(|rows| {
+                let mut params = Vec::with_capacity(rows.len());
+                let mut records = Vec::with_capacity(rows.len() * width);
+                for &row in rows {
+                    let placeholders: Vec<String> = (0..row.len())
+                        .map(|i| format!("${}", records.len() + i + 1))
+                        .collect();
+                    params.push(format!("({})", placeholders.join(",")));
+                    for ((t, value), name) in row.iter().zip(&self.columns) {
+                        records.push(Record {
+                            t: t.clone(),
+                            value,
+                            name,
+                        });
+                    }
+                }
+                let q = format!(
+                    "INSERT INTO {} ({}) VALUES {}{};",
+                    table,
+                    columns,
+                    params.join(","),
+                    conflict
+                );
+                (q, records)
+            })
+            .collect()
+    }
+}
+
+impl Postgres {
+    /// turns a value into a row, either via the configured columns or from
+    /// the intermediate representation
+    fn row(&self, value: &Value) -> Result<Row> {
+        if self.mapping.is_empty() {
+            let kv = value
+                .as_object()
+                .ok_or_else(|| Error::from("Postgres offramp expects a record"))?;
+            // sort the fields so rows with the same fields end up in the same batch
+            let mut fields: Vec<_> = kv.iter().collect();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let mut columns = Vec::with_capacity(fields.len());
+            let mut values = Vec::with_capacity(fields.len());
+            for (field, value) in fields {
+                let record = json_to_record(value)
+                    .map_err(|e| Error::from(format!("Could not convert json to record: {}", e)))?;
+                columns.push(field.to_string());
+                values.push((record.t, record.value.clone_static()));
+            }
+            Ok(Row { columns, values })
+        } else {
+            let mut columns = Vec::with_capacity(self.mapping.len());
+            let mut values = Vec::with_capacity(self.mapping.len());
+            for (name, path, t) in &self.mapping {
+                let mut current = Some(value);
+                for segment in path {
+                    current = current.and_then(|v| v.get(segment.as_str()));
+                }
+                let value = current.map_or_else(|| Ok(Value::null()), |v| coerce(t, v))?;
+                columns.push(name.clone());
+                values.push((t.clone(), value));
+            }
+            Ok(Row { columns, values })
+        }
+    }
 
-    Ok(cli)
+    /// (re)connects a closed connection
+    async fn connect(&mut self) -> Result<()> {
+        if self.client.as_ref().map_or(true, Client::is_closed) {
+            self.client = Some(init_cli(&self.config).await.map_err(|e| {
+                Error::from(format!("Could not initialize a Postgres client: {}", e))
+            })?);
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -78,64 +336,55 @@ impl Sink for Postgres {
         _codec_map: &HashMap<String, Box<dyn Codec>>,
         event: Event,
     ) -> ResultVec {
-        for val in event.value_iter() {
-            let obj = val.as_object();
-            if let Some(kv) = obj {
-                let mut fields: Vec<String> = Vec::with_capacity(kv.len());
-                let mut params: Vec<String> = Vec::with_capacity(kv.len());
-                let mut records: Vec<Record> = Vec::with_capacity(kv.len());
-
-                let mut ct: usize = 1;
-                for (field, value) in kv {
-                    fields.push(field.to_string());
-                    params.push(format!("${}", ct));
-                    ct += 1;
-                    let record = match json_to_record(value) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            return Err(format!("Could not convert json to record: {}", e).into())
-                        }
-                    };
-                    records.push(record);
-                }
-
-                let fields = fields.join(",");
-                let params = params.join(",");
-
-                let q = format!(
-                    "INSERT INTO {} ({}) VALUES ({});",
-                    self.config.table, fields, params
-                );
-
-                if self.client.is_none() {
-                    self.client = match init_cli(&self.config) {
-                        Ok(v) => Some(v),
-                        Err(e) => {
-                            return Err(
-                                format!("Could not initialize a Postgres client: {}", e).into()
-                            )
-                        }
-                    }
-                }
-
-                let client = match self.client.as_mut() {
-                    Some(v) => v,
-                    None => return Err("could not move client value".into()),
-                };
-
-                match client.query_raw(
-                    q.as_str(),
-                    records
-                        .iter()
-                        .map(|p| p as &dyn postgres::types::ToSql)
-                        .collect::<Vec<&dyn postgres::types::ToSql>>(),
-                ) {
-                    Ok(_) => return Ok(None),
-                    Err(e) => return Err(format!("Failure while querying: {}", e).into()),
-                }
+        let mut batches: Vec<Batch> = Vec::new();
+        for (value, meta) in event.value_meta_iter() {
+            let table = meta
+                .get("postgres")
+                .and_then(|m| m.get_str("table"))
+                .unwrap_or(&self.config.table);
+            let Row { columns, values } = self.row(value)?;
+            if let Some(batch) = batches
+                .iter_mut()
+                .find(|b| b.table == table && b.columns == columns)
+            {
+                batch.rows.push(values);
+            } else {
+                batches.push(Batch {
+                    table: table.to_string(),
+                    columns,
+                    rows: vec![values],
+                });
             }
         }
 
+        self.connect().await?;
+        let upsert = self.config.upsert.as_ref();
+        let quoted = self.config.quote_identifiers;
+        let statements: Vec<(String, Vec<Record>)> = batches
+            .iter()
+            .flat_map(|batch| batch.statements(upsert, quoted))
+            .collect();
+        // write each event in a single transaction
+        let client = self
+            .client
+            .as_mut()
+            .ok_or_else(|| Error::from("Postgres client not connected"))?;
+        let tx = Compat::new(client.transaction())
+            .await
+            .map_err(|e| Error::from(format!("Failure starting transaction: {}", e)))?;
+        let mut rows = 0;
+        for (q, records) in &statements {
+            let params: Vec<&(dyn ToSql + Sync)> =
+                records.iter().map(|r| r as &(dyn ToSql + Sync)).collect();
+            // dropping the transaction on error rolls it back
+            rows += Compat::new(tx.execute(q.as_str(), &params))
+                .await
+                .map_err(|e| Error::from(format!("Failure while querying: {}", e)))?;
+        }
+        Compat::new(tx.commit())
+            .await
+            .map_err(|e| Error::from(format!("Failure committing transaction: {}", e)))?;
+        debug!("Wrote {} rows", rows);
         Ok(None)
     }
     fn default_codec(&self) -> &str {
@@ -153,7 +402,7 @@ impl Sink for Postgres {
         _is_linked: bool,
         _reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
-        Ok(())
+        self.connect().await
     }
     async fn on_signal(&mut self, _signal: Event) -> ResultVec {
         Ok(None)
@@ -165,3 +414,109 @@ impl Sink for Postgres {
         true
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn upsert_statement() {
+        let batch = Batch {
+            table: "snot".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            rows: vec![
+                vec![(Type::INT8, Value::from(1)), (Type::TEXT, Value::from("a"))],
+                vec![(Type::INT8, Value::from(2)), (Type::TEXT, Value::from("b"))],
+            ],
+        };
+        let statements = batch.statements(None, true);
+        assert_eq!(1, statements.len());
+        assert_eq!(
+            "INSERT INTO \"snot\" (\"id\",\"name\") VALUES ($1,$2),($3,$4);",
+            statements[0].0
+        );
+        assert_eq!(4, statements[0].1.len());
+
+        let upsert = Upsert {
+            conflict_columns: vec!["id".to_string()],
+            update_columns: None,
+        };
+        assert_eq!(
+            "INSERT INTO \"snot\" (\"id\",\"name\") VALUES ($1,$2),($3,$4) ON CONFLICT (\"id\") DO UPDATE SET \"name\" = EXCLUDED.\"name\";",
+            batch.statements(Some(&upsert), true)[0].0
+        );
+        let upsert = Upsert {
+            conflict_columns: vec!["id".to_string()],
+            update_columns: Some(vec![]),
+        };
+        assert_eq!(
+            "INSERT INTO \"snot\" (\"id\",\"name\") VALUES ($1,$2),($3,$4) ON CONFLICT (\"id\") DO NOTHING;",
+            batch.statements(Some(&upsert), true)[0].0
+        );
+    }
+
+    #[test]
+    fn quoted_identifiers() {
+        assert_eq!("\"public\".\"snot\"", quote_table("public.snot", true));
+        let batch = Batch {
+            table: "snot\"; DROP TABLE badger; --".to_string(),
+            columns: vec!["Id".to_string(), "na\"me".to_string()],
+            rows: vec![vec![
+                (Type::INT8, Value::from(1)),
+                (Type::TEXT, Value::from("a")),
+            ]],
+        };
+        assert_eq!(
+            "INSERT INTO \"snot\"\"; DROP TABLE badger; --\" (\"Id\",\"na\"\"me\") VALUES ($1,$2);",
+            batch.statements(None, true)[0].0
+        );
+    }
+
+    #[test]
+    fn unquoted_identifiers() {
+        assert_eq!("public.MyTable", quote_table("public.MyTable", false));
+        let batch = Batch {
+            table: "MyTable".to_string(),
+            columns: vec!["Id".to_string()],
+            rows: vec![vec![(Type::INT8, Value::from(1))]],
+        };
+        assert_eq!(
+            "INSERT INTO MyTable (Id) VALUES ($1);",
+            batch.statements(None, false)[0].0
+        );
+    }
+
+    #[test]
+    fn upsert_duplicate_keys() {
+        let row = |id: i64, name: &'static str| {
+            vec![
+                (Type::INT8, Value::from(id)),
+                (Type::TEXT, Value::from(name)),
+            ]
+        };
+        let batch = Batch {
+            table: "snot".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            rows: vec![row(1, "a"), row(2, "b"), row(1, "c")],
+        };
+        let upsert = Upsert {
+            conflict_columns: vec!["id".to_string()],
+            update_columns: None,
+        };
+        let statements = batch.statements(Some(&upsert), false);
+        assert_eq!(
+            "INSERT INTO snot (id,name) VALUES ($1,$2),($3,$4) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name;",
+            statements[0].0
+        );
+        let values: Vec<String> = statements[0].1.iter().map(|r| r.value.encode()).collect();
+        assert_eq!(vec!["2", "\"b\"", "1", "\"c\""], values);
+
+        // without updates all rows are kept
+        let upsert = Upsert {
+            conflict_columns: vec!["id".to_string()],
+            update_columns: Some(vec![]),
+        };
+        assert_eq!(6, batch.statements(Some(&upsert), false)[0].1.len());
+        assert_eq!(6, batch.statements(None, false)[0].1.len());
+    }
+}
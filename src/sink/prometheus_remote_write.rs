@@ -0,0 +1,136 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Prometheus Remote Write Offramp
+//!
+//! Sends influx style metric events to a prometheus remote write endpoint.
+//! All events of a batch are sent in a single write request.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::prometheus::{
+    TimeSeries, WriteRequest, CONTENT_ENCODING, CONTENT_TYPE, VERSION_HEADER,
+};
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use http_types::headers;
+
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// url of the remote write endpoint
+    pub url: String,
+    /// additional headers sent with every request, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl ConfigImpl for Config {}
+
+pub struct PrometheusRemoteWrite {
+    config: Config,
+}
+
+impl offramp::Impl for PrometheusRemoteWrite {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self { config }))
+        } else {
+            Err("Missing config for prometheus-remote-write offramp".into())
+        }
+    }
+}
+
+impl PrometheusRemoteWrite {
+    async fn send(&self, request: &WriteRequest) -> Result<()> {
+        let mut req = surf::post(&self.config.url)
+            .header(headers::CONTENT_TYPE, CONTENT_TYPE)
+            .header(headers::CONTENT_ENCODING, CONTENT_ENCODING)
+            .header(VERSION_HEADER.0, VERSION_HEADER.1)
+            .body(request.to_body()?);
+        for (name, value) in &self.config.headers {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let mut response = req.await?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            let body = match response.body_string().await {
+                Ok(body) => body,
+                Err(err) => format!("failed to load body {}", err),
+            };
+            Err(format!(
+                "error sending remote write request\nstatus: {}\nreturned body: {}",
+                response.status(),
+                body
+            )
+            .into())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for PrometheusRemoteWrite {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let mut timeseries = Vec::new();
+        for value in event.value_iter() {
+            timeseries.append(&mut TimeSeries::from_value(value)?);
+        }
+        if !timeseries.is_empty() {
+            debug!("Sending {} time series", timeseries.len());
+            self.send(&WriteRequest { timeseries }).await?;
+        }
+        Ok(None)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        Ok(None)
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        true
+    }
+}
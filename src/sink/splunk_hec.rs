@@ -0,0 +1,423 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Splunk HTTP Event Collector Offramp
+//!
+//! Sends events to the `/services/collector/event` endpoint of a Splunk HEC.
+//! All events of a batch are sent in a single request. `index`, `sourcetype`,
+//! `source`, `host`, `time` and `fields` are taken from `$splunk` and default
+//! to the configured values, `time` defaults to the ingest time of the event.
+//!
+//! Without `ack` transactional events are acked once the request succeeded.
+//! With `ack` indexer acknowledgement is used: the events are acked once
+//! Splunk reports them as indexed on the `/services/collector/ack` endpoint
+//! and failed if that doesn't happen within `ack_timeout_ms`.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::sink::prelude::*;
+use async_channel::{unbounded, Receiver, TryRecvError};
+use halfbrown::HashMap;
+use http_types::headers;
+use rand::RngCore;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    /// base url of the HEC, e.g. `https://splunk:8088`
+    pub url: String,
+    /// HEC token
+    pub token: String,
+    /// default index
+    #[serde(default = "Default::default")]
+    pub index: Option<String>,
+    /// default sourcetype
+    #[serde(default = "Default::default")]
+    pub sourcetype: Option<String>,
+    /// default source
+    #[serde(default = "Default::default")]
+    pub source: Option<String>,
+    /// default host, defaults to the hostname
+    #[serde(default = "Default::default")]
+    pub host: Option<String>,
+    /// gzip compress requests, defaults to true
+    #[serde(default = "dflt_true")]
+    pub gzip: bool,
+    /// use indexer acknowledgement, the token needs to have it enabled
+    #[serde(default = "Default::default")]
+    pub ack: bool,
+    /// channel for indexer acknowledgement, defaults to a random one
+    #[serde(default = "Default::default")]
+    pub channel: Option<String>,
+    /// interval to poll for acknowledgements in, defaults to 1s
+    #[serde(default = "dflt_ack_interval_ms")]
+    pub ack_interval_ms: u64,
+    /// time after which unacknowledged events are failed, defaults to 60s
+    #[serde(default = "dflt_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+}
+
+fn dflt_true() -> bool {
+    true
+}
+
+fn dflt_ack_interval_ms() -> u64 {
+    1000
+}
+
+fn dflt_ack_timeout_ms() -> u64 {
+    60_000
+}
+
+impl ConfigImpl for Config {}
+
+/// A random channel GUID
+fn random_channel() -> String {
+    let mut rng = rand::rngs::OsRng;
+    let a = rng.next_u64();
+    let b = rng.next_u64();
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        a >> 32,
+        (a >> 16) & 0xFFFF,
+        a & 0xFFFF,
+        b >> 48,
+        b & 0xFFFF_FFFF_FFFF
+    )
+}
+
+pub struct SplunkHec {
+    sink_url: TremorUrl,
+    config: Config,
+    channel: String,
+    client: surf::Client,
+    /// requests waiting for indexer acknowledgement
+    acks: Option<Sender<(u64, Event)>>,
+}
+
+impl offramp::Impl for SplunkHec {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            let channel = config.channel.clone().unwrap_or_else(random_channel);
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("splunk-hec")?,
+                config,
+                channel,
+                client: surf::client(),
+                acks: None,
+            }))
+        } else {
+            Err("Missing config for splunk-hec offramp".into())
+        }
+    }
+}
+
+/// Builds the HEC event for a value
+#[allow(clippy::cast_precision_loss)]
+fn to_hec(value: &Value, meta: &Value, ingest_ns: u64, config: &Config) -> Value<'static> {
+    let splunk = meta.get("splunk");
+    let mut hec = Value::object_with_capacity(7);
+    let time = splunk
+        .and_then(|m| m.get("time"))
+        .map_or_else(|| Value::from(ingest_ns as f64 / 1e9), Value::clone_static);
+    hec.try_insert("time", time);
+    let defaults = [
+        ("index", config.index.clone()),
+        ("sourcetype", config.sourcetype.clone()),
+        ("source", config.source.clone()),
+        ("host", Some(config.host.clone().unwrap_or_else(hostname))),
+        ("fields", None),
+    ];
+    for (key, default) in &defaults {
+        match splunk.and_then(|m| m.get(*key)) {
+            Some(v) if !v.is_null() => {
+                hec.try_insert(*key, v.clone_static());
+            }
+            _ => {
+                if let Some(default) = default {
+                    hec.try_insert(*key, default.clone());
+                }
+            }
+        }
+    }
+    hec.try_insert("event", value.clone_static());
+    hec
+}
+
+fn url(base: &str, path: &str) -> String {
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+async fn post(
+    client: &surf::Client,
+    config: &Config,
+    channel: &str,
+    path: &str,
+    body: Vec<u8>,
+) -> Result<Value<'static>> {
+    let mut req = client
+        .post(url(&config.url, path))
+        .header(headers::AUTHORIZATION, format!("Splunk {}", config.token))
+        .header(headers::CONTENT_TYPE, "application/json")
+        .header("X-Splunk-Request-Channel", channel);
+    if config.gzip {
+        let mut encoder = libflate::gzip::Encoder::new(Vec::new())?;
+        encoder.write_all(&body)?;
+        req = req
+            .header(headers::CONTENT_ENCODING, "gzip")
+            .body(encoder.finish().into_result()?);
+    } else {
+        req = req.body(body);
+    }
+    let mut response = req.await?;
+    let mut body = response.body_bytes().await?;
+    if response.status().is_success() {
+        Ok(tremor_value::parse_to_value(&mut body)?.into_static())
+    } else {
+        Err(format!(
+            "HEC request failed with {}: {}",
+            response.status(),
+            String::from_utf8_lossy(&body)
+        )
+        .into())
+    }
+}
+
+/// Asks the HEC which of the given acks are indexed
+async fn query_acks(
+    client: &surf::Client,
+    config: &Config,
+    channel: &str,
+    ids: Vec<u64>,
+) -> Result<Vec<u64>> {
+    let body = literal!({ "acks": ids }).encode().into_bytes();
+    let res = post(client, config, channel, "/services/collector/ack", body).await?;
+    Ok(res
+        .get_object("acks")
+        .map(|acks| {
+            acks.iter()
+                .filter(|(_, acked)| acked.as_bool() == Some(true))
+                .filter_map(|(id, _)| id.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+async fn send_insight(reply_tx: &Sender<sink::Reply>, cause: Event, ack: bool, sink_url: &str) {
+    if let Err(e) = reply_tx
+        .send(sink::Reply::Insight(cause.insight(ack)))
+        .await
+    {
+        error!(
+            "[Sink::{}] Error sending insight via reply channel: {}",
+            sink_url, e
+        );
+    }
+}
+
+/// Polls the ack endpoint for all requests waiting for their acknowledgement
+async fn ack_loop(
+    client: surf::Client,
+    config: Config,
+    channel: String,
+    rx: Receiver<(u64, Event)>,
+    reply_tx: Sender<sink::Reply>,
+    sink_url: String,
+) {
+    let interval = Duration::from_millis(config.ack_interval_ms);
+    let ack_timeout = Duration::from_millis(config.ack_timeout_ms);
+    let mut pending: HashMap<u64, (Event, Instant)> = HashMap::new();
+    loop {
+        task::sleep(interval).await;
+        loop {
+            match rx.try_recv() {
+                Ok((id, cause)) => {
+                    pending.insert(id, (cause, Instant::now() + ack_timeout));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Closed) if pending.is_empty() => return,
+                Err(TryRecvError::Closed) => break,
+            }
+        }
+        if pending.is_empty() {
+            continue;
+        }
+        let ids = pending.keys().copied().collect();
+        match query_acks(&client, &config, &channel, ids).await {
+            Ok(acked) => {
+                for id in acked {
+                    if let Some((cause, _)) = pending.remove(&id) {
+                        send_insight(&reply_tx, cause, true, &sink_url).await;
+                    }
+                }
+            }
+            Err(e) => warn!("[Sink::{}] Failed to query acks: {}", sink_url, e),
+        }
+        let now = Instant::now();
+        let expired: Vec<u64> = pending
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some((cause, _)) = pending.remove(&id) {
+                warn!("[Sink::{}] Ack {} timed out", sink_url, id);
+                send_insight(&reply_tx, cause, false, &sink_url).await;
+            }
+        }
+    }
+}
+
+impl SplunkHec {
+    /// Sends the event and returns the ack id if indexer acknowledgement is used
+    async fn send(&mut self, event: &Event) -> Result<Option<u64>> {
+        let mut body = Vec::new();
+        for (value, meta) in event.value_meta_iter() {
+            to_hec(value, meta, event.ingest_ns, &self.config).write(&mut body)?;
+            body.push(b'\n');
+        }
+        let res = post(
+            &self.client,
+            &self.config,
+            &self.channel,
+            "/services/collector/event",
+            body,
+        )
+        .await?;
+        Ok(res.get_u64("ackId"))
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for SplunkHec {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let cause = if event.transactional {
+            Some(event.to_fail())
+        } else {
+            None
+        };
+        match self.send(&event).await {
+            Ok(Some(id)) if self.config.ack => {
+                if let (Some(acks), Some(cause)) = (&self.acks, cause) {
+                    if let Err(e) = acks.send((id, cause)).await {
+                        error!("[Sink::{}] Ack loop stopped: {}", self.sink_url, e);
+                        return Ok(Some(vec![sink::Reply::Insight(
+                            e.into_inner().1.insight(false),
+                        )]));
+                    }
+                }
+                Ok(None)
+            }
+            Ok(_) => Ok(cause.map(|cause| vec![sink::Reply::Insight(cause.insight(true))])),
+            Err(e) => {
+                error!("[Sink::{}] Failed to send events: {}", self.sink_url, e);
+                Ok(cause.map(|cause| vec![sink::Reply::Insight(cause.insight(false))]))
+            }
+        }
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.sink_url = sink_url.clone();
+        if self.config.ack {
+            let (tx, rx) = unbounded();
+            task::spawn(ack_loop(
+                self.client.clone(),
+                self.config.clone(),
+                self.channel.clone(),
+                rx,
+                reply_channel,
+                sink_url.to_string(),
+            ));
+            self.acks = Some(tx);
+        }
+        Ok(())
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        Ok(None)
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    async fn terminate(&mut self) {
+        if let Some(acks) = self.acks.take() {
+            acks.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hec_event() -> Result<()> {
+        let config: Config = serde_yaml::from_str(
+            "url: http://localhost:8088\ntoken: snot\nindex: main\nhost: badger",
+        )?;
+        let meta = literal!({"splunk": {"sourcetype": "st", "index": "other", "time": 1.5}});
+        assert_eq!(
+            literal!({
+                "time": 1.5,
+                "index": "other",
+                "sourcetype": "st",
+                "host": "badger",
+                "event": {"snot": "badger"}
+            }),
+            to_hec(&literal!({"snot": "badger"}), &meta, 0, &config)
+        );
+        let hec = to_hec(
+            &Value::from("snot"),
+            &Value::object(),
+            2_000_000_000,
+            &config,
+        );
+        assert_eq!(Some(2.0), hec.get_f64("time"));
+        assert_eq!(Some("main"), hec.get_str("index"));
+        assert_eq!(36, random_channel().len());
+        Ok(())
+    }
+}
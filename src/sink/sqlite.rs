@@ -0,0 +1,524 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # SQLite Offramp
+//!
+//! Writes events as rows into a local SQLite database file. All rows of an
+//! event (or batch) are written in a single transaction. The table can be
+//! overwritten per event via `$sqlite.table`.
+//!
+//! Events with the metadata
+//!
+//! ```json
+//! {"sqlite": {"query": {"sql": "SELECT * FROM users WHERE id = ?1", "params": [1]}}}
+//! ```
+//!
+//! are executed as queries instead, their value is ignored. Results are sent
+//! out of the `out` port as `{"ok": [{"id": 1, "name": "snot"}]}`, errors are
+//! sent out of the `err` port. Statements that don't return rows reply with
+//! `{"ok": {"changes": <number of changed rows>}}`. `$correlation` is copied
+//! to the reply.
+//!
+//! The database is opened on start, all statements run on the blocking thread pool.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::sink::prelude::*;
+use halfbrown::HashMap;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{params_from_iter, Connection};
+use tremor_pipeline::{EventIdGenerator, EventOriginUri};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// path of the database file, it is created if it doesn't exist
+    pub path: String,
+    /// statements to execute on start, e.g. `CREATE TABLE IF NOT EXISTS ..`
+    #[serde(default = "Default::default")]
+    pub schema: Vec<String>,
+    /// table to write to, can be overwritten per event via `$sqlite.table`
+    pub table: String,
+    /// columns to write and where to find their values in the event,
+    /// defaults to all fields of the event
+    #[serde(default = "Default::default")]
+    pub columns: Vec<Column>,
+    /// columns of the unique constraint to upsert on, rows are only inserted
+    /// if this is empty
+    #[serde(default = "Default::default")]
+    pub conflict_columns: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Column {
+    /// column name
+    pub name: String,
+    /// dot separated path of the value in the event, defaults to the column name
+    #[serde(default = "Default::default")]
+    pub path: Option<String>,
+}
+
+impl ConfigImpl for Config {}
+
+/// A row to insert into `table`
+struct Row {
+    table: String,
+    columns: Vec<String>,
+    values: Vec<SqlValue>,
+}
+
+/// A single value of an event, executed in order
+enum Op {
+    Insert(Row),
+    Query {
+        cmd: Value<'static>,
+        correlation: Option<Value<'static>>,
+    },
+}
+
+pub struct Sqlite {
+    sink_url: TremorUrl,
+    event_origin_uri: EventOriginUri,
+    idgen: EventIdGenerator,
+    config: Config,
+    /// opened in `init`, only taken while statements run on the blocking pool
+    conn: Option<Connection>,
+}
+
+impl offramp::Impl for Sqlite {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            let event_origin_uri = EventOriginUri {
+                uid: 0,
+                scheme: "tremor-sqlite".to_string(),
+                host: "localhost".to_string(),
+                port: None,
+                path: config.path.split('/').map(ToString::to_string).collect(),
+            };
+            Ok(SinkManager::new_box(Self {
+                sink_url: TremorUrl::from_offramp_id("sqlite")?,
+                event_origin_uri,
+                idgen: EventIdGenerator::new(0),
+                config,
+                conn: None,
+            }))
+        } else {
+            Err("Missing config for sqlite offramp".into())
+        }
+    }
+}
+
+/// Quotes an identifier
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn to_sql(value: &Value) -> SqlValue {
+    if value.is_null() {
+        SqlValue::Null
+    } else if let Some(b) = value.as_bool() {
+        SqlValue::Integer(i64::from(b))
+    } else if let Some(i) = value.as_i64() {
+        SqlValue::Integer(i)
+    } else if let Some(f) = value.cast_f64() {
+        SqlValue::Real(f)
+    } else if let Some(s) = value.as_str() {
+        SqlValue::Text(s.to_string())
+    } else if let Value::Bytes(b) = value {
+        SqlValue::Blob(b.to_vec())
+    } else {
+        // arrays and records are stored as JSON
+        SqlValue::Text(value.encode())
+    }
+}
+
+fn from_sql(value: ValueRef<'_>) -> Value<'static> {
+    match value {
+        ValueRef::Null => Value::null(),
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(s) => Value::from(String::from_utf8_lossy(s).to_string()),
+        ValueRef::Blob(b) => Value::Bytes(b.to_vec().into()),
+    }
+}
+
+/// Opens the database and applies the schema
+fn open(path: &str, schema: &[String]) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    for statement in schema {
+        conn.execute_batch(statement)?;
+    }
+    Ok(conn)
+}
+
+/// Writes all rows in a single transaction
+fn insert(conn: &mut Connection, rows: &[Row], conflict_columns: &[String]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let mut changes = 0;
+    for row in rows {
+        let columns: Vec<String> = row.columns.iter().map(|c| quote(c)).collect();
+        let params: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+        let mut sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(&row.table),
+            columns.join(","),
+            params.join(",")
+        );
+        if !conflict_columns.is_empty() {
+            let update: Vec<String> = row
+                .columns
+                .iter()
+                .filter(|c| !conflict_columns.contains(c))
+                .map(|c| format!("{} = excluded.{}", quote(c), quote(c)))
+                .collect();
+            let target: Vec<String> = conflict_columns.iter().map(|c| quote(c)).collect();
+            if update.is_empty() {
+                sql.push_str(&format!(" ON CONFLICT ({}) DO NOTHING", target.join(",")));
+            } else {
+                sql.push_str(&format!(
+                    " ON CONFLICT ({}) DO UPDATE SET {}",
+                    target.join(","),
+                    update.join(",")
+                ));
+            }
+        }
+        changes += tx
+            .prepare_cached(&sql)?
+            .execute(params_from_iter(row.values.iter()))?;
+    }
+    tx.commit()?;
+    Ok(changes)
+}
+
+/// Executes a query command and returns its result
+fn query(conn: &Connection, cmd: &Value) -> Result<Value<'static>> {
+    let sql = cmd
+        .get_str("sql")
+        .ok_or_else(|| Error::from("Missing or invalid `sql` field"))?;
+    let params: Vec<SqlValue> = cmd
+        .get_array("params")
+        .map(|params| params.iter().map(to_sql).collect())
+        .unwrap_or_default();
+    let mut stmt = conn.prepare(sql)?;
+    if stmt.column_count() == 0 {
+        let changes = stmt.execute(params_from_iter(params.iter()))?;
+        return Ok(literal!({ "changes": changes }));
+    }
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let mut rows = stmt.query(params_from_iter(params.iter()))?;
+    let mut res = Vec::new();
+    while let Some(row) = rows.next()? {
+        let mut record = Value::object_with_capacity(names.len());
+        for (i, name) in names.iter().enumerate() {
+            record.try_insert(name.clone(), from_sql(row.get_ref(i)?));
+        }
+        res.push(record);
+    }
+    Ok(Value::from(res))
+}
+
+/// Results of the queries of an event with their correlation
+type QueryResults = Vec<(Result<Value<'static>>, Option<Value<'static>>)>;
+
+/// Runs the ops of an event, consecutive inserts share a transaction.
+/// Returns the number of written rows and the results of the queries.
+fn execute(
+    conn: &mut Connection,
+    ops: Vec<Op>,
+    conflict_columns: &[String],
+) -> Result<(usize, QueryResults)> {
+    let mut changes = 0;
+    let mut results = Vec::new();
+    let mut rows = Vec::new();
+    for op in ops {
+        match op {
+            Op::Insert(row) => rows.push(row),
+            Op::Query { cmd, correlation } => {
+                // queries see all rows written before them
+                if !rows.is_empty() {
+                    changes += insert(conn, &rows, conflict_columns)?;
+                    rows.clear();
+                }
+                results.push((query(conn, &cmd), correlation));
+            }
+        }
+    }
+    if !rows.is_empty() {
+        changes += insert(conn, &rows, conflict_columns)?;
+    }
+    Ok((changes, results))
+}
+
+impl Sqlite {
+    /// a query if `$sqlite.query` is set, otherwise a row to insert
+    fn op(&self, value: &Value, meta: &Value) -> Result<Op> {
+        let sqlite = meta.get("sqlite");
+        if let Some(cmd) = sqlite.and_then(|m| m.get("query")) {
+            return Ok(Op::Query {
+                cmd: cmd.clone_static(),
+                correlation: meta.get("correlation").map(Value::clone_static),
+            });
+        }
+        let table = sqlite
+            .and_then(|m| m.get_str("table"))
+            .unwrap_or(&self.config.table);
+        Ok(Op::Insert(self.row(table, value)?))
+    }
+
+    fn row(&self, table: &str, value: &Value) -> Result<Row> {
+        let (columns, values) = if self.config.columns.is_empty() {
+            let kv = value
+                .as_object()
+                .ok_or_else(|| Error::from("sqlite offramp expects a record"))?;
+            let mut fields: Vec<_> = kv.iter().collect();
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), to_sql(v)))
+                .unzip()
+        } else {
+            self.config
+                .columns
+                .iter()
+                .map(|c| {
+                    let path = c.path.as_deref().unwrap_or(&c.name);
+                    let mut current = Some(value);
+                    for segment in path.split('.') {
+                        current = current.and_then(|v| v.get(segment));
+                    }
+                    (c.name.clone(), current.map_or(SqlValue::Null, to_sql))
+                })
+                .unzip()
+        };
+        Ok(Row {
+            table: table.to_string(),
+            columns,
+            values,
+        })
+    }
+
+    fn reply(
+        &mut self,
+        event: &Event,
+        data: Value<'static>,
+        correlation: Option<&Value>,
+        error: Option<&Error>,
+    ) -> Reply {
+        let mut id = self.idgen.next_id();
+        id.track(&event.id);
+        let mut meta = Value::object_with_capacity(3);
+        meta.try_insert("sqlite", literal!({ "op": "query" }));
+        if let Some(error) = error {
+            meta.try_insert("error", error.to_string());
+        }
+        if let Some(correlation) = correlation {
+            meta.try_insert("correlation", correlation.clone_static());
+        }
+        let e = Event {
+            id,
+            ingest_ns: nanotime(),
+            data: (data, meta).into(),
+            origin_uri: Some(self.event_origin_uri.clone()),
+            ..Event::default()
+        };
+        Reply::Response(if error.is_some() { ERR } else { OUT }, e)
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for Sqlite {
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        _codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        event: Event,
+    ) -> ResultVec {
+        let ops = event
+            .value_meta_iter()
+            .map(|(value, meta)| self.op(value, meta))
+            .collect::<Result<Vec<_>>>()?;
+        let mut conn = self
+            .conn
+            .take()
+            .ok_or_else(|| Error::from("sqlite offramp is not initialized"))?;
+        let conflict_columns = self.config.conflict_columns.clone();
+        let (conn, res) = task::spawn_blocking(move || {
+            let res = execute(&mut conn, ops, &conflict_columns);
+            (conn, res)
+        })
+        .await;
+        self.conn = Some(conn);
+        let (changes, results) = res?;
+        debug!("[Sink::{}] Wrote {} rows", self.sink_url, changes);
+        let mut replies = Vec::with_capacity(results.len());
+        for (res, correlation) in results {
+            let correlation = correlation.as_ref();
+            let reply = match res {
+                Ok(res) => self.reply(&event, literal!({ "ok": res }), correlation, None),
+                Err(e) => {
+                    error!("[Sink::{}] Query failed: {}", self.sink_url, e);
+                    let data = literal!({ "error": e.to_string() });
+                    self.reply(&event, data, correlation, Some(&e))
+                }
+            };
+            replies.push(reply);
+        }
+        Ok(Some(replies))
+    }
+
+    async fn on_signal(&mut self, _signal: Event) -> ResultVec {
+        Ok(None)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        sink_uid: u64,
+        sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        _processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.event_origin_uri.uid = sink_uid;
+        self.sink_url = sink_url.clone();
+        self.idgen.set_source(sink_uid);
+        let path = self.config.path.clone();
+        let schema = self.config.schema.clone();
+        self.conn = Some(task::spawn_blocking(move || open(&path, &schema)).await?);
+        Ok(())
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn auto_ack(&self) -> bool {
+        true
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn insert_and_query() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, data TEXT)")?;
+        let row = |id: i64, name: &str| Row {
+            table: "t".to_string(),
+            columns: vec!["id".to_string(), "name".to_string(), "data".to_string()],
+            values: vec![
+                to_sql(&Value::from(id)),
+                to_sql(&Value::from(name)),
+                to_sql(&literal!({"snot": "badger"})),
+            ],
+        };
+        let conflict = vec!["id".to_string()];
+        assert_eq!(
+            2,
+            insert(&mut conn, &[row(1, "a"), row(2, "b")], &conflict)?
+        );
+        assert_eq!(1, insert(&mut conn, &[row(1, "c")], &conflict)?);
+
+        let cmd = literal!({"sql": "SELECT id, name, data FROM t WHERE id < ?1", "params": [3]});
+        assert_eq!(
+            literal!([
+                {"id": 1, "name": "c", "data": "{\"snot\":\"badger\"}"},
+                {"id": 2, "name": "b", "data": "{\"snot\":\"badger\"}"}
+            ]),
+            query(&conn, &cmd)?
+        );
+        let cmd = literal!({"sql": "DELETE FROM t WHERE id = ?1", "params": [2]});
+        assert_eq!(literal!({"changes": 1}), query(&conn, &cmd)?);
+        assert!(query(&conn, &literal!({"sql": "snot"})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn query_from_meta() -> Result<()> {
+        let config: Config = serde_yaml::from_str("path: \":memory:\"\ntable: t")?;
+        let sink = Sqlite {
+            sink_url: TremorUrl::from_offramp_id("sqlite")?,
+            event_origin_uri: EventOriginUri {
+                uid: 0,
+                scheme: "tremor-sqlite".to_string(),
+                host: "localhost".to_string(),
+                port: None,
+                path: vec![],
+            },
+            idgen: EventIdGenerator::new(0),
+            config,
+            conn: None,
+        };
+        // a `query` field in the value is just data
+        let value = literal!({"query": {"sql": "DROP TABLE t"}});
+        match sink.op(&value, &literal!({}))? {
+            Op::Insert(row) => {
+                assert_eq!("t", row.table);
+                assert_eq!(vec!["query".to_string()], row.columns);
+            }
+            Op::Query { .. } => return Err("value was run as a query".into()),
+        }
+        let meta = literal!({
+            "sqlite": {"query": {"sql": "SELECT 1"}},
+            "correlation": "snot"
+        });
+        match sink.op(&value, &meta)? {
+            Op::Query { cmd, correlation } => {
+                assert_eq!(literal!({"sql": "SELECT 1"}), cmd);
+                assert_eq!(Some(Value::from("snot")), correlation);
+            }
+            Op::Insert(_) => return Err("query was inserted".into()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn execute_in_order() -> Result<()> {
+        let mut conn = open(
+            ":memory:",
+            &["CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)".to_string()],
+        )?;
+        let row = |id: i64| Row {
+            table: "t".to_string(),
+            columns: vec!["id".to_string(), "name".to_string()],
+            values: vec![to_sql(&Value::from(id)), to_sql(&Value::from("snot"))],
+        };
+        let count = || Op::Query {
+            cmd: literal!({"sql": "SELECT count(*) AS n FROM t"}),
+            correlation: None,
+        };
+        let ops = vec![Op::Insert(row(1)), count(), Op::Insert(row(2)), count()];
+        let (changes, results) = execute(&mut conn, ops, &[])?;
+        assert_eq!(2, changes);
+        let counts = results
+            .into_iter()
+            .map(|(res, _)| res)
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(vec![literal!([{"n": 1}]), literal!([{"n": 2}])], counts);
+        Ok(())
+    }
+}
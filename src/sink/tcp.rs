@@ -18,35 +18,70 @@
 //!
 //! Sends each message as a tcp stream
 //!
+//! In the default `client` mode the offramp connects to `host` and `port`. In
+//! `server` mode it listens on them instead and sends events to all connected
+//! clients (`broadcast`) or to one client after the other (`round_robin`), as
+//! configured by `distribution`.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::sink::prelude::*;
-use async_std::net::TcpStream;
+use crate::source::tcp::{load_server_config, Mode, TLSConfig as ServerTLSConfig};
+use async_channel::{bounded, Receiver, TryRecvError};
+use async_std::future::timeout;
+use async_std::net::{SocketAddr, TcpListener, TcpStream};
+use async_tls::TlsAcceptor;
 use halfbrown::HashMap;
 
 use async_tls::TlsConnector;
 use rustls::ClientConfig;
 
-use async_std::io::Write;
 use either::Either;
+use futures::io::{AsyncRead, AsyncWrite};
 use std::io::Cursor;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-type Stream = Box<dyn Write + std::marker::Unpin + Send>;
+/// time a client gets to complete the TLS handshake in server mode
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// time a client gets to receive an event in server mode before it is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A plain or TLS connection
+pub(crate) trait Conn: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Conn for T {}
+
+type Stream = Box<dyn Conn>;
 
 /// An offramp streams over TCP/IP
 pub struct Tcp {
     // TODO: check if we can/should use an enum here where we implement async_std::io::Write manually?
     stream: Option<Stream>,
+    /// connected clients in server mode
+    clients: Vec<(SocketAddr, Stream)>,
+    /// newly accepted clients in server mode
+    accepted: Option<Receiver<(SocketAddr, Stream)>>,
+    /// the client to send the next event to for `round_robin`
+    next: usize,
+    /// if we triggered the CB as there were no clients connected
+    paused: bool,
     postprocessors: Postprocessors,
     config: Config,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Distribution {
+    /// send every event to all clients
+    Broadcast,
+    /// send every event to one client, cycling through them
+    RoundRobin,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     pub host: String,
@@ -57,12 +92,20 @@ pub struct Config {
     pub is_no_delay: bool,
     #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
     pub tls: Option<Either<TLSConfig, bool>>,
+    /// `client` (default) to connect to `host` and `port` or `server` to listen on them
+    #[serde(default = "default_mode")]
+    pub mode: Mode,
+    /// how events are sent to the clients in server mode, defaults to `broadcast`
+    #[serde(default = "default_distribution")]
+    pub distribution: Distribution,
+    /// certificate and key to accept TLS connections with in server mode
+    pub server_tls: Option<ServerTLSConfig>,
 }
 
-#[derive(Deserialize, Debug, Default)]
+#[derive(Deserialize, Debug, Default, Clone)]
 pub struct TLSConfig {
     cafile: Option<PathBuf>,
-    domain: Option<String>,
+    pub(crate) domain: Option<String>,
 }
 
 fn default_no_delay() -> bool {
@@ -73,17 +116,21 @@ fn default_ttl() -> u32 {
     64
 }
 
+fn default_mode() -> Mode {
+    Mode::Client
+}
+
+fn default_distribution() -> Distribution {
+    Distribution::Broadcast
+}
+
 impl ConfigImpl for Config {}
 
 impl offramp::Impl for Tcp {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
-            Ok(SinkManager::new_box(Self {
-                config,
-                stream: None,
-                postprocessors: vec![],
-            }))
+            Ok(SinkManager::new_box(Self::new(config)))
         } else {
             Err("TCP offramp requires a config".into())
         }
@@ -91,7 +138,22 @@ impl offramp::Impl for Tcp {
 }
 
 impl Tcp {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            stream: None,
+            clients: Vec::new(),
+            accepted: None,
+            next: 0,
+            paused: false,
+            postprocessors: vec![],
+        }
+    }
+
     async fn send_event(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        if self.config.mode == Mode::Server {
+            return self.send_to_clients(codec, event).await;
+        }
         let stream = self
             .stream
             .as_mut()
@@ -106,36 +168,143 @@ impl Tcp {
         Ok(())
     }
 
-    async fn connect(config: &Config) -> Result<Stream> {
-        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
-        stream.set_ttl(config.ttl)?;
-        stream.set_nodelay(config.is_no_delay)?;
-        let s: Stream = match config.tls.as_ref() {
-            Some(Either::Right(true)) => {
-                debug!("Returns a TLS stream via default TLS connector");
-                let tls_config = TLSConfig::default();
-                let c = connector(&tls_config).await?;
-                Box::new(c.connect(config.host.as_str(), stream).await?)
+    async fn send_to_clients(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        self.accept_clients();
+        if self.clients.is_empty() {
+            return Err(ErrorKind::NoSocket.into());
+        }
+        let mut packets = Vec::new();
+        for value in event.value_iter() {
+            let raw = codec.encode(value)?;
+            packets.append(&mut postprocess(
+                &mut self.postprocessors,
+                event.ingest_ns,
+                raw,
+            )?);
+        }
+        match self.config.distribution {
+            Distribution::Broadcast => {
+                // write to all clients at once so a slow one only delays the
+                // others up to the write timeout
+                let packets = &packets;
+                let writes: Vec<_> = self
+                    .clients
+                    .drain(..)
+                    .map(|(peer, mut stream)| async move {
+                        let res = write_packets(&mut stream, packets).await;
+                        (peer, stream, res)
+                    })
+                    .collect();
+                for (peer, stream, res) in futures::future::join_all(writes).await {
+                    match res {
+                        Ok(()) => self.clients.push((peer, stream)),
+                        Err(e) => debug!("[Sink::TCP] Dropping client {}: {}", peer, e),
+                    }
+                }
             }
-            Some(Either::Left(tls)) => {
-                debug!("Returns a TLS stream by a TLS connector started wiht cafile provided in the config");
-                let c = connector(tls).await?;
-                Box::new(
-                    c.connect(
-                        tls.domain
-                            .as_ref()
-                            .map_or_else(|| config.host.as_str(), String::as_str),
-                        stream,
-                    )
-                    .await?,
-                )
+            Distribution::RoundRobin => {
+                // try the clients in turn until one of them received the event
+                while !self.clients.is_empty() {
+                    let i = self.next % self.clients.len();
+                    if let Some((peer, stream)) = self.clients.get_mut(i) {
+                        match write_packets(stream, &packets).await {
+                            Ok(()) => {
+                                self.next = i + 1;
+                                return Ok(());
+                            }
+                            Err(e) => debug!("[Sink::TCP] Dropping client {}: {}", peer, e),
+                        }
+                    }
+                    self.clients.remove(i);
+                    self.next = i;
+                }
             }
-            Some(Either::Right(false)) | None => {
-                debug!("Returns the usual TCP stream");
-                Box::new(stream)
+        }
+        if self.clients.is_empty() {
+            Err(ErrorKind::NoSocket.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Adds the clients accepted since the last call
+    fn accept_clients(&mut self) {
+        if let Some(accepted) = self.accepted.as_ref() {
+            loop {
+                match accepted.try_recv() {
+                    Ok(client) => self.clients.push(client),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Closed) => {
+                        error!("[Sink::TCP] Listener stopped");
+                        self.accepted = None;
+                        break;
+                    }
+                }
             }
+        }
+    }
+
+    async fn listen(&mut self) -> Result<()> {
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
+        self.accept(listener)
+    }
+
+    /// Accepts clients in the background, each one in its own task so a slow
+    /// TLS handshake does not hold up the others
+    fn accept(&mut self, listener: TcpListener) -> Result<()> {
+        let tls_acceptor = if let Some(tls_config) = self.config.server_tls.as_ref() {
+            Some(TlsAcceptor::from(Arc::new(load_server_config(tls_config)?)))
+        } else {
+            None
         };
-        Ok(s)
+        let (tx, rx) = bounded(crate::QSIZE);
+        let ttl = self.config.ttl;
+        let no_delay = self.config.is_no_delay;
+        task::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                if tx.is_closed() {
+                    break;
+                }
+                if let Err(e) = stream
+                    .set_ttl(ttl)
+                    .and_then(|()| stream.set_nodelay(no_delay))
+                {
+                    warn!("[Sink::TCP] Unable to configure connection {}: {}", peer, e);
+                }
+                let tx = tx.clone();
+                let tls_acceptor = tls_acceptor.clone();
+                task::spawn(async move {
+                    let stream: Stream = if let Some(acceptor) = tls_acceptor {
+                        match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(tls_stream)) => Box::new(tls_stream),
+                            Ok(Err(e)) => {
+                                warn!("[Sink::TCP] TLS handshake with {} failed: {}", peer, e);
+                                return;
+                            }
+                            Err(_) => {
+                                warn!("[Sink::TCP] TLS handshake with {} timed out", peer);
+                                return;
+                            }
+                        }
+                    } else {
+                        Box::new(stream)
+                    };
+                    debug!("[Sink::TCP] Client {} connected", peer);
+                    if tx.send((peer, stream)).await.is_err() {
+                        debug!("[Sink::TCP] Sink stopped, dropping client {}", peer);
+                    }
+                });
+            }
+        });
+        self.accepted = Some(rx);
+        Ok(())
+    }
+
+    async fn connect(config: &Config) -> Result<Stream> {
+        let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+        stream.set_ttl(config.ttl)?;
+        stream.set_nodelay(config.is_no_delay)?;
+        tls_connect(stream, config.host.as_str(), config.tls.as_ref()).await
     }
 }
 
@@ -168,6 +337,8 @@ impl Sink for Tcp {
             // for TCP we always trigger the CB for IO/socket related errors
             Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
                 debug!("[Sink::TCP] Error sending event: {}.", e);
+                // in server mode we restore once a client connected
+                self.paused = self.config.mode == Mode::Server;
                 if event.transactional {
                     Some(vec![
                         sink::Reply::Insight(event.to_fail()),
@@ -206,14 +377,33 @@ impl Sink for Tcp {
         _reply_channel: Sender<sink::Reply>,
     ) -> Result<()> {
         self.postprocessors = make_postprocessors(processors.post)?;
-        let stream = Self::connect(&self.config).await?;
-        //let stream = TcpStream::connect((self.config.host.as_str(), self.config.port)).await?;
-        //stream.set_ttl(self.config.ttl)?;
-        //stream.set_nodelay(self.config.is_no_delay)?;
-        self.stream = Some(Box::new(stream));
+        if self.config.mode == Mode::Server {
+            return self.listen().await;
+        }
+        self.stream = Some(Self::connect(&self.config).await?);
         Ok(())
     }
     async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.config.mode == Mode::Server {
+            self.accept_clients();
+            return if self.clients.is_empty() {
+                if self.paused {
+                    Ok(None)
+                } else {
+                    self.paused = true;
+                    Ok(Some(vec![sink::Reply::Insight(Event::cb_trigger(
+                        signal.ingest_ns,
+                    ))]))
+                }
+            } else if self.paused {
+                self.paused = false;
+                Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                    signal.ingest_ns,
+                ))]))
+            } else {
+                Ok(None)
+            };
+        }
         if self.stream.is_none() {
             let stream = if let Ok(stream) = Self::connect(&self.config).await {
                 stream
@@ -231,13 +421,51 @@ impl Sink for Tcp {
         }
     }
     fn is_active(&self) -> bool {
-        self.stream.is_some()
+        self.stream.is_some() || !self.clients.is_empty()
     }
 }
 
+/// Writes the packets to a client, giving up after `WRITE_TIMEOUT`
+async fn write_packets(stream: &mut Stream, packets: &[Vec<u8>]) -> Result<()> {
+    let write = async {
+        for packet in packets {
+            stream.write_all(packet).await?;
+        }
+        Ok::<(), std::io::Error>(())
+    };
+    timeout(WRITE_TIMEOUT, write).await.map_err(|_| {
+        Error::from(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "Timed out writing to client",
+        ))
+    })??;
+    Ok(())
+}
+
+/// Wraps the stream in TLS if configured, `true` verifies the server against
+/// the default root certificates, the domain defaults to `host`
+pub(crate) async fn tls_connect(
+    stream: TcpStream,
+    host: &str,
+    tls: Option<&Either<TLSConfig, bool>>,
+) -> Result<Box<dyn Conn>> {
+    Ok(match tls {
+        Some(Either::Right(true)) => {
+            let c = connector(&TLSConfig::default()).await?;
+            Box::new(c.connect(host, stream).await?)
+        }
+        Some(Either::Left(tls)) => {
+            let c = connector(tls).await?;
+            let domain = tls.domain.as_ref().map_or(host, String::as_str);
+            Box::new(c.connect(domain, stream).await?)
+        }
+        Some(Either::Right(false)) | None => Box::new(stream),
+    })
+}
+
 /// if we have a cafile configured, we only load it, and no other ca certificates
 /// if there is no cafile configured, we load the default webpki-roots from Mozilla
-async fn connector(config: &TLSConfig) -> Result<TlsConnector> {
+pub(crate) async fn connector(config: &TLSConfig) -> Result<TlsConnector> {
     Ok(match config {
         TLSConfig {
             cafile: Some(cafile),
@@ -257,3 +485,103 @@ async fn connector(config: &TLSConfig) -> Result<TlsConnector> {
         TLSConfig { cafile: None, .. } => TlsConnector::default(),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const EVENT: &str = r#"{"snot":"badger"}"#;
+
+    fn sink(config: &str) -> Result<Tcp> {
+        Ok(Tcp::new(serde_yaml::from_str(config)?))
+    }
+
+    /// Sends a transactional event, returns the actions of the insights
+    async fn send(sink: &mut Tcp, codec: &mut dyn Codec) -> Result<Vec<CbAction>> {
+        let event = Event {
+            data: (literal!({"snot": "badger"}), Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        };
+        let replies = sink.on_event("in", codec, &HashMap::new(), event).await?;
+        Ok(replies
+            .into_iter()
+            .flatten()
+            .filter_map(|reply| match reply {
+                sink::Reply::Insight(insight) => Some(insight.cb),
+                sink::Reply::Response(..) => None,
+            })
+            .collect())
+    }
+
+    async fn read(stream: &mut TcpStream) -> Result<String> {
+        let mut data = vec![0; EVENT.len()];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut data))
+            .await
+            .map_err(|_| Error::from("Timed out reading the event"))??;
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    #[async_std::test]
+    async fn client() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let mut sink = sink(&format!("host: 127.0.0.1\nport: {}", port))?;
+        let mut codec = crate::codec::lookup("json")?;
+        assert_eq!(
+            vec![CbAction::Fail, CbAction::Close],
+            send(&mut sink, codec.as_mut()).await?
+        );
+
+        // the signal connects and restores the circuit breaker
+        let replies = sink.on_signal(Event::default()).await?;
+        assert!(matches!(
+            replies.as_deref(),
+            Some([sink::Reply::Insight(Event {
+                cb: CbAction::Open,
+                ..
+            })])
+        ));
+        let (mut server, _) = listener.accept().await?;
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, read(&mut server).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn server() -> Result<()> {
+        let mut sink = sink("host: 127.0.0.1\nport: 0\nmode: server")?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        sink.accept(listener)?;
+        let mut codec = crate::codec::lookup("json")?;
+        assert_eq!(
+            vec![CbAction::Fail, CbAction::Close],
+            send(&mut sink, codec.as_mut()).await?
+        );
+
+        let mut first = TcpStream::connect(addr).await?;
+        let mut second = TcpStream::connect(addr).await?;
+        for _ in 0..500 {
+            sink.accept_clients();
+            if sink.clients.len() == 2 {
+                break;
+            }
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(2, sink.clients.len());
+
+        // broadcast sends the event to every client
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, read(&mut first).await?);
+        assert_eq!(EVENT, read(&mut second).await?);
+
+        // round robin sends one event to each of them
+        sink.config.distribution = Distribution::RoundRobin;
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, read(&mut first).await?);
+        assert_eq!(EVENT, read(&mut second).await?);
+        Ok(())
+    }
+}
@@ -18,6 +18,10 @@
 //!
 //! Sends each message as a udp datagram
 //!
+//! To send to a multicast group, use its address as `host`, `multicast_ttl`
+//! and `multicast_loop` control how far the datagrams travel and whether they
+//! are looped back to this host. Broadcast addresses require `broadcast`.
+//!
 //! ## Configuration
 //!
 //! See [Config](struct.Config.html) for details.
@@ -27,6 +31,7 @@ use std::time::Instant;
 use crate::sink::prelude::*;
 use async_std::net::UdpSocket;
 use halfbrown::HashMap;
+use socket2::SockRef;
 
 /// An offramp that write a given file
 pub struct Udp {
@@ -60,6 +65,13 @@ pub struct Config {
     bind: Host,
     #[serde(default = "t")]
     bound: bool,
+    /// allow sending to broadcast addresses
+    #[serde(default)]
+    broadcast: bool,
+    /// time to live (IPv4) or hop limit (IPv6) of multicast datagrams
+    multicast_ttl: Option<u32>,
+    /// if multicast datagrams are looped back to this host
+    multicast_loop: Option<bool>,
 }
 
 fn t() -> bool {
@@ -78,6 +90,7 @@ impl Udp {
             let socket =
                 UdpSocket::bind((self.config.bind.host.as_str(), self.config.bind.port)).await?;
             info!("[Sink::UDP] bound.");
+            self.configure(&socket)?;
             if self.config.bound {
                 info!(
                     "[Sink::UDP] set peer address to {}:{} ...",
@@ -92,6 +105,30 @@ impl Udp {
         };
         Ok(())
     }
+
+    /// Applies the broadcast and multicast settings
+    fn configure(&self, socket: &UdpSocket) -> Result<()> {
+        if self.config.broadcast {
+            socket.set_broadcast(true)?;
+        }
+        let is_ipv4 = socket.local_addr()?.is_ipv4();
+        if let Some(ttl) = self.config.multicast_ttl {
+            if is_ipv4 {
+                socket.set_multicast_ttl_v4(ttl)?;
+            } else {
+                // std only exposes the unicast hop limit for IPv6
+                SockRef::from(socket).set_multicast_hops_v6(ttl)?;
+            }
+        }
+        if let Some(multicast_loop) = self.config.multicast_loop {
+            if is_ipv4 {
+                socket.set_multicast_loop_v4(multicast_loop)?;
+            } else {
+                socket.set_multicast_loop_v6(multicast_loop)?;
+            }
+        }
+        Ok(())
+    }
 }
 impl offramp::Impl for Udp {
     fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
@@ -234,3 +271,38 @@ impl Sink for Udp {
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sink(config: &str) -> Result<Udp> {
+        Ok(Udp {
+            socket: None,
+            config: serde_yaml::from_str(config)?,
+            postprocessors: vec![],
+        })
+    }
+
+    #[async_std::test]
+    async fn broadcast_and_multicast_ttl() -> Result<()> {
+        let sink = sink("host: 255.255.255.255\nport: 4242\nbroadcast: true\nmulticast_ttl: 3")?;
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        sink.configure(&socket)?;
+        assert!(socket.broadcast()?);
+        assert_eq!(3, socket.multicast_ttl_v4()?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn multicast_hops_v6() -> Result<()> {
+        let sink = sink("host: \"ff02::1\"\nport: 4242\nmulticast_ttl: 3\nmulticast_loop: false")?;
+        let socket = UdpSocket::bind("[::1]:0").await?;
+        let unicast_hops = SockRef::from(&socket).unicast_hops_v6()?;
+        sink.configure(&socket)?;
+        assert_eq!(3, SockRef::from(&socket).multicast_hops_v6()?);
+        assert_eq!(unicast_hops, SockRef::from(&socket).unicast_hops_v6()?);
+        assert!(!socket.multicast_loop_v6()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Unix Socket Offramp
+//!
+//! Sends each message to a unix domain socket, either as part of a stream or
+//! as a datagram. Lost connections are reestablished on the next signal.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use std::time::Instant;
+
+use crate::sink::prelude::*;
+use crate::source::unix_socket::SocketType;
+use async_std::os::unix::net::{UnixDatagram, UnixStream};
+use halfbrown::HashMap;
+
+enum Conn {
+    Stream(UnixStream),
+    Datagram(UnixDatagram),
+}
+
+impl Conn {
+    async fn send(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Self::Stream(stream) => stream.write_all(data).await?,
+            Self::Datagram(socket) => {
+                socket.send(data).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An offramp that writes to a unix domain socket
+pub struct UnixSocket {
+    conn: Option<Conn>,
+    postprocessors: Postprocessors,
+    config: Config,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    /// path of the socket to connect to
+    pub path: String,
+    /// `stream` (default) or `datagram`
+    #[serde(default)]
+    pub socket_type: SocketType,
+}
+
+impl ConfigImpl for Config {}
+
+impl offramp::Impl for UnixSocket {
+    fn from_config(config: &Option<OpConfig>) -> Result<Box<dyn Offramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(SinkManager::new_box(Self {
+                config,
+                conn: None,
+                postprocessors: vec![],
+            }))
+        } else {
+            Err("Missing config for unix-socket offramp".into())
+        }
+    }
+}
+
+impl UnixSocket {
+    async fn send_event(&mut self, codec: &mut dyn Codec, event: &Event) -> Result<()> {
+        let conn = self
+            .conn
+            .as_mut()
+            .ok_or_else(|| Error::from(ErrorKind::NoSocket))?;
+        for value in event.value_iter() {
+            let raw = codec.encode(value)?;
+            for packet in postprocess(&mut self.postprocessors, event.ingest_ns, raw)? {
+                conn.send(&packet).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn connect(config: &Config) -> Result<Conn> {
+        Ok(match config.socket_type {
+            SocketType::Stream => Conn::Stream(UnixStream::connect(&config.path).await?),
+            SocketType::Datagram => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&config.path)?;
+                Conn::Datagram(socket)
+            }
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Sink for UnixSocket {
+    /// We acknowledge ourself
+    fn auto_ack(&self) -> bool {
+        false
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    async fn on_event(
+        &mut self,
+        _input: &str,
+        codec: &mut dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        mut event: Event,
+    ) -> ResultVec {
+        let processing_start = Instant::now();
+        let replies = match self.send_event(codec, &event).await {
+            Ok(()) if event.transactional => Some(vec![sink::Reply::Insight(
+                event.insight_ack_with_timing(processing_start.elapsed().as_millis() as u64),
+            )]),
+            Ok(()) => None,
+            // the socket is gone, reconnect on the next signal
+            Err(e @ Error(ErrorKind::Io(_) | ErrorKind::NoSocket, _)) => {
+                debug!("[Sink::UnixSocket] Error sending event: {}", e);
+                self.conn = None;
+                let mut replies = vec![sink::Reply::Insight(event.insight_trigger())];
+                if event.transactional {
+                    replies.push(sink::Reply::Insight(event.to_fail()));
+                }
+                Some(replies)
+            }
+            Err(e) => {
+                debug!("[Sink::UnixSocket] Error sending event: {}", e);
+                event
+                    .transactional
+                    .then(|| vec![sink::Reply::Insight(event.to_fail())])
+            }
+        };
+        Ok(replies)
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn init(
+        &mut self,
+        _sink_uid: u64,
+        _sink_url: &TremorUrl,
+        _codec: &dyn Codec,
+        _codec_map: &HashMap<String, Box<dyn Codec>>,
+        processors: Processors<'_>,
+        _is_linked: bool,
+        _reply_channel: Sender<sink::Reply>,
+    ) -> Result<()> {
+        self.postprocessors = make_postprocessors(processors.post)?;
+        self.conn = Some(Self::connect(&self.config).await?);
+        Ok(())
+    }
+
+    async fn on_signal(&mut self, signal: Event) -> ResultVec {
+        if self.conn.is_none() {
+            match Self::connect(&self.config).await {
+                Ok(conn) => {
+                    self.conn = Some(conn);
+                    Ok(Some(vec![sink::Reply::Insight(Event::cb_restore(
+                        signal.ingest_ns,
+                    ))]))
+                }
+                Err(e) => {
+                    debug!("[Sink::UnixSocket] Unable to reconnect: {}", e);
+                    Ok(None)
+                }
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.conn.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::time::Duration;
+
+    const EVENT: &str = r#"{"snot":"badger"}"#;
+
+    fn sink(path: &Path, socket_type: &str) -> Result<UnixSocket> {
+        let config = format!("path: {}\nsocket_type: {}", path.display(), socket_type);
+        Ok(UnixSocket {
+            conn: None,
+            postprocessors: vec![],
+            config: serde_yaml::from_str(&config)?,
+        })
+    }
+
+    /// Sends a transactional event, returns the actions of the insights
+    async fn send(sink: &mut UnixSocket, codec: &mut dyn Codec) -> Result<Vec<CbAction>> {
+        let event = Event {
+            data: (literal!({"snot": "badger"}), Value::object()).into(),
+            transactional: true,
+            ..Event::default()
+        };
+        let replies = sink.on_event("in", codec, &HashMap::new(), event).await?;
+        Ok(replies
+            .into_iter()
+            .flatten()
+            .filter_map(|reply| match reply {
+                sink::Reply::Insight(insight) => Some(insight.cb),
+                sink::Reply::Response(..) => None,
+            })
+            .collect())
+    }
+
+    /// Signals the sink, returns if it reconnected
+    async fn reconnect(sink: &mut UnixSocket) -> Result<bool> {
+        let replies = sink.on_signal(Event::default()).await?;
+        Ok(matches!(
+            replies.as_deref(),
+            Some([sink::Reply::Insight(Event {
+                cb: CbAction::Open,
+                ..
+            })])
+        ))
+    }
+
+    async fn read(stream: &mut UnixStream) -> Result<String> {
+        let mut data = vec![0; EVENT.len()];
+        timeout(Duration::from_secs(5), stream.read_exact(&mut data))
+            .await
+            .map_err(|_| Error::from("Timed out reading the event"))??;
+        Ok(String::from_utf8_lossy(&data).to_string())
+    }
+
+    async fn recv(socket: &UnixDatagram) -> Result<String> {
+        let mut data = vec![0; 1024];
+        let n = timeout(Duration::from_secs(5), socket.recv(&mut data))
+            .await
+            .map_err(|_| Error::from("Timed out receiving the event"))??;
+        Ok(String::from_utf8_lossy(data.get(..n).unwrap_or_default()).to_string())
+    }
+
+    #[async_std::test]
+    async fn stream() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stream.sock");
+        let mut sink = sink(&path, "stream")?;
+        let mut codec = crate::codec::lookup("json")?;
+        // nobody is listening yet
+        assert!(!reconnect(&mut sink).await?);
+        assert_eq!(
+            vec![CbAction::Close, CbAction::Fail],
+            send(&mut sink, codec.as_mut()).await?
+        );
+
+        let listener = UnixListener::bind(&path).await?;
+        assert!(reconnect(&mut sink).await?);
+        let (mut server, _) = listener.accept().await?;
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, read(&mut server).await?);
+
+        // the peer goes away, the sink reconnects once it is back
+        drop(server);
+        drop(listener);
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            vec![CbAction::Close, CbAction::Fail],
+            send(&mut sink, codec.as_mut()).await?
+        );
+        assert!(!sink.is_active());
+        assert!(!reconnect(&mut sink).await?);
+        let listener = UnixListener::bind(&path).await?;
+        assert!(reconnect(&mut sink).await?);
+        let (mut server, _) = listener.accept().await?;
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, read(&mut server).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn datagram() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("datagram.sock");
+        let mut sink = sink(&path, "datagram")?;
+        let mut codec = crate::codec::lookup("json")?;
+        assert!(!reconnect(&mut sink).await?);
+
+        let server = UnixDatagram::bind(&path).await?;
+        assert!(reconnect(&mut sink).await?);
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, recv(&server).await?);
+
+        // the peer goes away, the sink reconnects once it is back
+        drop(server);
+        std::fs::remove_file(&path)?;
+        assert_eq!(
+            vec![CbAction::Close, CbAction::Fail],
+            send(&mut sink, codec.as_mut()).await?
+        );
+        assert!(!reconnect(&mut sink).await?);
+        let server = UnixDatagram::bind(&path).await?;
+        assert!(reconnect(&mut sink).await?);
+        assert_eq!(vec![CbAction::Ack], send(&mut sink, codec.as_mut()).await?);
+        assert_eq!(EVENT, recv(&server).await?);
+        Ok(())
+    }
+}
@@ -17,11 +17,11 @@
 use crate::sink::prelude::*;
 use crate::source::prelude::*;
 use async_channel::{bounded, unbounded, Receiver, Sender};
-use async_std::net::TcpStream;
+use async_tungstenite::async_std::{connect_async, ConnectStream};
 use async_tungstenite::tungstenite::error::Error as WsError;
 use async_tungstenite::tungstenite::error::ProtocolError as WsProtocolError;
 use async_tungstenite::tungstenite::Message;
-use async_tungstenite::{async_std::connect_async, WebSocketStream};
+use async_tungstenite::WebSocketStream;
 use futures::SinkExt;
 use halfbrown::HashMap;
 use std::boxed::Box;
@@ -119,7 +119,7 @@ async fn handle_error(
 /// close the given stream if it is not already closed.
 async fn close_stream_on_error(
     e: WsError,
-    stream: &mut WebSocketStream<TcpStream>,
+    stream: &mut WebSocketStream<ConnectStream>,
     sink_url: &TremorUrl,
     url: &str,
 ) {
@@ -42,23 +42,34 @@ pub(crate) mod blaster;
 pub(crate) mod cb;
 pub(crate) mod crononome;
 pub(crate) mod discord;
+pub(crate) mod elastic;
+pub(crate) mod elastic_bulk;
 pub(crate) mod env;
+pub(crate) mod exec;
 pub(crate) mod file;
+pub(crate) mod fluent;
 pub(crate) mod gsub;
 pub(crate) mod kafka;
 pub(crate) mod metronome;
+pub(crate) mod mqtt;
 pub(crate) mod nats;
 pub(crate) mod otel;
 pub(crate) mod postgres;
+pub(crate) mod postgres_cdc;
 pub(crate) mod prelude;
+pub(crate) mod prometheus;
+pub(crate) mod prometheus_remote_write;
 pub(crate) mod rest;
+pub(crate) mod splunk_hec;
 pub(crate) mod sse;
 pub(crate) mod stdin;
+pub(crate) mod syslog;
 pub(crate) mod tcp;
 pub(crate) mod udp;
 #[cfg(unix)]
 pub mod unix_socket;
 pub(crate) mod ws;
+pub(crate) mod ws_client;
 
 struct StaticValue(Value<'static>);
 
@@ -103,6 +114,11 @@ pub(crate) enum SourceReply {
         origin_uri: EventOriginUri,
         data: EventPayload,
     },
+    /// Allow for passthrough of already structured events to the `err` port
+    StructuredErr {
+        origin_uri: EventOriginUri,
+        data: EventPayload,
+    },
     /// A stream is opened
     StartStream(usize),
     /// A stream is closed
@@ -239,18 +255,24 @@ where
             Ok(data) => {
                 let meta_value = meta.map_or_else(Value::object, |m| m.0);
                 for d in data {
+                    // a codec can decode multiple values out of a single buffer,
+                    // the first one keeps referencing the buffer, all following
+                    // values are owned and become events of their own
+                    let mut rest: Vec<Value<'static>> = Vec::new();
                     let line_value = EventPayload::try_new::<Option<Error>, _>(d, |mut_data| {
                         let codec_map = &mut self.codec_map;
                         let codec = codec_override
                             .as_ref()
                             .and_then(|codec_name| codec_map.get_mut(codec_name))
                             .unwrap_or(&mut self.codec);
-                        let decoded = codec.decode(mut_data, *ingest_ns);
+                        let decoded = codec.decode_stream(mut_data, *ingest_ns, stream);
                         match decoded {
-                            Ok(None) => Err(None),
                             Err(e) => Err(Some(e)),
-                            Ok(Some(decoded)) => {
-                                Ok(ValueAndMeta::from_parts(decoded, meta_value.clone()))
+                            Ok(decoded) => {
+                                let mut decoded = decoded.into_iter();
+                                let first = decoded.next().ok_or(None)?;
+                                rest.extend(decoded.map(Value::into_static));
+                                Ok(ValueAndMeta::from_parts(first, meta_value.clone()))
                             }
                         }
                     });
@@ -262,6 +284,12 @@ where
                             results.push(Err(e));
                         }
                     }
+                    results.extend(rest.into_iter().map(|value| {
+                        Ok(EventPayload::from(ValueAndMeta::from_parts(
+                            value,
+                            meta_value.clone(),
+                        )))
+                    }));
                 }
             }
             Err(e) => {
@@ -482,7 +510,7 @@ where
         // N is the maximum number of counterflow events a single event can trigger.
         // N is normally < 1.
         let (tx, rx) = unbounded();
-        let codec = codec::lookup(config.codec)?;
+        let codec = codec::resolve(config.codec, &config.codec_config)?;
         let mut resolved_codec_map = codec::builtin_codec_map();
         // override the builtin map
         for (k, v) in config.codec_map {
@@ -561,12 +589,21 @@ where
                     }
                     Ok(SourceReply::EndStream(id)) => {
                         self.preprocessors.remove(&id);
+                        self.codec.end_stream(id);
+                        for codec in self.codec_map.values_mut() {
+                            codec.end_stream(id);
+                        }
                     }
                     Ok(SourceReply::Structured { origin_uri, data }) => {
                         let ingest_ns = nanotime();
 
                         self.transmit_event(data, ingest_ns, origin_uri, OUT).await;
                     }
+                    Ok(SourceReply::StructuredErr { origin_uri, data }) => {
+                        let ingest_ns = nanotime();
+
+                        self.transmit_event(data, ingest_ns, origin_uri, ERR).await;
+                    }
                     Ok(SourceReply::BatchData {
                         mut origin_uri,
                         batch_data,
@@ -690,6 +727,7 @@ mod tests {
         let o_config = OnrampConfig {
             onramp_uid: 1,
             codec: "string",
+            codec_config: None,
             codec_map: HashMap::new(),
             processors: Processors::default(),
             metrics_reporter: RampReporter::new(onramp_url.clone(), None),
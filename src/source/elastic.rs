@@ -0,0 +1,536 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Elastic Search Onramp
+//!
+//! Runs a query against one or more indices and emits every hit as an event,
+//! paginating via `search_after` (the default) or the scroll API. The event
+//! is the `_source` of the hit, `_index`, `_id`, `_score` and the `sort`
+//! values of the hit are available as `$elastic._index`, `$elastic._id`,
+//! `$elastic._score` and `$elastic.sort`.
+//!
+//! With `search_after` the sort values of the last acked hit can be written
+//! to a `checkpoint` file, after a restart the query resumes from there.
+//! Failed events are read again from the last acked hit. This requires a
+//! unique `sort`, e.g. a timestamp and a unique id field as a tie breaker.
+//! The checkpoint is written at most once per second.
+//!
+//! A scroll can't be rewound, so with `scroll` pagination delivery is
+//! at-most-once: failed events are logged and not read again.
+//!
+//! The onramp stops emitting events once all hits are read.
+//!
+//! ## Configuration
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use simd_json::OwnedValue;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// minimum time between two checkpoint writes
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pagination {
+    SearchAfter,
+    Scroll,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self::SearchAfter
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// list of elasticsearch cluster nodes, the next one is tried if a request fails
+    pub nodes: Vec<String>,
+    /// indices to search
+    pub indices: Vec<String>,
+    /// query to run, defaults to `{"match_all": {}}`
+    #[serde(default = "Default::default")]
+    pub query: Option<OwnedValue>,
+    /// sort of the hits, has to be unique for `search_after`, defaults to `["_doc"]` for `scroll`
+    #[serde(default = "Default::default")]
+    pub sort: Option<OwnedValue>,
+    /// number of hits per request, defaults to 1000
+    #[serde(default = "dflt_page_size")]
+    pub page_size: u64,
+    /// `search_after` or `scroll`, defaults to `search_after`
+    #[serde(default = "Default::default")]
+    pub pagination: Pagination,
+    /// how long to keep the scroll context alive between requests, defaults to `1m`
+    #[serde(default = "dflt_scroll")]
+    pub scroll: String,
+    /// file to store the sort values of the last acked hit in, requires `search_after`
+    #[serde(default = "Default::default")]
+    pub checkpoint: Option<String>,
+}
+
+fn dflt_page_size() -> u64 {
+    1000
+}
+
+fn dflt_scroll() -> String {
+    "1m".to_string()
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Elastic {
+    onramp_id: TremorUrl,
+    pub config: Config,
+}
+
+impl onramp::Impl for Elastic {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.nodes.is_empty() {
+                return Err("elastic onramp requires at least one node".into());
+            }
+            if config.indices.is_empty() {
+                return Err("elastic onramp requires at least one index".into());
+            }
+            if config.pagination == Pagination::SearchAfter && config.sort.is_none() {
+                return Err("elastic onramp requires a unique `sort` for `search_after`".into());
+            }
+            if config.checkpoint.is_some() && config.pagination != Pagination::SearchAfter {
+                return Err("elastic onramp checkpoints require `search_after` pagination".into());
+            }
+            Ok(Box::new(Self {
+                onramp_id: id.clone(),
+                config,
+            }))
+        } else {
+            Err("Missing config for elastic onramp".into())
+        }
+    }
+}
+
+struct Hit {
+    data: Value<'static>,
+    meta: Value<'static>,
+    sort: Value<'static>,
+}
+
+pub struct Int {
+    uid: u64,
+    onramp_id: TremorUrl,
+    config: Config,
+    client: surf::Client,
+    /// index of the node to send requests to
+    node: usize,
+    hits: VecDeque<Hit>,
+    /// sort values of the last hit read
+    search_after: Option<Value<'static>>,
+    scroll_id: Option<String>,
+    /// all hits are read
+    done: bool,
+    /// sort values of the last acked hit
+    acked: Option<Value<'static>>,
+    /// `acked` changed since the checkpoint got written
+    checkpoint_pending: bool,
+    checkpoint_written: Option<Instant>,
+    /// hits in flight by event id and if they are acked
+    in_flight: BTreeMap<u64, (Value<'static>, bool)>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Elastic")
+    }
+}
+
+/// Turns a search response into hits and the scroll id
+fn parse_response(mut body: Vec<u8>) -> Result<(Vec<Hit>, Option<String>)> {
+    let res = tremor_value::parse_to_value(&mut body)?;
+    let scroll_id = res.get_str("_scroll_id").map(ToString::to_string);
+    let hits = res
+        .get("hits")
+        .and_then(|h| h.get_array("hits"))
+        .map(|hits| {
+            hits.iter()
+                .map(|hit| {
+                    let get = |k: &str| hit.get(k).map_or_else(Value::null, Value::clone_static);
+                    let sort = get("sort");
+                    Hit {
+                        data: get("_source"),
+                        meta: literal!({
+                            "elastic": {
+                                "_index": get("_index"),
+                                "_id": get("_id"),
+                                "_score": get("_score"),
+                                "sort": sort.clone()
+                            }
+                        }),
+                        sort,
+                    }
+                })
+                .collect()
+        })
+        .ok_or_else(|| Error::from("Invalid search response"))?;
+    Ok((hits, scroll_id))
+}
+
+fn read_checkpoint(path: &str) -> Result<Option<Value<'static>>> {
+    if Path::new(path).exists() {
+        let mut data = std::fs::read(path)?;
+        Ok(Some(tremor_value::parse_to_value(&mut data)?.into_static()))
+    } else {
+        Ok(None)
+    }
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Result<Self> {
+        let search_after = match &config.checkpoint {
+            Some(path) => read_checkpoint(path)?,
+            None => None,
+        };
+        Ok(Self {
+            uid,
+            onramp_id,
+            config: config.clone(),
+            client: surf::client(),
+            node: 0,
+            hits: VecDeque::new(),
+            acked: search_after.clone(),
+            checkpoint_pending: false,
+            checkpoint_written: None,
+            search_after,
+            scroll_id: None,
+            done: false,
+            in_flight: BTreeMap::new(),
+        })
+    }
+
+    fn node(&self) -> &str {
+        self.config.nodes[self.node % self.config.nodes.len()].trim_end_matches('/')
+    }
+
+    async fn post(&self, path: &str, body: &Value<'_>) -> Result<Vec<u8>> {
+        let url = format!("{}/{}", self.node(), path);
+        let mut res = self
+            .client
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .body(body.encode())
+            .await?;
+        let body = res.body_bytes().await?;
+        if res.status().is_success() {
+            Ok(body)
+        } else {
+            Err(format!(
+                "Request to {} failed with {}: {}",
+                url,
+                res.status(),
+                String::from_utf8_lossy(&body)
+            )
+            .into())
+        }
+    }
+
+    /// Reads the next page of hits
+    async fn search(&mut self) -> Result<()> {
+        let indices = self.config.indices.join(",");
+        let body = if let Some(scroll_id) = &self.scroll_id {
+            let body = literal!({
+                "scroll": self.config.scroll.clone(),
+                "scroll_id": scroll_id.clone()
+            });
+            self.post("_search/scroll", &body).await?
+        } else {
+            let mut query = literal!({
+                "size": self.config.page_size,
+                "query": self.config.query.clone().map_or_else(|| literal!({"match_all": {}}), Value::from),
+                "sort": self.config.sort.clone().map_or_else(|| literal!(["_doc"]), Value::from)
+            });
+            match self.config.pagination {
+                Pagination::SearchAfter => {
+                    if let Some(search_after) = &self.search_after {
+                        query.try_insert("search_after", search_after.clone());
+                    }
+                    self.post(&format!("{}/_search", indices), &query).await?
+                }
+                Pagination::Scroll => {
+                    let path = format!("{}/_search?scroll={}", indices, self.config.scroll);
+                    self.post(&path, &query).await?
+                }
+            }
+        };
+        let (hits, scroll_id) = parse_response(body)?;
+        if self.config.pagination == Pagination::Scroll {
+            self.scroll_id = scroll_id;
+        }
+        if let Some(last) = hits.last() {
+            self.search_after = Some(last.sort.clone());
+        } else {
+            self.done = true;
+            info!("[Source::{}] Read all hits", self.onramp_id);
+            if let Some(scroll_id) = self.scroll_id.take() {
+                let url = format!("{}/_search/scroll", self.node());
+                let body = literal!({ "scroll_id": [scroll_id] });
+                if let Err(e) = self.client.delete(&url).body(body.encode()).await {
+                    warn!("[Source::{}] Failed to clear scroll: {}", self.onramp_id, e);
+                }
+            }
+        }
+        self.hits.extend(hits);
+        Ok(())
+    }
+
+    /// Writes the sort values of the last acked hit, at most once per `CHECKPOINT_INTERVAL`
+    async fn write_checkpoint(&mut self) -> Result<()> {
+        let due = self
+            .checkpoint_written
+            .map_or(true, |written| written.elapsed() >= CHECKPOINT_INTERVAL);
+        if self.checkpoint_pending && due {
+            if let (Some(path), Some(acked)) = (&self.config.checkpoint, &self.acked) {
+                async_std::fs::write(path, acked.encode()).await?;
+            }
+            self.checkpoint_pending = false;
+            self.checkpoint_written = Some(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        if let Err(e) = self.write_checkpoint().await {
+            error!(
+                "[Source::{}] Failed to write checkpoint: {}",
+                self.onramp_id, e
+            );
+        }
+        if self.hits.is_empty() && !self.done {
+            if let Err(e) = self.search().await {
+                error!("[Source::{}] Search failed: {}", self.onramp_id, e);
+                // a scroll context is bound to the node that created it
+                if self.scroll_id.is_none() {
+                    self.node += 1;
+                }
+                return Ok(SourceReply::Empty(1000));
+            }
+        }
+        if let Some(Hit { data, meta, sort }) = self.hits.pop_front() {
+            self.in_flight.insert(id, (sort, false));
+            let origin_uri = EventOriginUri {
+                uid: self.uid,
+                scheme: "tremor-elastic".to_string(),
+                host: self.node().to_string(),
+                port: None,
+                path: vec![meta
+                    .get("elastic")
+                    .and_then(|m| m.get_str("_index"))
+                    .unwrap_or_default()
+                    .to_string()],
+            };
+            Ok(SourceReply::Structured {
+                origin_uri,
+                data: (data, meta).into(),
+            })
+        } else {
+            Ok(SourceReply::Empty(1000))
+        }
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    fn ack(&mut self, id: u64) {
+        if let Some((_, acked)) = self.in_flight.get_mut(&id) {
+            *acked = true;
+        }
+        while let Some((&front, (_, true))) = self.in_flight.iter().next() {
+            if let Some((sort, _)) = self.in_flight.remove(&front) {
+                self.acked = Some(sort);
+                self.checkpoint_pending = self.config.checkpoint.is_some();
+            }
+        }
+    }
+
+    fn fail(&mut self, id: u64) {
+        if !self.in_flight.contains_key(&id) {
+            return;
+        }
+        match self.config.pagination {
+            Pagination::SearchAfter => {
+                warn!(
+                    "[Source::{}] Event {} failed, reading hits after the last acked hit again",
+                    self.onramp_id, id
+                );
+                self.hits.clear();
+                self.in_flight.clear();
+                self.search_after = self.acked.clone();
+                self.done = false;
+            }
+            Pagination::Scroll => {
+                warn!(
+                    "[Source::{}] Event {} failed, it is dropped as a scroll can't be read again",
+                    self.onramp_id, id
+                );
+                // releases the hit like an ack so later acks aren't held back
+                self.ack(id);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for Elastic {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config)?;
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn response() -> Result<()> {
+        let body = br#"{
+            "_scroll_id": "snot",
+            "hits": {"hits": [
+                {"_index": "i", "_id": "1", "_score": null, "_source": {"a": 1}, "sort": [1, "1"]},
+                {"_index": "i", "_id": "2", "_score": null, "_source": {"a": 2}, "sort": [2, "2"]}
+            ]}
+        }"#;
+        let (hits, scroll_id) = parse_response(body.to_vec())?;
+        assert_eq!(Some("snot".to_string()), scroll_id);
+        assert_eq!(2, hits.len());
+        assert_eq!(literal!({"a": 2}), hits[1].data);
+        assert_eq!(literal!([2, "2"]), hits[1].sort);
+        assert_eq!(
+            literal!({"elastic": {"_index": "i", "_id": "1", "_score": null, "sort": [1, "1"]}}),
+            hits[0].meta
+        );
+        assert!(parse_response(b"{}".to_vec()).is_err());
+        Ok(())
+    }
+
+    /// An elasticsearch stand-in serving the documents 1, 2 and 3 sorted by `n`
+    async fn stand_in() -> Result<String> {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let mut app = tide::new();
+        app.at("/:index/_search")
+            .post(|mut req: tide::Request<()>| async move {
+                let mut body = req.body_bytes().await?;
+                let query = simd_json::to_owned_value(&mut body)?;
+                let after = query
+                    .get("search_after")
+                    .and_then(|s| s.get_idx(0))
+                    .and_then(ValueAccess::as_u64)
+                    .unwrap_or_default();
+                let size = usize::try_from(query.get_u64("size").unwrap_or_default())?;
+                let hits: Vec<Value> = (after + 1..=3)
+                    .take(size)
+                    .map(|n| {
+                        literal!({
+                            "_index": "i",
+                            "_id": n.to_string(),
+                            "_score": null,
+                            "_source": {"n": n},
+                            "sort": [n]
+                        })
+                    })
+                    .collect();
+                Ok(literal!({ "hits": { "hits": hits } }).encode())
+            });
+        task::spawn(app.listen(listener));
+        Ok(url)
+    }
+
+    async fn pull(source: &mut Int, id: u64) -> Result<Option<u64>> {
+        Ok(match source.pull_event(id).await? {
+            SourceReply::Structured { data, .. } => data.parts().0.get_u64("n"),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn scroll_fail() -> Result<()> {
+        let config: Config = serde_yaml::from_str(
+            "nodes: [\"http://127.0.0.1:9200\"]\nindices: [i]\npagination: scroll",
+        )?;
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("elastic")?, &config)?;
+        source.in_flight.insert(1, (literal!([1]), false));
+        source.in_flight.insert(2, (literal!([2]), false));
+        source.fail(1);
+        assert_eq!(1, source.in_flight.len());
+        source.ack(2);
+        assert!(source.in_flight.is_empty());
+        assert!(source.search_after.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn search_after() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let checkpoint = dir.path().join("checkpoint.json");
+        let config: Config = serde_yaml::from_str(&format!(
+            "nodes: [\"{}\"]\nindices: [i]\nsort: [n]\npage_size: 2\ncheckpoint: {}",
+            stand_in().await?,
+            checkpoint.display()
+        ))?;
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("elastic")?, &config)?;
+        assert_eq!(Some(1), pull(&mut source, 1).await?);
+        assert_eq!(Some(2), pull(&mut source, 2).await?);
+        assert_eq!(Some(3), pull(&mut source, 3).await?);
+        assert_eq!(None, pull(&mut source, 4).await?);
+        assert!(source.done);
+
+        // out of order acks only advance the checkpoint to the first unacked hit
+        source.ack(2);
+        source.ack(1);
+        pull(&mut source, 5).await?;
+        let mut written = async_std::fs::read(&checkpoint).await?;
+        assert_eq!(literal!([2]), tremor_value::parse_to_value(&mut written)?);
+
+        // a failed hit is read again after the last acked one
+        source.fail(3);
+        assert_eq!(Some(3), pull(&mut source, 6).await?);
+
+        // a restarted onramp resumes from the checkpoint
+        let mut source = Int::from_config(0, TremorUrl::from_onramp_id("elastic")?, &config)?;
+        assert_eq!(Some(3), pull(&mut source, 1).await?);
+        Ok(())
+    }
+}
@@ -0,0 +1,545 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Elastic Search bulk onramp
+//!
+//! Emulates the parts of the elasticsearch HTTP API needed by Beats, Logstash
+//! and Fluent Bit to ship data: the version handshake on `/` and the `_bulk`
+//! endpoint on `/_bulk` and `/<index>/_bulk`.
+//!
+//! Every action of a bulk request is emitted as an event. The event is the
+//! document (for `update` the whole `{"doc": ..}` line, for `delete` `null`),
+//! the action, index and document id are available as `$elastic.action`,
+//! `$elastic.index` and `$elastic.doc_id`.
+//!
+//! The response is sent once all events of the request got acked or failed,
+//! or after `timeout_ms`. Failed items are reported with status `500`, items
+//! without ack or fail in time with status `429` so clients retry them.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use async_channel::{unbounded, Sender, TryRecvError};
+use async_std::future::timeout;
+use halfbrown::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+use tide::{Request, Response};
+use tremor_common::time::nanotime;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// port to listen to, defaults to 9200
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// elasticsearch version to report to clients, defaults to "7.10.2"
+    #[serde(default = "dflt_version")]
+    pub version: String,
+    /// time to wait for all items of a request to be acked, defaults to 10s
+    #[serde(default = "dflt_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn dflt_port() -> u16 {
+    9200
+}
+
+fn dflt_version() -> String {
+    String::from("7.10.2")
+}
+
+fn dflt_timeout_ms() -> u64 {
+    10_000
+}
+
+pub struct ElasticBulk {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for ElasticBulk {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for elastic-bulk onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for ElasticBulk {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+            pending: HashMap::new(),
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+/// Where to report the outcome of an item: the requests channel and the index
+/// of the item in the request
+type ResultTx = (Sender<(usize, bool)>, usize);
+
+struct Item {
+    origin_uri: EventOriginUri,
+    data: Value<'static>,
+    meta: Value<'static>,
+    result: ResultTx,
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<Item>>,
+    /// events waiting for their ack or fail
+    pending: HashMap<u64, ResultTx>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ElasticBulk")
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    tx: Sender<Item>,
+    uid: u64,
+    port: u16,
+    version: String,
+    timeout: Duration,
+}
+
+/// A parsed action of a bulk request
+#[derive(Debug, PartialEq)]
+struct BulkItem {
+    action: String,
+    index: Option<String>,
+    id: String,
+    doc: Value<'static>,
+}
+
+/// Parses a bulk request body, invalid actions are returned as errors
+fn parse_bulk(
+    body: &[u8],
+    default_index: Option<&str>,
+) -> Vec<std::result::Result<BulkItem, String>> {
+    let mut items = Vec::new();
+    let mut lines = body
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.iter().all(u8::is_ascii_whitespace));
+    let ingest_ns = nanotime();
+    while let Some(line) = lines.next() {
+        let mut line = line.to_vec();
+        let action = match tremor_value::parse_to_value(&mut line) {
+            Ok(action) => action,
+            Err(e) => {
+                // skip the document that belongs to the action
+                lines.next();
+                items.push(Err(format!("Malformed action: {}", e)));
+                continue;
+            }
+        };
+        let (name, params) = match action.as_object().and_then(|o| o.iter().next()) {
+            Some((name, params)) => (name.to_string(), params),
+            None => {
+                lines.next();
+                items.push(Err("Malformed action, expected a record".to_string()));
+                continue;
+            }
+        };
+        let index = params
+            .get_str("_index")
+            .or(default_index)
+            .map(ToString::to_string);
+        let id = params.get_str("_id").map_or_else(
+            || format!("{:x}{:04x}", ingest_ns, items.len()),
+            ToString::to_string,
+        );
+        let doc = match name.as_str() {
+            "delete" => Ok(Value::null()),
+            "index" | "create" | "update" => match lines.next() {
+                Some(doc) => tremor_value::parse_to_value(&mut doc.to_vec())
+                    .map(Value::into_static)
+                    .map_err(|e| format!("Malformed document: {}", e)),
+                None => Err(format!("Missing document for {} action", name)),
+            },
+            other => {
+                // skip the document that belongs to the action
+                lines.next();
+                Err(format!("Unknown action: {}", other))
+            }
+        };
+        items.push(doc.map(|doc| BulkItem {
+            action: name,
+            index,
+            id,
+            doc,
+        }));
+    }
+    items
+}
+
+/// Builds the bulk response, `results` holds the outcome for every item that
+/// was emitted as event
+fn bulk_response(
+    items: &[std::result::Result<BulkItem, String>],
+    results: &[Option<bool>],
+    took: u64,
+) -> Value<'static> {
+    let mut errors = false;
+    let items: Vec<Value> = items
+        .iter()
+        .zip(results)
+        .map(|(item, result)| {
+            let (action, mut res) = match item {
+                Ok(item) => (
+                    item.action.clone(),
+                    literal!({
+                        "_index": item.index.clone(),
+                        "_id": item.id.clone(),
+                        "_version": 1
+                    }),
+                ),
+                Err(_) => ("index".to_string(), Value::object()),
+            };
+            let (status, error) = match (item, result) {
+                (Err(reason), _) => (400, Some(("illegal_argument_exception", reason.as_str()))),
+                (Ok(item), Some(true)) => {
+                    let (status, result) = match item.action.as_str() {
+                        "update" => (200, "updated"),
+                        "delete" => (200, "deleted"),
+                        _ => (201, "created"),
+                    };
+                    res.try_insert("result", result);
+                    (status, None)
+                }
+                (Ok(_), Some(false)) => (500, Some(("tremor_exception", "Event failed"))),
+                (Ok(_), None) => (429, Some(("es_rejected_execution_exception", "Timed out"))),
+            };
+            res.try_insert("status", status);
+            if let Some((t, reason)) = error {
+                errors = true;
+                res.try_insert(
+                    "error",
+                    literal!({ "type": t, "reason": reason.to_string() }),
+                );
+            }
+            let mut entry = Value::object_with_capacity(1);
+            entry.try_insert(action, res);
+            entry
+        })
+        .collect();
+    literal!({
+        "took": took,
+        "errors": errors,
+        "items": items
+    })
+}
+
+fn json(status: u16, body: &Value) -> Response {
+    Response::builder(status)
+        .header("X-Elastic-Product", "Elasticsearch")
+        .content_type(tide::http::mime::JSON)
+        .body(body.encode())
+        .build()
+}
+
+async fn handle_info(req: Request<ServerState>) -> tide::Result<Response> {
+    let body = literal!({
+        "name": hostname(),
+        "cluster_name": "tremor",
+        "cluster_uuid": "tremor",
+        "version": {
+            "number": req.state().version.clone(),
+            "build_flavor": "default",
+            "build_type": "tremor",
+            "lucene_version": "8.7.0",
+            "minimum_wire_compatibility_version": "6.8.0",
+            "minimum_index_compatibility_version": "6.0.0-beta1"
+        },
+        "tagline": "You Know, for Search"
+    });
+    Ok(json(200, &body))
+}
+
+async fn handle_bulk(mut req: Request<ServerState>) -> tide::Result<Response> {
+    let start = Instant::now();
+    let default_index = req.param("index").ok().map(ToString::to_string);
+    let body = req.body_bytes().await?;
+    let items = parse_bulk(&body, default_index.as_deref());
+    let state = req.state();
+    let origin_uri = EventOriginUri {
+        uid: state.uid,
+        scheme: "tremor-elastic-bulk".to_string(),
+        host: req
+            .remote()
+            .unwrap_or("tremor-elastic-bulk-client.remote")
+            .to_string(),
+        port: Some(state.port),
+        path: req
+            .url()
+            .path_segments()
+            .map(|s| s.map(String::from).collect())
+            .unwrap_or_default(),
+    };
+
+    let (result_tx, result_rx) = unbounded();
+    let mut outstanding = 0;
+    for (i, item) in items.iter().enumerate() {
+        if let Ok(item) = item {
+            let meta = literal!({
+                "elastic": {
+                    "action": item.action.clone(),
+                    "index": item.index.clone(),
+                    "doc_id": item.id.clone()
+                }
+            });
+            state
+                .tx
+                .send(Item {
+                    origin_uri: origin_uri.clone(),
+                    data: item.doc.clone(),
+                    meta,
+                    result: (result_tx.clone(), i),
+                })
+                .await?;
+            outstanding += 1;
+        }
+    }
+
+    let mut results = vec![None; items.len()];
+    let deadline = start + state.timeout;
+    while outstanding > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match timeout(remaining, result_rx.recv()).await {
+            Ok(Ok((i, ok))) => {
+                if let Some(result) = results.get_mut(i) {
+                    *result = Some(ok);
+                }
+                outstanding -= 1;
+            }
+            Ok(Err(_)) | Err(_) => break,
+        }
+    }
+    let took = u64::try_from(start.elapsed().as_millis()).unwrap_or(u64::MAX);
+    Ok(json(200, &bulk_response(&items, &results, took)))
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+
+        let mut server = tide::Server::with_state(ServerState {
+            tx,
+            uid: self.uid,
+            port: self.config.port,
+            version: self.config.version.clone(),
+            timeout: Duration::from_millis(self.config.timeout_ms),
+        });
+        server.at("/").get(handle_info);
+        server.at("/_bulk").post(handle_bulk).put(handle_bulk);
+        server
+            .at("/:index/_bulk")
+            .post(handle_bulk)
+            .put(handle_bulk);
+
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let source_id = self.onramp_id.to_string();
+
+        // the listener gets closed once the server and with it the sender is dropped
+        task::spawn(async move {
+            info!("[Source::{}] Listening at {}", source_id, addr);
+            if let Err(e) = server.listen(addr).await {
+                error!(
+                    "[Source::{}] Error while listening from the bulk server: {}",
+                    source_id, e
+                );
+            }
+            warn!("[Source::{}] Server stopped", source_id);
+        });
+
+        self.listener = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        match self.listener.as_ref().map(Receiver::try_recv) {
+            Some(Ok(Item {
+                origin_uri,
+                data,
+                meta,
+                result,
+            })) => {
+                self.pending.insert(id, result);
+                Ok(SourceReply::Structured {
+                    origin_uri,
+                    data: (data, meta).into(),
+                })
+            }
+            Some(Err(TryRecvError::Empty)) => Ok(SourceReply::Empty(10)),
+            Some(Err(TryRecvError::Closed)) | None => {
+                Ok(SourceReply::StateChange(SourceState::Disconnected))
+            }
+        }
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    fn ack(&mut self, id: u64) {
+        if let Some((tx, i)) = self.pending.remove(&id) {
+            if tx.try_send((i, true)).is_err() {
+                debug!(
+                    "[Source::{}] Request of event {} timed out",
+                    self.onramp_id, id
+                );
+            }
+        }
+    }
+
+    fn fail(&mut self, id: u64) {
+        if let Some((tx, i)) = self.pending.remove(&id) {
+            if tx.try_send((i, false)).is_err() {
+                debug!(
+                    "[Source::{}] Request of event {} timed out",
+                    self.onramp_id, id
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bulk() {
+        let body = br#"{"index": {"_index": "logs", "_id": "1"}}
+{"message": "snot"}
+{"delete": {"_id": "2"}}
+
+{"update": {"_id": "3"}}
+{"doc": {"message": "badger"}}
+{"snot": {}}
+{"message": "skipped"}
+{"create": {}}"#;
+        let items = parse_bulk(body, Some("default"));
+        assert_eq!(5, items.len());
+        assert_eq!(
+            Ok(BulkItem {
+                action: "index".to_string(),
+                index: Some("logs".to_string()),
+                id: "1".to_string(),
+                doc: literal!({"message": "snot"})
+            }),
+            items[0]
+        );
+        assert_eq!(
+            Ok(BulkItem {
+                action: "delete".to_string(),
+                index: Some("default".to_string()),
+                id: "2".to_string(),
+                doc: Value::null()
+            }),
+            items[1]
+        );
+        assert_eq!(
+            Ok(BulkItem {
+                action: "update".to_string(),
+                index: Some("default".to_string()),
+                id: "3".to_string(),
+                doc: literal!({"doc": {"message": "badger"}})
+            }),
+            items[2]
+        );
+        assert!(items[3].is_err());
+        assert!(items[4].is_err());
+
+        let response = bulk_response(&items, &[Some(true), Some(false), None, None, None], 3);
+        assert_eq!(
+            literal!({
+                "took": 3,
+                "errors": true,
+                "items": [
+                    {"index": {"_index": "logs", "_id": "1", "_version": 1, "result": "created", "status": 201}},
+                    {"delete": {"_index": "default", "_id": "2", "_version": 1, "status": 500,
+                        "error": {"type": "tremor_exception", "reason": "Event failed"}}},
+                    {"update": {"_index": "default", "_id": "3", "_version": 1, "status": 429,
+                        "error": {"type": "es_rejected_execution_exception", "reason": "Timed out"}}},
+                    {"index": {"status": 400, "error": {"type": "illegal_argument_exception", "reason": "Unknown action: snot"}}},
+                    {"index": {"status": 400, "error": {"type": "illegal_argument_exception", "reason": "Missing document for create action"}}}
+                ]
+            }),
+            response
+        );
+    }
+
+    #[test]
+    fn bad_action_in_batch() {
+        let body = br#"{"index": {"_id": "1"}}
+{"message": "snot"}
+{"index": {"_id": "2"
+{"message": "lost"}
+["index"]
+{"message": "lost too"}
+{"index": {"_id": "3"}}
+{"message": "badger"}"#;
+        let items = parse_bulk(body, None);
+        assert_eq!(4, items.len());
+        assert!(items[1].is_err());
+        assert!(items[2].is_err());
+        assert_eq!(
+            Ok(BulkItem {
+                action: "index".to_string(),
+                index: None,
+                id: "3".to_string(),
+                doc: literal!({"message": "badger"})
+            }),
+            items[3]
+        );
+    }
+}
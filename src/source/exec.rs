@@ -0,0 +1,327 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Exec onramp
+//!
+//! Runs a command and emits what it writes to stdout as is, use a preprocessor
+//! like `lines` to split it up into events. Lines written to stderr are sent
+//! as strings to the `err` port.
+//!
+//! Once the process exited it is restarted after `restart_delay_ms` depending
+//! on the `restart` policy: `never` (default), `on_failure` or `always`.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::exec::{Process, Restart};
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use async_std::io::BufReader;
+use async_std::process::ChildStdout;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub process: Process,
+    /// when to restart the process once it exited
+    #[serde(default)]
+    pub restart: Restart,
+    /// time to wait before restarting the process, defaults to 1s
+    #[serde(default = "dflt_restart_delay_ms")]
+    pub restart_delay_ms: u64,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_restart_delay_ms() -> u64 {
+    1_000
+}
+
+pub struct Exec {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Exec {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for exec onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for Exec {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "string"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Exec:{}", self.config.process.command)
+    }
+}
+
+/// Runs the process once, returns if it exited successfully
+async fn run_once(
+    config: &Config,
+    tx: &Sender<SourceReply>,
+    stream: usize,
+    origin_uri: &EventOriginUri,
+) -> Result<bool> {
+    let mut child = config.process.command(false, true, true).spawn()?;
+    let stdout = child.stdout.take().ok_or("Unable to capture stdout")?;
+    let stderr = child.stderr.take().ok_or("Unable to capture stderr")?;
+
+    let err_tx = tx.clone();
+    let err_uri = origin_uri.clone();
+    let errors = task::spawn(async move {
+        let mut stderr = BufReader::new(stderr);
+        let mut line = Vec::new();
+        while let Ok(n) = stderr.read_until(b'\n', &mut line).await {
+            if n == 0 {
+                break;
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            let reply = SourceReply::StructuredErr {
+                origin_uri: err_uri.clone(),
+                data: (
+                    Value::from(String::from_utf8_lossy(&line).into_owned()),
+                    Value::object(),
+                )
+                    .into(),
+            };
+            if err_tx.send(reply).await.is_err() {
+                break;
+            }
+            line.clear();
+        }
+    });
+
+    tx.send(SourceReply::StartStream(stream)).await?;
+    let read = forward(stdout, tx, stream, origin_uri).await;
+    errors.await;
+    // the stream is ended even if reading from stdout failed
+    tx.send(SourceReply::EndStream(stream)).await?;
+    read?;
+    Ok(child.status().await?.success())
+}
+
+/// Sends everything the process writes to stdout as is, splitting it up is
+/// left to the preprocessors
+async fn forward(
+    mut stdout: ChildStdout,
+    tx: &Sender<SourceReply>,
+    stream: usize,
+    origin_uri: &EventOriginUri,
+) -> Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let n = stdout.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        tx.send(SourceReply::Data {
+            origin_uri: origin_uri.clone(),
+            // ALLOW: we define n as part of the read
+            data: buffer[0..n].to_vec(),
+            meta: None,
+            codec_override: None,
+            stream,
+        })
+        .await?;
+    }
+}
+
+/// Runs the process and restarts it according to the restart policy
+async fn run(config: Config, tx: Sender<SourceReply>, origin_uri: EventOriginUri, id: String) {
+    let delay = Duration::from_millis(config.restart_delay_ms);
+    let mut stream = 0;
+    loop {
+        stream += 1;
+        let success = match run_once(&config, &tx, stream, &origin_uri).await {
+            Ok(success) => {
+                info!("[Source::{}] Process exited, success: {}", id, success);
+                success
+            }
+            Err(e) => {
+                warn!("[Source::{}] Process failed: {}", id, e);
+                false
+            }
+        };
+        if tx.is_closed() || !config.restart.applies(success) {
+            // dropping the sender stops the onramp
+            return;
+        }
+        task::sleep(delay).await;
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+        let origin_uri = EventOriginUri {
+            uid: self.uid,
+            scheme: "tremor-exec".to_string(),
+            host: hostname(),
+            port: None,
+            path: vec![self.config.process.command.clone()],
+        };
+        task::spawn(run(
+            self.config.clone(),
+            tx,
+            origin_uri,
+            self.onramp_id.to_string(),
+        ));
+        self.listener = Some(rx);
+
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(r) => Ok(r),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn source(config: &str) -> Result<Int> {
+        Ok(Int {
+            uid: 0,
+            config: serde_yaml::from_str(config)?,
+            onramp_id: TremorUrl::from_onramp_id("exec")?,
+            listener: None,
+        })
+    }
+
+    /// Pulls until the source has something to report
+    async fn next(source: &mut Int) -> Result<SourceReply> {
+        for _ in 0..500 {
+            match source.pull_event(0).await? {
+                SourceReply::Empty(_) => task::sleep(Duration::from_millis(10)).await,
+                reply => return Ok(reply),
+            }
+        }
+        Err("Timed out waiting for the source".into())
+    }
+
+    #[async_std::test]
+    async fn stdout_and_stderr() -> Result<()> {
+        let mut source = source(
+            r#"
+command: sh
+args: ["-c", "printf 'snot\\nbadger'; echo oh no >&2; exit 1"]
+restart: on_failure
+restart_delay_ms: 10
+"#,
+        )?;
+        assert!(matches!(source.init().await?, SourceState::Connected));
+
+        // the failed process is restarted with a new stream
+        for stream in 1..=2 {
+            assert!(matches!(next(&mut source).await?, SourceReply::StartStream(s) if s == stream));
+            let mut stdout = Vec::new();
+            let mut stderr = Vec::new();
+            loop {
+                match next(&mut source).await? {
+                    SourceReply::Data {
+                        data, stream: s, ..
+                    } if s == stream => stdout.extend(data),
+                    SourceReply::StructuredErr { data, .. } => {
+                        stderr.push(data.suffix().value().clone_static());
+                    }
+                    SourceReply::EndStream(s) if s == stream => break,
+                    _ => return Err("Unexpected reply".into()),
+                }
+            }
+            // stdout is passed on as is, without splitting or trimming it
+            assert_eq!(b"snot\nbadger".to_vec(), stdout);
+            assert_eq!(vec![Value::from("oh no")], stderr);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn binary_stdout() -> Result<()> {
+        let mut source = source(
+            r#"
+command: sh
+args: ["-c", "printf '\\377\\000'"]
+"#,
+        )?;
+        assert!(matches!(source.init().await?, SourceState::Connected));
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::StartStream(1)
+        ));
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::Data { data, .. } if data == [0xff, 0x00]
+        ));
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::EndStream(1)
+        ));
+        // the process isn't restarted, so the onramp stops
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::StateChange(SourceState::Disconnected)
+        ));
+        Ok(())
+    }
+}
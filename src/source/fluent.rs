@@ -0,0 +1,310 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Fluent Forward onramp
+//!
+//! Accepts the fluentd forward protocol over TCP (optionally TLS), as spoken
+//! by Fluent Bit's and fluentd's `forward` outputs. Messages in all modes are
+//! supported: `Message`, `Forward`, `PackedForward` and
+//! `CompressedPackedForward`.
+//!
+//! Every entry is emitted as an event with the record as data, its tag and
+//! timestamp in nanoseconds are available as `$fluent.tag` and `$fluent.time`.
+//!
+//! If the client asks for an acknowledgement via the `chunk` option, the
+//! chunk is only acknowledged once all of its events got acked. Chunks with
+//! failed events or events not acked within `ack_timeout_ms` are not
+//! acknowledged, so the client resends them.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::fluent::{decode_message, encode_ack, Framer, Message};
+use crate::source::prelude::*;
+use crate::source::tcp::{load_server_config, TLSConfig};
+use async_channel::{unbounded, Sender, TryRecvError};
+use async_std::future::timeout;
+use async_std::net::TcpListener;
+use async_tls::TlsAcceptor;
+use futures::io::{AsyncRead, AsyncWrite};
+use halfbrown::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BUFFER_SIZE_BYTES: usize = 8192;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// port to listen to, defaults to 24224
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// certificate and key to accept TLS connections with
+    pub tls: Option<TLSConfig>,
+    /// time to wait for all events of a chunk to be acked, defaults to 30s
+    #[serde(default = "dflt_ack_timeout_ms")]
+    pub ack_timeout_ms: u64,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn dflt_port() -> u16 {
+    24224
+}
+
+fn dflt_ack_timeout_ms() -> u64 {
+    30_000
+}
+
+pub struct Fluent {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Fluent {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for fluent onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for Fluent {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+            pending: HashMap::new(),
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+struct Item {
+    origin_uri: EventOriginUri,
+    data: Value<'static>,
+    meta: Value<'static>,
+    /// where to report the outcome of the event, if its chunk needs an ack
+    result: Option<Sender<bool>>,
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<Item>>,
+    /// events of chunks waiting for their ack or fail
+    pending: HashMap<u64, Sender<bool>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fluent")
+    }
+}
+
+/// Emits the entries of a message as events, returns if its chunk can be
+/// acknowledged, i.e. all of them got acked in time
+async fn emit(
+    message: Message,
+    tx: &Sender<Item>,
+    origin_uri: &EventOriginUri,
+    ack_timeout: Duration,
+) -> Result<bool> {
+    let Message {
+        tag,
+        entries,
+        chunk,
+    } = message;
+    let (result_tx, result_rx) = unbounded();
+    let outstanding = entries.len();
+    for (time, data) in entries {
+        tx.send(Item {
+            origin_uri: origin_uri.clone(),
+            data,
+            meta: literal!({
+                "fluent": {
+                    "tag": tag.clone(),
+                    "time": time
+                }
+            }),
+            result: chunk.as_ref().map(|_| result_tx.clone()),
+        })
+        .await?;
+    }
+    if chunk.is_none() {
+        return Ok(false);
+    }
+    let deadline = Instant::now() + ack_timeout;
+    for _ in 0..outstanding {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !matches!(timeout(remaining, result_rx.recv()).await, Ok(Ok(true))) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Reads messages from a connection until it is closed
+async fn serve<S>(
+    mut stream: S,
+    tx: Sender<Item>,
+    origin_uri: EventOriginUri,
+    ack_timeout: Duration,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = [0; BUFFER_SIZE_BYTES];
+    let mut framer = Framer::default();
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        // ALLOW: we define n as part of the read
+        framer.extend(&buffer[0..n]);
+        while let Some(value) = framer.next_value()? {
+            let message = decode_message(value)?;
+            let chunk = message.chunk.clone();
+            let acked = emit(message, &tx, &origin_uri, ack_timeout).await?;
+            if let Some(chunk) = chunk.filter(|_| acked) {
+                stream.write_all(&encode_ack(&chunk)?).await?;
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
+        let (tx, rx) = bounded(crate::QSIZE);
+        let uid = self.uid;
+        let path = vec![self.config.port.to_string()];
+        let ack_timeout = Duration::from_millis(self.config.ack_timeout_ms);
+        let source_id = self.onramp_id.to_string();
+        let server_config = if let Some(tls_config) = self.config.tls.as_ref() {
+            Some(load_server_config(tls_config)?)
+        } else {
+            None
+        };
+
+        task::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let tx = tx.clone();
+                let source_id = source_id.clone();
+                let origin_uri = EventOriginUri {
+                    uid,
+                    scheme: "tremor-fluent".to_string(),
+                    host: peer.ip().to_string(),
+                    port: Some(peer.port()),
+                    path: path.clone(),
+                };
+                let tls_acceptor = server_config
+                    .clone()
+                    .map(|s| TlsAcceptor::from(Arc::new(s)));
+                task::spawn(async move {
+                    let res = if let Some(acceptor) = tls_acceptor {
+                        match acceptor.accept(stream).await {
+                            Ok(tls_stream) => serve(tls_stream, tx, origin_uri, ack_timeout).await,
+                            Err(e) => Err(e.into()),
+                        }
+                    } else {
+                        serve(stream, tx, origin_uri, ack_timeout).await
+                    };
+                    if let Err(e) = res {
+                        warn!("[Source::{}] Closing connection: {}", source_id, e);
+                    }
+                });
+            }
+        });
+        self.listener = Some(rx);
+
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        match self.listener.as_ref().map(Receiver::try_recv) {
+            Some(Ok(Item {
+                origin_uri,
+                data,
+                meta,
+                result,
+            })) => {
+                if let Some(result) = result {
+                    self.pending.insert(id, result);
+                }
+                Ok(SourceReply::Structured {
+                    origin_uri,
+                    data: (data, meta).into(),
+                })
+            }
+            Some(Err(TryRecvError::Empty)) => Ok(SourceReply::Empty(10)),
+            Some(Err(TryRecvError::Closed)) | None => {
+                Ok(SourceReply::StateChange(SourceState::Disconnected))
+            }
+        }
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    fn ack(&mut self, id: u64) {
+        if let Some(tx) = self.pending.remove(&id) {
+            if tx.try_send(true).is_err() {
+                debug!(
+                    "[Source::{}] Chunk of event {} timed out",
+                    self.onramp_id, id
+                );
+            }
+        }
+    }
+
+    fn fail(&mut self, id: u64) {
+        if let Some(tx) = self.pending.remove(&id) {
+            if tx.try_send(false).is_err() {
+                debug!(
+                    "[Source::{}] Chunk of event {} timed out",
+                    self.onramp_id, id
+                );
+            }
+        }
+    }
+}
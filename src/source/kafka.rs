@@ -82,6 +82,28 @@ pub struct Config {
     #[serde(default = "default_check_topic_metadata")]
     pub check_topic_metadata: bool,
 
+    /// Replay the topics from a point in time or from explicit offsets, e.g.:
+    ///
+    /// ```yaml
+    /// start_from:
+    ///   timestamp: 1634567890000 # milliseconds since epoch
+    /// ```
+    ///
+    /// or
+    ///
+    /// ```yaml
+    /// start_from:
+    ///   offsets:
+    ///     my_topic:
+    ///       0: 42
+    ///       1: 1337
+    /// ```
+    ///
+    /// The partitions are assigned to this onramp directly instead of being
+    /// balanced within the consumer group.
+    #[serde(default = "Default::default")]
+    pub start_from: Option<StartFrom>,
+
     /// Optional rdkafka configuration
     ///
     /// Default settings:
@@ -95,6 +117,56 @@ pub struct Config {
     pub rdkafka_options: Option<HashMap<String, String>>,
 }
 
+/// Position to start consuming from
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum StartFrom {
+    /// the first message with a timestamp (in milliseconds) at or after the given one
+    Timestamp(i64),
+    /// explicit offsets per topic and partition
+    Offsets(HashMap<String, HashMap<i32, i64>>),
+}
+
+impl StartFrom {
+    /// the partitions and offsets to assign to the consumer
+    fn assignment(
+        &self,
+        consumer: &LoggingConsumer,
+        topics: &[String],
+    ) -> Result<TopicPartitionList> {
+        let mut tpl = TopicPartitionList::new();
+        match self {
+            Self::Timestamp(timestamp) => {
+                for topic in topics {
+                    let metadata = consumer.fetch_metadata(Some(topic), Duration::from_secs(1))?;
+                    for t in metadata.topics().iter().filter(|t| t.name() == topic) {
+                        for partition in t.partitions() {
+                            tpl.add_partition_offset(
+                                topic,
+                                partition.id(),
+                                Offset::Offset(*timestamp),
+                            )?;
+                        }
+                    }
+                }
+                if tpl.count() == 0 {
+                    return Err("No partitions found for the configured topics".into());
+                }
+                // turns the timestamps into the offsets of the first message at or after them
+                Ok(consumer.offsets_for_times(tpl, Duration::from_secs(10))?)
+            }
+            Self::Offsets(offsets) => {
+                for (topic, partitions) in offsets {
+                    for (partition, offset) in partitions {
+                        tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+                    }
+                }
+                Ok(tpl)
+            }
+        }
+    }
+}
+
 /// defaults to `true` to keep backwards compatibility
 fn default_retry_failed_events() -> bool {
     true
@@ -275,6 +347,15 @@ impl onramp::Impl for Kafka {
     fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
+            if let Some(StartFrom::Offsets(offsets)) = &config.start_from {
+                if let Some(topic) = offsets.keys().find(|t| !config.topics.contains(*t)) {
+                    return Err(format!(
+                        "[Source::{}] Offsets given for topic {} which is not in `topics`.",
+                        id, topic
+                    )
+                    .into());
+                }
+            }
             Ok(Box::new(Self {
                 config,
                 onramp_id: id.clone(),
@@ -540,6 +621,26 @@ impl Source for Int {
         // Set up the the consumer
         let consumer: LoggingConsumer = client_config.create_with_context(context)?;
 
+        if let Some(start_from) = self.config.start_from.clone() {
+            // fetching metadata and offsets blocks on the brokers
+            let topics = self.config.topics.clone();
+            let (consumer, assignment) = task::spawn_blocking(move || {
+                let assignment = start_from.assignment(&consumer, &topics);
+                (consumer, assignment)
+            })
+            .await;
+            let assignment = assignment?;
+            consumer.assign(&assignment)?;
+            info!(
+                "[Source::{}] Replaying from: {:?}",
+                self.onramp_id, assignment
+            );
+            let stream =
+                rentals::MessageStream::new(Box::new(consumer), |c| StreamAndMsgs::new(c.stream()));
+            self.stream = Some(stream);
+            return Ok(SourceState::Connected);
+        }
+
         // Handle topics
         let topics: Vec<&str> = self
             .config
@@ -676,3 +777,73 @@ impl Onramp for Kafka {
         "json"
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(start_from: &str) -> Result<YamlValue> {
+        Ok(serde_yaml::from_str(&format!(
+            "group_id: test\ntopics: [snot, badger]\nbrokers: [\"localhost:9092\"]\n{}",
+            start_from
+        ))?)
+    }
+
+    #[test]
+    fn start_from_config() -> Result<()> {
+        let config = Config::new(&config("start_from:\n  timestamp: 1634567890000")?)?;
+        assert!(matches!(
+            config.start_from,
+            Some(StartFrom::Timestamp(1_634_567_890_000))
+        ));
+        let config = Config::new(&config(
+            "start_from:\n  offsets:\n    snot:\n      0: 42\n      1: 1337",
+        )?)?;
+        match config.start_from {
+            Some(StartFrom::Offsets(offsets)) => {
+                let snot = offsets.get("snot").ok_or("missing topic")?;
+                assert_eq!(Some(&42), snot.get(&0));
+                assert_eq!(Some(&1337), snot.get(&1));
+            }
+            other => return Err(format!("unexpected start_from: {:?}", other).into()),
+        }
+        assert!(Config::new(&config("")?)?.start_from.is_none());
+        assert!(Config::new(&config("start_from:\n  latest: true")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn start_from_unknown_topic() -> Result<()> {
+        let id = TremorUrl::from_onramp_id("kafka")?;
+        let config = config("start_from:\n  offsets:\n    snot:\n      0: 42")?;
+        assert!(Kafka::from_config(&id, &Some(config)).is_ok());
+        let config = config("start_from:\n  offsets:\n    unknown:\n      0: 42")?;
+        assert!(Kafka::from_config(&id, &Some(config)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn offsets_assignment() -> Result<()> {
+        let config = Config::new(&config(
+            "start_from:\n  offsets:\n    snot:\n      0: 42\n      1: 1337",
+        )?)?;
+        let consumer: LoggingConsumer = ClientConfig::new()
+            .set("group.id", "test")
+            .set("bootstrap.servers", "localhost:9092")
+            .create_with_context(LoggingConsumerContext {
+                onramp_id: TremorUrl::from_onramp_id("kafka")?,
+            })?;
+        let start_from = config.start_from.ok_or("missing start_from")?;
+        let tpl = start_from.assignment(&consumer, &config.topics)?;
+        assert_eq!(2, tpl.count());
+        assert_eq!(
+            Some(Offset::Offset(42)),
+            tpl.find_partition("snot", 0).map(|p| p.offset())
+        );
+        assert_eq!(
+            Some(Offset::Offset(1337)),
+            tpl.find_partition("snot", 1).map(|p| p.offset())
+        );
+        Ok(())
+    }
+}
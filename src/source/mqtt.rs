@@ -0,0 +1,247 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # MQTT onramp
+//!
+//! Subscribes to the topic filters in `topics` and emits one event per
+//! received message. Topic, QoS and retain flag are available as
+//! `$mqtt.topic`, `$mqtt.qos` and `$mqtt.retain`.
+//!
+//! Messages received with QoS 1 or 2 are only acknowledged to the broker
+//! once the event got acked inside tremor. Failed events are acknowledged as
+//! well, with a warning: the broker only sends as many unacknowledged messages
+//! as its in-flight window allows, so holding them back would stall the onramp.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::mqtt::{self, Client, ConnectConfig, Notification, Publish};
+use crate::source::prelude::*;
+use async_channel::TryRecvError;
+use halfbrown::HashMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub connect: ConnectConfig,
+    /// topic filters to subscribe to, wildcards are allowed
+    pub topics: Vec<String>,
+    /// QoS of the subscriptions, defaults to 1
+    #[serde(default = "dflt_qos")]
+    pub qos: u8,
+}
+
+fn dflt_qos() -> u8 {
+    1
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Mqtt {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Mqtt {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.topics.is_empty() {
+                return Err("mqtt onramp requires at least one topic".into());
+            }
+            // fail early on invalid QoS
+            mqtt::qos(config.qos)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for mqtt onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for Mqtt {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config);
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    onramp_id: TremorUrl,
+    config: Config,
+    client: Option<Client>,
+    messages: Option<Receiver<Publish>>,
+    /// received messages waiting for their event to be acked
+    pending: HashMap<u64, Publish>,
+    origin_uri: EventOriginUri,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MQTT")
+    }
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Self {
+        let config = config.clone();
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-mqtt".to_string(),
+            host: config.connect.host.clone(),
+            port: Some(config.connect.port),
+            path: vec![],
+        };
+        Self {
+            uid,
+            onramp_id,
+            config,
+            client: None,
+            messages: None,
+            pending: HashMap::new(),
+            origin_uri,
+        }
+    }
+
+    fn with_ack(&self) -> bool {
+        self.config.qos > 0
+    }
+}
+
+#[async_trait::async_trait]
+impl Source for Int {
+    fn is_transactional(&self) -> bool {
+        self.with_ack()
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        let publish = match self.messages.as_ref().map(Receiver::try_recv) {
+            Some(Ok(publish)) => publish,
+            Some(Err(TryRecvError::Empty)) => return Ok(SourceReply::Empty(10)),
+            Some(Err(TryRecvError::Closed)) | None => {
+                return Ok(SourceReply::StateChange(SourceState::Disconnected))
+            }
+        };
+        let topic = publish.topic();
+        let mut origin_uri = self.origin_uri.clone();
+        origin_uri.path = topic.split('/').map(String::from).collect();
+        let meta = literal!({
+            "mqtt": {
+                "topic": topic,
+                "qos": publish.qos(),
+                "retain": publish.retain()
+            }
+        });
+        let data = publish.payload();
+        if self.with_ack() && publish.qos() > 0 {
+            self.pending.insert(id, publish);
+        }
+        Ok(SourceReply::Data {
+            origin_uri,
+            data,
+            meta: Some(meta),
+            codec_override: None,
+            stream: 0,
+        })
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let client_id = format!("tremor-{}-{}", hostname(), self.uid);
+        let (client, mut eventloop) = self.config.connect.connect(&client_id, self.with_ack())?;
+        let qos = mqtt::qos(self.config.qos)?;
+
+        let (tx, rx) = bounded(crate::QSIZE);
+        let onramp_id = self.onramp_id.clone();
+        let topics = self.config.topics.clone();
+        let subscriber = client.clone();
+        task::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Notification::Connected) => {
+                        // subscriptions don't survive a clean session, so we (re-)subscribe
+                        // on every connect. This must not wait for the request channel, it is
+                        // only drained by the `poll` of this very task.
+                        match subscriber.try_subscribe_many(&topics, qos) {
+                            Ok(()) => info!("[Source::{}] Subscribed to {:?}", onramp_id, topics),
+                            Err(e) => error!("[Source::{}] Failed to subscribe: {}", onramp_id, e),
+                        }
+                    }
+                    Ok(Notification::Publish(publish)) => {
+                        if tx.send(publish).await.is_err() {
+                            // the source got terminated
+                            break;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(_) if tx.is_closed() => break,
+                    Err(e) => {
+                        // the next poll reconnects
+                        warn!("[Source::{}] {}", onramp_id, e);
+                        task::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
+        self.client = Some(client);
+        self.messages = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    fn ack(&mut self, id: u64) {
+        if let Some(publish) = self.pending.remove(&id) {
+            if let Some(client) = &self.client {
+                if let Err(e) = client.try_ack(&publish) {
+                    error!("[Source::{}] failed to ack message: {}", self.onramp_id, e);
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, id: u64) {
+        if let Some(publish) = self.pending.remove(&id) {
+            warn!(
+                "[Source::{}] Acknowledging failed message {}, it will not be redelivered",
+                self.onramp_id, id
+            );
+            if let Some(client) = &self.client {
+                if let Err(e) = client.try_ack(&publish) {
+                    error!("[Source::{}] failed to ack message: {}", self.onramp_id, e);
+                }
+            }
+        }
+    }
+
+    async fn terminate(&mut self) {
+        if let Some(messages) = self.messages.take() {
+            messages.close();
+        }
+        if let Some(client) = self.client.take() {
+            client.disconnect().await;
+        }
+    }
+}
@@ -13,9 +13,16 @@
 // limitations under the License.
 #![cfg(not(tarpaulin_include))]
 
+use crate::ramp::nats::{inbox, messages, Consumer, Delivery};
 use crate::sink::nats::ConnectOptions;
 use crate::source::prelude::*;
-use async_nats::{Connection as NatsConnection, Subscription};
+use async_channel::TryRecvError;
+use async_nats::{Connection as NatsConnection, Message};
+use halfbrown::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a pull request waits for messages before it expires
+const FETCH_EXPIRES: Duration = Duration::from_secs(1);
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
@@ -29,6 +36,9 @@ pub struct Config {
     // options to use when opening a new connection
     #[serde(default = "Default::default")]
     pub options: ConnectOptions,
+    // consume from a JetStream stream, messages are acked once their events are
+    #[serde(default = "Default::default")]
+    pub jetstream: Option<Consumer>,
 }
 
 impl ConfigImpl for Config {}
@@ -76,9 +86,16 @@ pub struct Int {
     uid: u64,
     onramp_id: TremorUrl,
     config: Config,
-    subscription: Option<Subscription>,
+    /// messages of the subscription, see `ramp::nats::messages`
+    messages: Option<Receiver<Message>>,
     connection: Option<NatsConnection>,
     origin_uri: EventOriginUri,
+    /// reply subjects of JetStream messages waiting for their ack or fail
+    pending: HashMap<u64, String>,
+    /// inbox pull consumers deliver to
+    inbox: String,
+    /// expiry and number of outstanding messages of the current pull request
+    fetch: Option<(Instant, usize)>,
 }
 
 impl std::fmt::Debug for Int {
@@ -101,61 +118,162 @@ impl Int {
             uid,
             onramp_id,
             config,
-            subscription: None,
+            messages: None,
             connection: None,
             origin_uri,
+            pending: HashMap::new(),
+            inbox: inbox(),
+            fetch: None,
+        }
+    }
+
+    /// Receives the next message of a pull consumer, requesting a new batch
+    /// once the previous request is done
+    async fn pull_message(&mut self) -> Result<Option<Message>> {
+        let (consumer, messages, nc) =
+            match (&self.config.jetstream, &self.messages, &self.connection) {
+                (Some(consumer), Some(messages), Some(nc)) => (consumer, messages, nc),
+                _ => return Ok(None),
+            };
+        let now = Instant::now();
+        let (expires, outstanding) = match self.fetch {
+            Some((expires, outstanding)) if expires > now && outstanding > 0 => {
+                (expires, outstanding)
+            }
+            _ => {
+                consumer.fetch(nc, &self.inbox, FETCH_EXPIRES).await?;
+                (now + FETCH_EXPIRES, consumer.batch)
+            }
+        };
+        self.fetch = Some((expires, outstanding));
+        match messages.try_recv() {
+            Ok(msg) if msg.reply.as_deref().and_then(Delivery::parse).is_some() => {
+                self.fetch = Some((expires, outstanding - 1));
+                Ok(Some(msg))
+            }
+            Ok(_status) => {
+                // no messages or the request expired
+                self.fetch = None;
+                Ok(None)
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Closed) => {
+                self.messages = None;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Responds to a JetStream message to ack or nak it
+    fn respond(&mut self, id: u64, response: String) {
+        if let Some((reply, nc)) = self.pending.remove(&id).zip(self.connection.clone()) {
+            let onramp_id = self.onramp_id.clone();
+            task::spawn(async move {
+                if let Err(e) = nc.publish(&reply, response).await {
+                    error!(
+                        "[Source::{}] Failed to respond to message: {}",
+                        onramp_id, e
+                    );
+                }
+            });
         }
     }
 }
 
 #[async_trait::async_trait]
 impl Source for Int {
-    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
-        if let Some(sub) = &self.subscription {
-            if let Some(msg) = sub.next().await {
-                let mut origin_uri = self.origin_uri.clone();
-                origin_uri.path = vec![msg.subject];
-                let data = msg.data;
-                let mut nats_meta_data = Value::object_with_capacity(1);
-                let msg_headers = msg.headers.map(|headers| {
-                    let mut key_val: Value = Value::object_with_capacity(headers.len());
-                    for (key, val) in headers.iter() {
-                        let key = String::from(key);
-                        let val: Vec<String> = val.iter().map(String::from).collect();
-                        key_val.insert(key, val).ok();
-                    }
-                    key_val
-                });
-                let mut meta_data = Value::object_with_capacity(2);
-                if let Some(msg_reply) = msg.reply {
-                    meta_data.insert("reply", msg_reply)?;
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        if self.messages.is_none() {
+            return Ok(SourceReply::StateChange(SourceState::Disconnected));
+        }
+        let msg = if self.config.jetstream.as_ref().map_or(false, |c| c.pull) {
+            self.pull_message().await?
+        } else if let Some(messages) = &self.messages {
+            match messages.try_recv() {
+                Ok(msg) => Some(msg),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Closed) => {
+                    self.messages = None;
+                    None
                 }
-                if let Some(msg_headers) = msg_headers {
-                    meta_data.insert("headers", msg_headers)?;
+            }
+        } else {
+            None
+        };
+        if let Some(msg) = msg {
+            let mut origin_uri = self.origin_uri.clone();
+            origin_uri.path = vec![msg.subject];
+            let data = msg.data;
+            let mut nats_meta_data = Value::object_with_capacity(1);
+            let msg_headers = msg.headers.map(|headers| {
+                let mut key_val: Value = Value::object_with_capacity(headers.len());
+                for (key, val) in headers.iter() {
+                    let key = String::from(key);
+                    let val: Vec<String> = val.iter().map(String::from).collect();
+                    key_val.insert(key, val).ok();
                 }
-                nats_meta_data.insert("nats", meta_data)?;
-                Ok(SourceReply::Data {
-                    origin_uri,
-                    data,
-                    meta: Some(nats_meta_data),
-                    codec_override: None,
-                    stream: 0,
-                })
-            } else {
-                Ok(SourceReply::StateChange(SourceState::Disconnected))
+                key_val
+            });
+            let mut meta_data = Value::object_with_capacity(3);
+            if let Some(msg_reply) = msg.reply {
+                if self.config.jetstream.is_some() {
+                    if let Some(delivery) = Delivery::parse(&msg_reply) {
+                        meta_data.insert("jetstream", delivery.meta())?;
+                    }
+                    self.pending.insert(id, msg_reply.clone());
+                }
+                meta_data.insert("reply", msg_reply)?;
             }
+            if let Some(msg_headers) = msg_headers {
+                meta_data.insert("headers", msg_headers)?;
+            }
+            nats_meta_data.insert("nats", meta_data)?;
+            Ok(SourceReply::Data {
+                origin_uri,
+                data,
+                meta: Some(nats_meta_data),
+                codec_override: None,
+                stream: 0,
+            })
+        } else if self.messages.is_some() {
+            Ok(SourceReply::Empty(10))
         } else {
             Ok(SourceReply::StateChange(SourceState::Disconnected))
         }
     }
 
+    fn is_transactional(&self) -> bool {
+        self.config.jetstream.is_some()
+    }
+
+    fn ack(&mut self, id: u64) {
+        self.respond(id, "+ACK".to_string());
+    }
+
+    fn fail(&mut self, id: u64) {
+        if let Some(nak) = self.config.jetstream.as_ref().map(Consumer::nak) {
+            self.respond(id, nak);
+        }
+    }
+
     fn id(&self) -> &TremorUrl {
         &self.onramp_id
     }
 
     async fn init(&mut self) -> Result<SourceState> {
         let nc = self.config.connection().await?;
-        let sub = if let Some(queue) = &self.config.queue {
+        let sub = if let Some(consumer) = &self.config.jetstream {
+            let queue = self.config.queue.as_deref();
+            consumer.create(&nc, &self.config.subject, queue).await?;
+            if consumer.pull {
+                nc.subscribe(&self.inbox).await?
+            } else if let Some(queue) = queue {
+                nc.queue_subscribe(&consumer.deliver_subject(), queue)
+                    .await?
+            } else {
+                nc.subscribe(&consumer.deliver_subject()).await?
+            }
+        } else if let Some(queue) = &self.config.queue {
             nc.queue_subscribe(self.config.subject.as_str(), queue.as_str())
                 .await?
         } else {
@@ -186,7 +304,7 @@ impl Source for Int {
             path: vec![],
         };
         self.connection = Some(nc);
-        self.subscription = Some(sub);
+        self.messages = Some(messages(sub));
         Ok(SourceState::Connected)
     }
 
@@ -0,0 +1,498 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+#![cfg(not(tarpaulin_include))]
+
+//! # Postgres change data capture Onramp
+//!
+//! Reads changes from a logical replication slot using the `wal2json`
+//! output plugin and emits one event per inserted, updated, deleted or
+//! truncated row:
+//!
+//! ```json
+//! {"op": "update", "schema": "public", "table": "users", "before": {"id": 1}, "after": {"id": 1, "name": "snot"}}
+//! ```
+//!
+//! `before` contains the replica identity of the row, this is the primary key
+//! unless the table uses `REPLICA IDENTITY FULL`. The LSN of the change, the
+//! LSN of its transaction's commit, the transaction id and commit timestamp are
+//! available as `$postgres.lsn`, `$postgres.commit_lsn`, `$postgres.xid` and
+//! `$postgres.timestamp`.
+//!
+//! The slot is only advanced once all events of a transaction and all
+//! transactions before it got acked, so unacked changes are read again after a
+//! restart. Failed events are read again from the slot right away.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use async_compat::Compat;
+use halfbrown::HashMap;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use tokio_postgres::{Client, NoTls};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u32,
+    pub user: String,
+    pub password: String,
+    pub dbname: String,
+    /// name of the logical replication slot to read from
+    pub slot: String,
+    /// create the slot with the `wal2json` plugin if it doesn't exist, defaults to true
+    #[serde(default = "dflt_true")]
+    pub create_slot: bool,
+    /// tables to capture as `schema.table`, `*` can be used as wildcard.
+    /// Defaults to all tables.
+    #[serde(default = "Default::default")]
+    pub tables: Vec<String>,
+    /// maximum number of changes to read from the slot at once, defaults to 1000
+    #[serde(default = "dflt_batch_size")]
+    pub batch_size: u32,
+    /// time to wait when there are no new changes, defaults to 1000ms
+    #[serde(default = "dflt_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn dflt_true() -> bool {
+    true
+}
+
+fn dflt_batch_size() -> u32 {
+    1000
+}
+
+fn dflt_interval_ms() -> u64 {
+    1000
+}
+
+impl ConfigImpl for Config {}
+
+pub struct PostgresCdc {
+    onramp_id: TremorUrl,
+    pub config: Config,
+}
+
+impl onramp::Impl for PostgresCdc {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            i32::try_from(config.batch_size)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for postgres-cdc onramp".into())
+        }
+    }
+}
+
+/// A decoded change, waiting to be emitted
+struct Change {
+    /// position after the commit of the changes transaction
+    commit_lsn: u64,
+    data: Value<'static>,
+    meta: Value<'static>,
+}
+
+pub struct Int {
+    pub config: Config,
+    onramp_id: TremorUrl,
+    origin_uri: EventOriginUri,
+    cli: Option<Client>,
+    changes: VecDeque<Change>,
+    /// position after the last transaction read from the slot
+    read_lsn: u64,
+    /// position up to which all events are acked
+    acked_lsn: u64,
+    /// acked position that still needs to be confirmed to postgres
+    confirm: Option<u64>,
+    /// transactions that are not yet completely acked and their number of
+    /// outstanding events
+    txns: BTreeMap<u64, usize>,
+    /// transaction of every event in flight
+    events: HashMap<u64, u64>,
+}
+
+impl fmt::Debug for Int {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PostgresCdc")
+    }
+}
+
+/// Parses a textual LSN like `16/B374D848`
+fn parse_lsn(lsn: &str) -> Result<u64> {
+    let (hi, lo) = lsn
+        .split_once('/')
+        .ok_or_else(|| Error::from(format!("Invalid LSN: {}", lsn)))?;
+    Ok(u64::from_str_radix(hi, 16)? << 32 | u64::from_str_radix(lo, 16)?)
+}
+
+fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// Turns wal2json `columns` or `identity` into a record
+fn to_record(columns: Option<&Value>) -> Value<'static> {
+    columns
+        .and_then(Value::as_array)
+        .map_or_else(Value::null, |columns| {
+            let mut record = Value::object_with_capacity(columns.len());
+            for c in columns {
+                if let Some(name) = c.get_str("name") {
+                    let value = c.get("value").map_or_else(Value::null, Value::clone_static);
+                    // can't fail as record is an object
+                    let _ = record.insert(name.to_string(), value);
+                }
+            }
+            record
+        })
+}
+
+/// Turns a wal2json (format version 2) row change into an event, other
+/// messages are ignored
+fn change_to_event(change: &Value) -> Option<Value<'static>> {
+    let op = match change.get_str("action")? {
+        "I" => "insert",
+        "U" => "update",
+        "D" => "delete",
+        "T" => "truncate",
+        _ => return None,
+    };
+    let (before, after) = match op {
+        "insert" => (Value::null(), to_record(change.get("columns"))),
+        "update" => (
+            to_record(change.get("identity")),
+            to_record(change.get("columns")),
+        ),
+        "delete" => (to_record(change.get("identity")), Value::null()),
+        _ => (Value::null(), Value::null()),
+    };
+    let schema = change.get_str("schema").unwrap_or_default().to_string();
+    let table = change.get_str("table").unwrap_or_default().to_string();
+    Some(literal!({
+        "op": op,
+        "schema": schema,
+        "table": table,
+        "before": before,
+        "after": after
+    }))
+}
+
+impl Int {
+    fn from_config(uid: u64, onramp_id: TremorUrl, config: &Config) -> Self {
+        let origin_uri = EventOriginUri {
+            uid,
+            scheme: "tremor-postgres".to_string(),
+            host: config.host.clone(),
+            port: u16::try_from(config.port).ok(),
+            path: vec![config.dbname.clone(), config.slot.clone()],
+        };
+        Self {
+            config: config.clone(),
+            onramp_id,
+            origin_uri,
+            cli: None,
+            changes: VecDeque::new(),
+            read_lsn: 0,
+            acked_lsn: 0,
+            confirm: None,
+            txns: BTreeMap::new(),
+            events: HashMap::new(),
+        }
+    }
+
+    async fn init_cli(&mut self) -> Result<()> {
+        let conn_str = format!(
+            "host={} user={} password={} port={} dbname={}",
+            self.config.host,
+            self.config.user,
+            self.config.password,
+            self.config.port,
+            self.config.dbname
+        );
+        let (client, connection) = Compat::new(tokio_postgres::connect(&conn_str, NoTls)).await?;
+        task::spawn(async move {
+            if let Err(e) = Compat::new(connection).await {
+                error!("connection error: {}", e);
+            }
+        });
+        self.cli = Some(client);
+        Ok(())
+    }
+
+    async fn client(&mut self) -> Result<&Client> {
+        if self.cli.as_ref().map_or(true, Client::is_closed) {
+            self.init_cli().await?;
+        }
+        self.cli
+            .as_ref()
+            .ok_or_else(|| Error::from("No CLI connection"))
+    }
+
+    /// Reads the next batch of changes from the slot without consuming them,
+    /// transactions that were read before are skipped
+    async fn read_changes(&mut self) -> Result<()> {
+        let slot = self.config.slot.clone();
+        let batch_size = i32::try_from(self.config.batch_size)?;
+        let tables = self.config.tables.join(",");
+        let client = self.client().await?;
+        let rows = if tables.is_empty() {
+            let q = "SELECT lsn::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2, \
+                     'format-version', '2', 'include-lsn', '1', 'include-xids', '1', \
+                     'include-timestamp', '1')";
+            Compat::new(client.query(q, &[&slot, &batch_size])).await?
+        } else {
+            let q = "SELECT lsn::text, data FROM pg_logical_slot_peek_changes($1, NULL, $2, \
+                     'format-version', '2', 'include-lsn', '1', 'include-xids', '1', \
+                     'include-timestamp', '1', 'add-tables', $3)";
+            Compat::new(client.query(q, &[&slot, &batch_size, &tables])).await?
+        };
+
+        let mut txn: Vec<(Value<'static>, Value<'static>)> = Vec::new();
+        let mut xid = Value::null();
+        let mut timestamp = Value::null();
+        for row in rows {
+            let lsn: String = row.try_get(0)?;
+            let mut data = row.try_get::<_, String>(1)?.into_bytes();
+            let message = tremor_value::parse_to_value(&mut data)?;
+            match message.get_str("action") {
+                Some("B") => {
+                    txn.clear();
+                    xid = message
+                        .get("xid")
+                        .map_or_else(Value::null, Value::clone_static);
+                    timestamp = message
+                        .get("timestamp")
+                        .map_or_else(Value::null, Value::clone_static);
+                }
+                Some("C") => {
+                    // the commit record starts at `lsn`, decoding skips transactions
+                    // committed before the confirmed position, so we confirm right after it
+                    let commit_lsn = parse_lsn(&lsn)? + 1;
+                    if commit_lsn <= self.read_lsn {
+                        txn.clear();
+                        continue;
+                    }
+                    self.read_lsn = commit_lsn;
+                    self.txns.insert(commit_lsn, txn.len());
+                    for (data, mut meta) in txn.drain(..) {
+                        if let Some(pg) = meta.get_mut("postgres") {
+                            pg.try_insert("commit_lsn", format_lsn(commit_lsn - 1));
+                            pg.try_insert("xid", xid.clone());
+                            pg.try_insert("timestamp", timestamp.clone());
+                        }
+                        self.changes.push_back(Change {
+                            commit_lsn,
+                            data,
+                            meta,
+                        });
+                    }
+                }
+                _ => {
+                    if let Some(data) = change_to_event(&message) {
+                        let change_lsn = message.get_str("lsn").unwrap_or(&lsn).to_string();
+                        let meta = literal!({
+                            "postgres": {
+                                "lsn": change_lsn
+                            }
+                        });
+                        txn.push((data, meta));
+                    }
+                }
+            }
+        }
+        // transactions without any captured changes are done right away
+        self.advance();
+        Ok(())
+    }
+
+    /// Moves the acked position past all completely acked transactions
+    fn advance(&mut self) {
+        while let Some((&commit_lsn, &0)) = self.txns.iter().next() {
+            self.txns.remove(&commit_lsn);
+            self.acked_lsn = commit_lsn;
+            self.confirm = Some(commit_lsn);
+        }
+    }
+
+    /// Confirms the acked position to postgres, this allows it to free the WAL
+    async fn confirm(&mut self) -> Result<()> {
+        if let Some(lsn) = self.confirm.take() {
+            let slot = self.config.slot.clone();
+            let client = self.client().await?;
+            let q = "SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)";
+            let res = Compat::new(client.query(q, &[&slot, &format_lsn(lsn)])).await;
+            if let Err(e) = res {
+                self.confirm = Some(lsn);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Source for Int {
+    async fn pull_event(&mut self, id: u64) -> Result<SourceReply> {
+        if let Some(Change {
+            commit_lsn,
+            data,
+            meta,
+        }) = self.changes.pop_front()
+        {
+            self.events.insert(id, commit_lsn);
+            return Ok(SourceReply::Structured {
+                origin_uri: self.origin_uri.clone(),
+                data: (data, meta).into(),
+            });
+        }
+
+        if let Err(e) = self.confirm().await {
+            error!("[Source::{}] Failed to advance slot: {}", self.onramp_id, e);
+        }
+        if let Err(e) = self.read_changes().await {
+            error!("[Source::{}] Failed to read changes: {}", self.onramp_id, e);
+            return Ok(SourceReply::Empty(self.config.interval_ms));
+        }
+        if self.changes.is_empty() {
+            Ok(SourceReply::Empty(self.config.interval_ms))
+        } else {
+            Ok(SourceReply::Empty(0))
+        }
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let slot = self.config.slot.clone();
+        let create_slot = self.config.create_slot;
+        let client = self.client().await?;
+        let q = "SELECT slot_name FROM pg_replication_slots WHERE slot_name = $1";
+        let exists = !Compat::new(client.query(q, &[&slot])).await?.is_empty();
+        if !exists {
+            if create_slot {
+                let q = "SELECT pg_create_logical_replication_slot($1, 'wal2json')";
+                Compat::new(client.query(q, &[&slot])).await?;
+                info!("[Source::{}] Created slot {}", self.onramp_id, slot);
+            } else {
+                return Err(format!("Replication slot {} does not exist", slot).into());
+            }
+        }
+        Ok(SourceState::Connected)
+    }
+
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    fn is_transactional(&self) -> bool {
+        true
+    }
+
+    fn ack(&mut self, id: u64) {
+        if let Some(commit_lsn) = self.events.remove(&id) {
+            if let Some(outstanding) = self.txns.get_mut(&commit_lsn) {
+                *outstanding = outstanding.saturating_sub(1);
+            }
+            self.advance();
+        }
+    }
+
+    fn fail(&mut self, id: u64) {
+        if self.events.remove(&id).is_some() {
+            warn!(
+                "[Source::{}] Event {} failed, reading unacked changes again",
+                self.onramp_id, id
+            );
+            // events still in flight are read again as well, their acks are ignored
+            self.changes.clear();
+            self.txns.clear();
+            self.events.clear();
+            self.read_lsn = self.acked_lsn;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Onramp for PostgresCdc {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int::from_config(config.onramp_uid, self.onramp_id.clone(), &self.config);
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lsn() -> Result<()> {
+        assert_eq!(0x16_B374_D848, parse_lsn("16/B374D848")?);
+        assert_eq!("16/B374D848", format_lsn(0x16_B374_D848));
+        assert_eq!("0/0", format_lsn(0));
+        assert!(parse_lsn("snot").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn changes() {
+        let update = literal!({
+            "action": "U",
+            "schema": "public",
+            "table": "users",
+            "columns": [
+                {"name": "id", "type": "integer", "value": 1},
+                {"name": "name", "type": "text", "value": "badger"}
+            ],
+            "identity": [
+                {"name": "id", "type": "integer", "value": 1}
+            ]
+        });
+        assert_eq!(
+            Some(literal!({
+                "op": "update",
+                "schema": "public",
+                "table": "users",
+                "before": {"id": 1},
+                "after": {"id": 1, "name": "badger"}
+            })),
+            change_to_event(&update)
+        );
+        let delete = literal!({
+            "action": "D",
+            "schema": "public",
+            "table": "users",
+            "identity": [
+                {"name": "id", "type": "integer", "value": 1}
+            ]
+        });
+        assert_eq!(
+            Some(literal!({
+                "op": "delete",
+                "schema": "public",
+                "table": "users",
+                "before": {"id": 1},
+                "after": null
+            })),
+            change_to_event(&delete)
+        );
+        assert_eq!(None, change_to_event(&literal!({"action": "M"})));
+    }
+}
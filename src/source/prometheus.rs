@@ -0,0 +1,224 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Prometheus scrape onramp
+//!
+//! Scrapes the metrics endpoints given in `targets` every `interval_ms`
+//! milliseconds. Each scrape is decoded with the `prometheus` codec by default,
+//! the scraped url is available as `$prometheus.target`.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use async_channel::TryRecvError;
+use async_std::future::timeout;
+use halfbrown::HashMap;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const ACCEPT: &str = "text/plain;version=0.0.4";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// urls of the metrics endpoints to scrape
+    pub targets: Vec<String>,
+    /// scrape interval in milliseconds
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// timeout of a single scrape in milliseconds
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// additional headers sent with every scrape
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_interval_ms() -> u64 {
+    15_000
+}
+
+fn default_timeout_ms() -> u64 {
+    10_000
+}
+
+impl ConfigImpl for Config {}
+
+pub struct Prometheus {
+    pub config: Config,
+    targets: Vec<Url>,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Prometheus {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.targets.is_empty() {
+                return Err("prometheus onramp requires at least one target".into());
+            }
+            let targets = config
+                .targets
+                .iter()
+                .map(|t| Url::parse(t))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(Box::new(Self {
+                config,
+                targets,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for prometheus onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for Prometheus {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            targets: self.targets.clone(),
+            onramp_id: self.onramp_id.clone(),
+            scrapes: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "prometheus"
+    }
+}
+
+struct Scrape {
+    target: Url,
+    data: Vec<u8>,
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    targets: Vec<Url>,
+    onramp_id: TremorUrl,
+    scrapes: Option<Receiver<Scrape>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Prometheus")
+    }
+}
+
+async fn fetch(
+    client: &surf::Client,
+    target: &Url,
+    headers: &HashMap<String, String>,
+) -> Result<Vec<u8>> {
+    let mut req = client.get(target.as_str()).header("Accept", ACCEPT);
+    for (name, value) in headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    let mut res = req.await?;
+    if !res.status().is_success() {
+        return Err(format!("scraping {} failed with status {}", target, res.status()).into());
+    }
+    Ok(res.body_bytes().await?)
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+        let interval = Duration::from_millis(self.config.interval_ms);
+        let scrape_timeout = Duration::from_millis(self.config.timeout_ms);
+        for target in &self.targets {
+            info!("[Source::{}] scraping {}", self.onramp_id, target);
+            let target = target.clone();
+            let headers = self.config.headers.clone();
+            let tx = tx.clone();
+            task::spawn(async move {
+                let client = surf::client();
+                loop {
+                    let start = Instant::now();
+                    match timeout(scrape_timeout, fetch(&client, &target, &headers)).await {
+                        Ok(Ok(data)) => {
+                            let scrape = Scrape {
+                                target: target.clone(),
+                                data,
+                            };
+                            if tx.send(scrape).await.is_err() {
+                                // the source got terminated
+                                break;
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            warn!("[Source::Prometheus] Error scraping {}: {}", target, e)
+                        }
+                        Err(_) => warn!("[Source::Prometheus] Scraping {} timed out", target),
+                    }
+                    task::sleep(interval.saturating_sub(start.elapsed())).await;
+                }
+            });
+        }
+        self.scrapes = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.scrapes.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |scrapes| match scrapes.try_recv() {
+                Ok(Scrape { target, data }) => {
+                    let origin_uri = EventOriginUri {
+                        uid: self.uid,
+                        scheme: "tremor-prometheus".to_string(),
+                        host: target.host_str().unwrap_or_default().to_string(),
+                        port: target.port_or_known_default(),
+                        path: target
+                            .path_segments()
+                            .map(|s| s.map(String::from).collect())
+                            .unwrap_or_default(),
+                    };
+                    Ok(SourceReply::Data {
+                        origin_uri,
+                        data,
+                        meta: Some(literal!({
+                            "prometheus": {
+                                "target": target.to_string()
+                            }
+                        })),
+                        codec_override: None,
+                        stream: 0,
+                    })
+                }
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+
+    async fn terminate(&mut self) {
+        if let Some(scrapes) = self.scrapes.take() {
+            scrapes.close();
+        }
+    }
+}
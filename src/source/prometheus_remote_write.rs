@@ -0,0 +1,196 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Prometheus remote write onramp
+//!
+//! Accepts prometheus remote write requests and emits one influx style
+//! metric event per sample.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::ramp::prometheus::WriteRequest;
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use tide::{Request, Response};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// port to listen to
+    pub port: u16,
+    /// path remote write requests are accepted on, defaults to "/api/v1/write"
+    #[serde(default = "dflt_path")]
+    pub path: String,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn dflt_path() -> String {
+    String::from("/api/v1/write")
+}
+
+pub struct PrometheusRemoteWrite {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for PrometheusRemoteWrite {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for prometheus-remote-write onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for PrometheusRemoteWrite {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PrometheusRemoteWrite")
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    tx: Sender<SourceReply>,
+    uid: u64,
+    port: u16,
+}
+
+async fn handle_write(mut req: Request<ServerState>) -> tide::Result<Response> {
+    let body = req.body_bytes().await?;
+    let request = match WriteRequest::from_body(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Ok(Response::builder(400).body(e.to_string()).build());
+        }
+    };
+    let origin_uri = EventOriginUri {
+        uid: req.state().uid,
+        scheme: "tremor-prometheus-remote-write".to_string(),
+        host: req
+            .remote()
+            .unwrap_or("tremor-prometheus-remote-write-client.remote")
+            .to_string(),
+        port: Some(req.state().port),
+        path: req
+            .url()
+            .path_segments()
+            .map(|s| s.map(String::from).collect())
+            .unwrap_or_default(),
+    };
+    let mut values = Vec::new();
+    for ts in &request.timeseries {
+        match ts.to_values() {
+            Ok(mut ts_values) => values.append(&mut ts_values),
+            Err(e) => return Ok(Response::builder(400).body(e.to_string()).build()),
+        }
+    }
+    let tx = &req.state().tx;
+    for value in values {
+        tx.send(SourceReply::Structured {
+            origin_uri: origin_uri.clone(),
+            data: value.into(),
+        })
+        .await?;
+    }
+    Ok(Response::new(204))
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+
+        let mut server = tide::Server::with_state(ServerState {
+            tx: tx.clone(),
+            uid: self.uid,
+            port: self.config.port,
+        });
+        server.at(&self.config.path).post(handle_write);
+
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let source_id = self.onramp_id.to_string();
+
+        task::spawn::<_, Result<()>>(async move {
+            info!("[Source::{}] Listening at {}", source_id, addr);
+            if let Err(e) = server.listen(addr).await {
+                error!(
+                    "[Source::{}] Error while listening from the remote write server: {}",
+                    source_id, e
+                );
+            }
+            warn!("[Source::{}] Server stopped", source_id);
+            tx.send(SourceReply::StateChange(SourceState::Disconnected))
+                .await?;
+            Ok(())
+        });
+
+        self.listener = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(reply) => Ok(reply),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+}
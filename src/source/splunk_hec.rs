@@ -0,0 +1,416 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Splunk HTTP Event Collector onramp
+//!
+//! Accepts requests of Splunk HEC clients:
+//!
+//! * `/services/collector/event` (and `/services/collector`) take one or
+//!   more concatenated JSON events, the `event` field of each becomes the
+//!   event, `time`, `host`, `source`, `sourcetype`, `index` and `fields` are
+//!   available as `$splunk.time`, `$splunk.host` and so on.
+//! * `/services/collector/raw` takes raw data, every line is decoded with
+//!   the configured codec into an event. `host`, `source`, `sourcetype` and
+//!   `index` query parameters are available as metadata as above.
+//! * `/services/collector/health` reports the onramp as healthy.
+//!
+//! If `tokens` are configured requests need to send one of them as
+//! `Authorization: Splunk <token>` header. Gzip compressed bodies are
+//! supported.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use std::collections::HashMap;
+use std::io::Read;
+use tide::{Request, Response};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// port to listen to, defaults to 8088
+    #[serde(default = "dflt_port")]
+    pub port: u16,
+    /// accepted tokens, if empty requests are not authenticated
+    #[serde(default = "Default::default")]
+    pub tokens: Vec<String>,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+fn dflt_port() -> u16 {
+    8088
+}
+
+pub struct SplunkHec {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for SplunkHec {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for splunk-hec onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for SplunkHec {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "string"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SplunkHec")
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    tx: Sender<SourceReply>,
+    uid: u64,
+    port: u16,
+    tokens: Vec<String>,
+}
+
+/// A HEC status response
+fn hec_response(status: u16, code: u64, text: &str) -> Response {
+    Response::builder(status)
+        .content_type(tide::http::mime::JSON)
+        .body(literal!({ "text": text.to_string(), "code": code }).encode())
+        .build()
+}
+
+/// Checks the token, returns the error response if the request is not authorized
+fn authorize(req: &Request<ServerState>) -> Option<Response> {
+    let tokens = &req.state().tokens;
+    if tokens.is_empty() {
+        return None;
+    }
+    let token = req
+        .header("Authorization")
+        .map(|h| h.last().as_str())
+        .and_then(|h| h.strip_prefix("Splunk "));
+    match token {
+        None => Some(hec_response(401, 2, "Token is required")),
+        Some(token) if tokens.iter().any(|t| t == token) => None,
+        Some(_) => Some(hec_response(403, 4, "Invalid token")),
+    }
+}
+
+async fn body(req: &mut Request<ServerState>) -> tide::Result<Vec<u8>> {
+    let body = req.body_bytes().await?;
+    let gzip = req
+        .header("Content-Encoding")
+        .map_or(false, |h| h.last().as_str().eq_ignore_ascii_case("gzip"));
+    if gzip {
+        let mut decoder = libflate::gzip::Decoder::new(body.as_slice())?;
+        let mut data = Vec::new();
+        decoder.read_to_end(&mut data)?;
+        Ok(data)
+    } else {
+        Ok(body)
+    }
+}
+
+fn origin_uri(req: &Request<ServerState>) -> EventOriginUri {
+    EventOriginUri {
+        uid: req.state().uid,
+        scheme: "tremor-splunk-hec".to_string(),
+        host: req
+            .remote()
+            .unwrap_or("tremor-splunk-hec-client.remote")
+            .to_string(),
+        port: Some(req.state().port),
+        path: req
+            .url()
+            .path_segments()
+            .map(|s| s.map(String::from).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Splits concatenated JSON objects
+fn split_objects(data: &[u8]) -> std::result::Result<Vec<&[u8]>, String> {
+    let mut objects = Vec::new();
+    let mut depth = 0_usize;
+    let mut start = 0;
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, b) in data.iter().enumerate() {
+        if in_str {
+            if escaped {
+                escaped = false;
+            } else if *b == b'\\' {
+                escaped = true;
+            } else if *b == b'"' {
+                in_str = false;
+            }
+            continue;
+        }
+        match b {
+            b'{' => {
+                if depth == 0 {
+                    start = i;
+                }
+                depth += 1;
+            }
+            _ if depth == 0 && !b.is_ascii_whitespace() => {
+                return Err(format!("Unexpected character at {}", i));
+            }
+            b'"' => in_str = true,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    objects.push(&data[start..=i]);
+                }
+            }
+            _ => (),
+        }
+    }
+    if depth == 0 {
+        Ok(objects)
+    } else {
+        Err("Incomplete event".to_string())
+    }
+}
+
+/// Turns a HEC event into the event and its metadata
+fn parse_event(data: &[u8]) -> std::result::Result<(Value<'static>, Value<'static>), String> {
+    let mut data = data.to_vec();
+    let hec = tremor_value::parse_to_value(&mut data).map_err(|e| e.to_string())?;
+    let event = match hec.get("event") {
+        None => return Err("Event field is required".to_string()),
+        Some(e) if e.as_str() == Some("") => return Err("Event field cannot be blank".to_string()),
+        Some(e) => e.clone_static(),
+    };
+    let get = |k: &str| hec.get(k).map_or_else(Value::null, Value::clone_static);
+    let meta = literal!({
+        "splunk": {
+            "time": get("time"),
+            "host": get("host"),
+            "source": get("source"),
+            "sourcetype": get("sourcetype"),
+            "index": get("index"),
+            "fields": get("fields")
+        }
+    });
+    Ok((event, meta))
+}
+
+async fn handle_health(_req: Request<ServerState>) -> tide::Result<Response> {
+    Ok(hec_response(200, 17, "HEC is healthy"))
+}
+
+async fn handle_event(mut req: Request<ServerState>) -> tide::Result<Response> {
+    if let Some(res) = authorize(&req) {
+        return Ok(res);
+    }
+    let data = body(&mut req).await?;
+    let objects = match split_objects(&data) {
+        Ok(objects) if objects.is_empty() => return Ok(hec_response(400, 5, "No data")),
+        Ok(objects) => objects,
+        Err(_) => return Ok(hec_response(400, 6, "Invalid data format")),
+    };
+    // Splunk rejects the whole request if any event is invalid
+    let mut events = Vec::with_capacity(objects.len());
+    for (i, object) in objects.into_iter().enumerate() {
+        match parse_event(object) {
+            Ok(event) => events.push(event),
+            Err(e) => {
+                let body = literal!({
+                    "text": e,
+                    "code": 12,
+                    "invalid-event-number": i
+                });
+                return Ok(Response::builder(400)
+                    .content_type(tide::http::mime::JSON)
+                    .body(body.encode())
+                    .build());
+            }
+        }
+    }
+    let origin_uri = origin_uri(&req);
+    for event in events {
+        req.state()
+            .tx
+            .send(SourceReply::Structured {
+                origin_uri: origin_uri.clone(),
+                data: event.into(),
+            })
+            .await?;
+    }
+    Ok(hec_response(200, 0, "Success"))
+}
+
+async fn handle_raw(mut req: Request<ServerState>) -> tide::Result<Response> {
+    if let Some(res) = authorize(&req) {
+        return Ok(res);
+    }
+    let data = body(&mut req).await?;
+    let query: HashMap<String, String> = req.query().unwrap_or_default();
+    let get = |k: &str| query.get(k).cloned();
+    let meta = literal!({
+        "splunk": {
+            "host": get("host"),
+            "source": get("source"),
+            "sourcetype": get("sourcetype"),
+            "index": get("index")
+        }
+    });
+    let origin_uri = origin_uri(&req);
+    for line in data.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        req.state()
+            .tx
+            .send(SourceReply::Data {
+                origin_uri: origin_uri.clone(),
+                data: line.to_vec(),
+                meta: Some(meta.clone()),
+                codec_override: None,
+                stream: 0,
+            })
+            .await?;
+    }
+    Ok(hec_response(200, 0, "Success"))
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+
+        let mut server = tide::Server::with_state(ServerState {
+            tx: tx.clone(),
+            uid: self.uid,
+            port: self.config.port,
+            tokens: self.config.tokens.clone(),
+        });
+        server.at("/services/collector").post(handle_event);
+        server.at("/services/collector/event").post(handle_event);
+        server
+            .at("/services/collector/event/1.0")
+            .post(handle_event);
+        server.at("/services/collector/raw").post(handle_raw);
+        server.at("/services/collector/raw/1.0").post(handle_raw);
+        server.at("/services/collector/health").get(handle_health);
+        server
+            .at("/services/collector/health/1.0")
+            .get(handle_health);
+
+        let addr = format!("{}:{}", self.config.host, self.config.port);
+        let source_id = self.onramp_id.to_string();
+
+        task::spawn::<_, Result<()>>(async move {
+            info!("[Source::{}] Listening at {}", source_id, addr);
+            if let Err(e) = server.listen(addr).await {
+                error!(
+                    "[Source::{}] Error while listening from the HEC server: {}",
+                    source_id, e
+                );
+            }
+            warn!("[Source::{}] Server stopped", source_id);
+            tx.send(SourceReply::StateChange(SourceState::Disconnected))
+                .await?;
+            Ok(())
+        });
+
+        self.listener = Some(rx);
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(reply) => Ok(reply),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let data = br#"{"event": "snot {"}
+        {"event": {"a": "\"}"}, "time": 1}{"event": 1}"#;
+        let objects = split_objects(data).expect("valid objects");
+        assert_eq!(3, objects.len());
+        assert_eq!(br#"{"event": 1}"#, objects[2]);
+        assert_eq!(
+            Ok((
+                literal!({"a": "\"}"}),
+                literal!({"splunk": {
+                    "time": 1,
+                    "host": null,
+                    "source": null,
+                    "sourcetype": null,
+                    "index": null,
+                    "fields": null
+                }})
+            )),
+            parse_event(objects[1])
+        );
+        assert!(split_objects(br#"{"event": 1}}"#).is_err());
+        assert!(split_objects(br#"{"event": 1"#).is_err());
+        assert!(split_objects(br#"[1]"#).is_err());
+        assert!(parse_event(br#"{"time": 1}"#).is_err());
+        assert!(parse_event(br#"{"event": ""}"#).is_err());
+    }
+}
@@ -0,0 +1,509 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # Syslog onramp
+//!
+//! Receives syslog messages over UDP (RFC 5426), TCP (RFC 6587) and TLS
+//! (RFC 5425) at the same time. Every message is emitted as its own event,
+//! decoded with the `syslog` codec by default.
+//!
+//! The framing of TCP and TLS connections is detected from the first byte
+//! sent: octet counting frames start with the length of the message, non
+//! transparent frames with the `<` of the message priority and end with a
+//! newline.
+//!
+//! The transport (`udp`, `tcp` or `tls`) and the peer are available as
+//! `$syslog.transport` and `$syslog.peer.{host, port}`.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use crate::source::tcp::{load_server_config, TLSConfig};
+use async_channel::{Sender, TryRecvError};
+use async_std::net::{TcpListener, UdpSocket};
+use async_tls::TlsAcceptor;
+use futures::io::AsyncRead;
+use rustls::ServerConfig;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const BUFFER_SIZE_BYTES: usize = 8192;
+/// the largest message accepted
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// host to listen to, defaults to "0.0.0.0"
+    #[serde(default = "dflt_host")]
+    pub host: String,
+    /// UDP port to listen to, defaults to 514, `null` disables UDP
+    #[serde(default = "dflt_port")]
+    pub udp_port: Option<u16>,
+    /// TCP port to listen to, defaults to 514, `null` disables TCP
+    #[serde(default = "dflt_port")]
+    pub tcp_port: Option<u16>,
+    /// certificate and key to accept TLS connections with
+    pub tls: Option<TLSConfig>,
+    /// TLS port to listen to if `tls` is configured, defaults to 6514
+    #[serde(default = "dflt_tls_port")]
+    pub tls_port: u16,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_host() -> String {
+    String::from("0.0.0.0")
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn dflt_port() -> Option<u16> {
+    Some(514)
+}
+
+fn dflt_tls_port() -> u16 {
+    6514
+}
+
+pub struct Syslog {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for Syslog {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            if config.udp_port.is_none() && config.tcp_port.is_none() && config.tls.is_none() {
+                return Err(
+                    "The syslog onramp needs at least one of udp_port, tcp_port or tls".into(),
+                );
+            }
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for syslog onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for Syslog {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "syslog"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Syslog")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    /// `MSG-LEN SP SYSLOG-MSG`
+    OctetCounting,
+    /// `SYSLOG-MSG LF`
+    NonTransparent,
+}
+
+/// Splits the bytes of a connection into messages as per RFC 6587
+#[derive(Debug, Default)]
+struct Framer {
+    /// the framing of the connection, detected from the first byte
+    framing: Option<Framing>,
+    buffer: Vec<u8>,
+}
+
+impl Framer {
+    /// Adds data read from the connection, returns all messages completed by it
+    fn push(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.buffer.extend_from_slice(data);
+        let framing = if let Some(framing) = self.framing {
+            framing
+        } else {
+            let framing = match self.buffer.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b) if b.is_ascii_digit() => Framing::OctetCounting,
+                Some(_) => Framing::NonTransparent,
+                None => {
+                    self.buffer.clear();
+                    return Ok(Vec::new());
+                }
+            };
+            self.framing = Some(framing);
+            framing
+        };
+        let mut messages = Vec::new();
+        loop {
+            let message = match framing {
+                Framing::OctetCounting => self.next_counted()?,
+                Framing::NonTransparent => self.next_line()?,
+            };
+            if let Some(message) = message {
+                messages.push(message);
+            } else {
+                return Ok(messages);
+            }
+        }
+    }
+
+    /// Returns the incomplete last message once the connection got closed
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        let message = std::mem::take(&mut self.buffer);
+        (self.framing == Some(Framing::NonTransparent)
+            && !message.iter().all(u8::is_ascii_whitespace))
+        .then(|| message)
+    }
+
+    fn next_counted(&mut self) -> Result<Option<Vec<u8>>> {
+        // some senders terminate octet counted frames with a newline as well
+        let start = match self.buffer.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(start) => start,
+            None => {
+                self.buffer.clear();
+                return Ok(None);
+            }
+        };
+        // ALLOW: start is a position in the buffer
+        let len_end = match self.buffer[start..].iter().position(|b| *b == b' ') {
+            Some(len_end) => start + len_end,
+            None if self.buffer.len() - start > 10 => {
+                return Err("Invalid octet counting frame, missing length".into())
+            }
+            None => return Ok(None),
+        };
+        // ALLOW: start and len_end are positions in the buffer
+        let len: usize = std::str::from_utf8(&self.buffer[start..len_end])?
+            .parse()
+            .map_err(|_| Error::from("Invalid octet counting frame, bad length"))?;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(format!("Message of {} bytes exceeds the maximum size", len).into());
+        }
+        let end = len_end + 1 + len;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+        let mut frame: Vec<u8> = self.buffer.drain(..end).collect();
+        Ok(Some(frame.split_off(len_end + 1)))
+    }
+
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let end = match self.buffer.iter().position(|b| *b == b'\n') {
+                Some(end) => end,
+                None if self.buffer.len() > MAX_MESSAGE_SIZE => return Err(line_too_large()),
+                None => return Ok(None),
+            };
+            let mut message: Vec<u8> = self.buffer.drain(..=end).collect();
+            message.pop();
+            if message.last() == Some(&b'\r') {
+                message.pop();
+            }
+            if message.len() > MAX_MESSAGE_SIZE {
+                return Err(line_too_large());
+            }
+            if !message.is_empty() {
+                return Ok(Some(message));
+            }
+        }
+    }
+}
+
+fn line_too_large() -> Error {
+    format!(
+        "Line exceeds the maximum message size of {} bytes",
+        MAX_MESSAGE_SIZE
+    )
+    .into()
+}
+
+fn meta(transport: &'static str, peer: SocketAddr) -> Value<'static> {
+    literal!({
+        "syslog": {
+            "transport": transport,
+            "peer": {
+                "host": peer.ip().to_string(),
+                "port": peer.port()
+            }
+        }
+    })
+}
+
+fn origin_uri(uid: u64, port: u16, peer: SocketAddr) -> EventOriginUri {
+    EventOriginUri {
+        uid,
+        scheme: "tremor-syslog".to_string(),
+        host: peer.ip().to_string(),
+        port: Some(peer.port()),
+        path: vec![port.to_string()], // captures server port
+    }
+}
+
+async fn udp_loop(socket: UdpSocket, tx: Sender<SourceReply>, uid: u64, port: u16) -> Result<()> {
+    let mut buffer = [0; 65535];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buffer).await?;
+        // ALLOW: we get n from recv
+        let mut data = buffer[0..n].to_vec();
+        while matches!(data.last(), Some(b'\n' | b'\r' | b'\0')) {
+            data.pop();
+        }
+        tx.send(SourceReply::Data {
+            origin_uri: origin_uri(uid, port, peer),
+            data,
+            meta: Some(meta("udp", peer)),
+            codec_override: None,
+            stream: 0,
+        })
+        .await?;
+    }
+}
+
+async fn read_loop(
+    mut stream: impl AsyncRead + Unpin,
+    tx: &Sender<SourceReply>,
+    stream_id: usize,
+    origin_uri: &EventOriginUri,
+    meta: &Value<'static>,
+) -> Result<()> {
+    let mut framer = Framer::default();
+    let mut buffer = [0; BUFFER_SIZE_BYTES];
+    loop {
+        let n = stream.read(&mut buffer).await?;
+        let messages = if n == 0 {
+            framer.finish().into_iter().collect()
+        } else {
+            // ALLOW: we define n as part of the read
+            framer.push(&buffer[0..n])?
+        };
+        for data in messages {
+            tx.send(SourceReply::Data {
+                origin_uri: origin_uri.clone(),
+                data,
+                meta: Some(meta.clone()),
+                codec_override: None,
+                stream: stream_id,
+            })
+            .await?;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    server_config: Option<ServerConfig>,
+    tx: Sender<SourceReply>,
+    stream_ids: Arc<AtomicUsize>,
+    uid: u64,
+    port: u16,
+    source_id: String,
+) {
+    let transport = if server_config.is_some() {
+        "tls"
+    } else {
+        "tcp"
+    };
+    while let Ok((stream, peer)) = listener.accept().await {
+        let tx = tx.clone();
+        let source_id = source_id.clone();
+        let stream_id = stream_ids.fetch_add(1, Ordering::Relaxed);
+        let origin_uri = origin_uri(uid, port, peer);
+        let meta = meta(transport, peer);
+        let tls_acceptor = server_config
+            .clone()
+            .map(|s| TlsAcceptor::from(Arc::new(s)));
+        task::spawn(async move {
+            if let Err(e) = tx.send(SourceReply::StartStream(stream_id)).await {
+                error!("[Source::{}] {}", source_id, e);
+                return;
+            }
+            let res = if let Some(acceptor) = tls_acceptor {
+                match acceptor.accept(stream).await {
+                    Ok(tls_stream) => {
+                        read_loop(tls_stream, &tx, stream_id, &origin_uri, &meta).await
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            } else {
+                read_loop(stream, &tx, stream_id, &origin_uri, &meta).await
+            };
+            if let Err(e) = res {
+                warn!(
+                    "[Source::{}] Closing connection from {}: {}",
+                    source_id, peer, e
+                );
+            }
+            if let Err(e) = tx.send(SourceReply::EndStream(stream_id)).await {
+                error!("[Source::{}] {}", source_id, e);
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+        let host = self.config.host.as_str();
+        let uid = self.uid;
+        let source_id = self.onramp_id.to_string();
+        // stream 0 is used for UDP
+        let stream_ids = Arc::new(AtomicUsize::new(1));
+
+        if let Some(port) = self.config.udp_port {
+            let socket = UdpSocket::bind((host, port)).await?;
+            info!(
+                "[Source::{}] Listening on udp://{}:{}",
+                source_id, host, port
+            );
+            let tx = tx.clone();
+            let source_id = source_id.clone();
+            task::spawn(async move {
+                if let Err(e) = udp_loop(socket, tx, uid, port).await {
+                    error!("[Source::{}] UDP error: {}", source_id, e);
+                }
+            });
+        }
+        if let Some(port) = self.config.tcp_port {
+            let listener = TcpListener::bind((host, port)).await?;
+            info!(
+                "[Source::{}] Listening on tcp://{}:{}",
+                source_id, host, port
+            );
+            task::spawn(accept_loop(
+                listener,
+                None,
+                tx.clone(),
+                stream_ids.clone(),
+                uid,
+                port,
+                source_id.clone(),
+            ));
+        }
+        if let Some(tls_config) = self.config.tls.as_ref() {
+            let port = self.config.tls_port;
+            let server_config = load_server_config(tls_config)?;
+            let listener = TcpListener::bind((host, port)).await?;
+            info!(
+                "[Source::{}] Listening on tls://{}:{}",
+                source_id, host, port
+            );
+            task::spawn(accept_loop(
+                listener,
+                Some(server_config),
+                tx,
+                stream_ids,
+                uid,
+                port,
+                source_id,
+            ));
+        }
+        self.listener = Some(rx);
+
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(r) => Ok(r),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn octet_counting() -> Result<()> {
+        let mut framer = Framer::default();
+        assert!(framer.push(b"12 <13>1 - snot")?.is_empty());
+        assert_eq!(
+            vec![b"<13>1 - snot".to_vec(), b"<13>badger".to_vec()],
+            framer.push(b"\n10 <13>badger")?
+        );
+        assert!(framer.push(b"3")?.is_empty());
+        assert_eq!(vec![b"<1>".to_vec()], framer.push(b" <1>")?);
+        assert_eq!(None, framer.finish());
+        assert!(Framer::default().push(b"x1 <13>")?.is_empty());
+        assert!(Framer::default().push(b"1x <13>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn non_transparent() -> Result<()> {
+        let mut framer = Framer::default();
+        assert_eq!(
+            vec![b"<13>snot".to_vec(), b"<13>2 badger".to_vec()],
+            framer.push(b"<13>snot\r\n\n<13>2 badger\n<13>")?
+        );
+        assert_eq!(vec![b"<13>last".to_vec()], framer.push(b"last\n")?);
+        framer.push(b"<13>eof")?;
+        assert_eq!(Some(b"<13>eof".to_vec()), framer.finish());
+        Ok(())
+    }
+
+    #[test]
+    fn non_transparent_max_size() -> Result<()> {
+        let line = vec![b'x'; MAX_MESSAGE_SIZE];
+        let mut framer = Framer::default();
+        assert!(framer.push(&line)?.is_empty());
+        assert_eq!(vec![line.clone()], framer.push(b"\r\n")?);
+        // a line without end can not grow past the maximum size
+        let mut framer = Framer::default();
+        assert!(framer.push(&line)?.is_empty());
+        assert!(framer.push(b"x").is_err());
+        // neither can one that ends in the same read
+        let mut data = line;
+        data.extend_from_slice(b"x\n");
+        assert!(Framer::default().push(&data).is_err());
+        Ok(())
+    }
+}
@@ -14,25 +14,64 @@
 #![cfg(not(tarpaulin_include))]
 
 use crate::errors::{Error, ErrorKind, Result};
+use crate::sink::tcp::{tls_connect, Conn, TLSConfig as ClientTLSConfig};
 use crate::source::prelude::*;
 use async_channel::Sender;
 use async_channel::TryRecvError;
-use async_std::net::TcpListener;
+use async_std::net::{TcpListener, TcpStream};
 use async_tls::TlsAcceptor;
+use either::Either;
 use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 // TODO expose this as config (would have to change buffer to be vector?)
 const BUFFER_SIZE_BYTES: usize = 8192;
 
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// listen on `host` and `port`
+    Server,
+    /// connect to `host` and `port`
+    Client,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Self::Server
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
     pub port: u16,
     pub host: String,
+    /// certificate and key to accept TLS connections with in server mode
     pub tls: Option<TLSConfig>,
+    /// `server` (default) to accept connections or `client` to connect out
+    #[serde(default)]
+    pub mode: Mode,
+    /// TLS settings in client mode, `true` or `cafile` and `domain`
+    #[serde(with = "either::serde_untagged_optional", default = "Default::default")]
+    pub client_tls: Option<Either<ClientTLSConfig, bool>>,
+    /// initial time to wait before reconnecting in client mode, defaults to 1s
+    #[serde(default = "dflt_backoff_ms")]
+    pub backoff_ms: u64,
+    /// maximum time to wait before reconnecting in client mode, defaults to 60s
+    #[serde(default = "dflt_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn dflt_backoff_ms() -> u64 {
+    1_000
+}
+
+fn dflt_max_backoff_ms() -> u64 {
+    60_000
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -106,8 +145,18 @@ impl Source for Int {
     }
 
     async fn init(&mut self) -> Result<SourceState> {
-        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
         let (tx, rx) = bounded(crate::QSIZE);
+        if self.config.mode == Mode::Client {
+            task::spawn(connect_loop(
+                self.config.clone(),
+                tx,
+                self.uid,
+                self.onramp_id.to_string(),
+            ));
+            self.listener = Some(rx);
+            return Ok(SourceState::Connected);
+        }
+        let listener = TcpListener::bind((self.config.host.as_str(), self.config.port)).await?;
         let uid = self.uid;
         let path = vec![self.config.port.to_string()];
 
@@ -183,12 +232,15 @@ async fn read_loop(
     origin_uri: EventOriginUri,
 ) {
     let mut buffer = [0; BUFFER_SIZE_BYTES];
-    while let Ok(n) = stream.read(&mut buffer).await {
-        if n == 0 {
-            if let Err(e) = tx.send(SourceReply::EndStream(stream_id)).await {
-                error!("TCP Error: {}", e);
-            };
-            break;
+    loop {
+        let n = match stream.read(&mut buffer).await {
+            Ok(n) if n > 0 => n,
+            _ => {
+                if let Err(e) = tx.send(SourceReply::EndStream(stream_id)).await {
+                    error!("TCP Error: {}", e);
+                };
+                break;
+            }
         };
         if let Err(e) = tx
             .send(SourceReply::Data {
@@ -207,6 +259,55 @@ async fn read_loop(
     }
 }
 
+type ClientStream = Box<dyn Conn>;
+
+async fn connect(config: &Config) -> Result<ClientStream> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    tls_connect(stream, config.host.as_str(), config.client_tls.as_ref()).await
+}
+
+/// Keeps (re)connecting to the configured server until the onramp is stopped
+async fn connect_loop(config: Config, tx: Sender<SourceReply>, uid: u64, source_id: String) {
+    let min_backoff = Duration::from_millis(config.backoff_ms);
+    let max_backoff = Duration::from_millis(config.max_backoff_ms);
+    let mut backoff = min_backoff;
+    let mut stream_id = 0;
+    let origin_uri = EventOriginUri {
+        uid,
+        scheme: "tremor-tcp".to_string(),
+        host: config.host.clone(),
+        port: Some(config.port),
+        path: vec![],
+    };
+    loop {
+        match connect(&config).await {
+            Ok(stream) => {
+                info!(
+                    "[Source::{}] Connected to {}:{}",
+                    source_id, config.host, config.port
+                );
+                backoff = min_backoff;
+                stream_id += 1;
+                if let Err(e) = tx.send(SourceReply::StartStream(stream_id)).await {
+                    error!("TCP Error: {}", e);
+                    return;
+                }
+                read_loop(stream, tx.clone(), stream_id, origin_uri.clone()).await;
+                warn!("[Source::{}] Connection closed", source_id);
+            }
+            Err(e) => warn!(
+                "[Source::{}] Unable to connect to {}:{}: {}",
+                source_id, config.host, config.port, e
+            ),
+        }
+        if tx.is_closed() {
+            return;
+        }
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
 // Load the passed certificates file
 fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
     let certfile = tremor_common::file::open(path)?;
@@ -257,7 +358,7 @@ fn load_keys(path: &Path) -> Result<PrivateKey> {
     }
 }
 
-fn load_server_config(config: &TLSConfig) -> Result<ServerConfig> {
+pub(crate) fn load_server_config(config: &TLSConfig) -> Result<ServerConfig> {
     let certs = load_certs(&config.cert)?;
     let keys = load_keys(&config.key)?;
 
@@ -15,12 +15,69 @@
 
 use crate::source::prelude::*;
 use async_std::net::UdpSocket;
+use socket2::SockRef;
+use std::net::{IpAddr, Ipv4Addr};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     /// The port to listen on.
     pub port: u16,
     pub host: String,
+    /// multicast group to join
+    pub multicast: Option<Multicast>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Multicast {
+    /// IPv4 or IPv6 address of the group
+    pub group: IpAddr,
+    /// the interface to join the group on, an IPv4 address for IPv4 groups and
+    /// an interface index for IPv6 groups, defaults to any interface
+    pub interface: Option<String>,
+    /// time to live (IPv4) or hop limit (IPv6) of multicast packets sent from the socket
+    pub ttl: Option<u32>,
+    /// if multicast packets sent from this host are received as well
+    pub loopback: Option<bool>,
+}
+
+impl Multicast {
+    /// Joins the group and applies the ttl and loop settings
+    pub(crate) fn join(&self, socket: &UdpSocket) -> Result<()> {
+        match self.group {
+            IpAddr::V4(group) => {
+                let interface = match self.interface.as_ref() {
+                    Some(interface) => interface.parse().map_err(|_| {
+                        Error::from(format!("Invalid IPv4 interface address: {}", interface))
+                    })?,
+                    None => Ipv4Addr::UNSPECIFIED,
+                };
+                socket.join_multicast_v4(group, interface)?;
+                if let Some(ttl) = self.ttl {
+                    socket.set_multicast_ttl_v4(ttl)?;
+                }
+                if let Some(loopback) = self.loopback {
+                    socket.set_multicast_loop_v4(loopback)?;
+                }
+            }
+            IpAddr::V6(group) => {
+                let interface = match self.interface.as_ref() {
+                    Some(interface) => interface.parse().map_err(|_| {
+                        Error::from(format!("Invalid IPv6 interface index: {}", interface))
+                    })?,
+                    None => 0,
+                };
+                socket.join_multicast_v6(&group, interface)?;
+                if let Some(ttl) = self.ttl {
+                    // std only exposes the unicast hop limit for IPv6
+                    SockRef::from(socket).set_multicast_hops_v6(ttl)?;
+                }
+                if let Some(loopback) = self.loopback {
+                    socket.set_multicast_loop_v6(loopback)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl ConfigImpl for Config {}
@@ -59,6 +116,22 @@ impl Int {
         }
     }
 }
+impl Int {
+    async fn bind(&mut self) -> Result<()> {
+        let socket = UdpSocket::bind((self.config.host.as_str(), self.config.port)).await?;
+        info!(
+            "[UDP Onramp] listening on {}:{}",
+            self.config.host, self.config.port
+        );
+        if let Some(multicast) = self.config.multicast.as_ref() {
+            multicast.join(&socket)?;
+            info!("[UDP Onramp] joined multicast group {}", multicast.group);
+        }
+        self.socket = Some(socket);
+        Ok(())
+    }
+}
+
 impl onramp::Impl for Udp {
     fn from_config(onramp_id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
         if let Some(config) = config {
@@ -86,11 +159,17 @@ impl Source for Int {
                     // TODO add a method in origin_uri for changes like this?
                     origin_uri.host = peer.ip().to_string();
                     origin_uri.port = Some(peer.port());
+                    let meta = literal!({
+                        "udp": {
+                            "host": origin_uri.host.clone(),
+                            "port": peer.port()
+                        }
+                    });
                     Ok(SourceReply::Data {
                         origin_uri,
                         // ALLOW: we get n from recv
                         data: buf[0..n].to_vec(),
-                        meta: None,
+                        meta: Some(meta),
                         codec_override: None,
                         stream: 0,
                     })
@@ -104,22 +183,12 @@ impl Source for Int {
                 }
             }
         } else {
-            let socket = UdpSocket::bind((self.config.host.as_str(), self.config.port)).await?;
-            info!(
-                "[UDP Onramp] listening on {}:{}",
-                self.config.host, self.config.port
-            );
-            self.socket = Some(socket);
+            self.bind().await?;
             Ok(SourceReply::StateChange(SourceState::Connected))
         }
     }
     async fn init(&mut self) -> Result<SourceState> {
-        let socket = UdpSocket::bind((self.config.host.as_str(), self.config.port)).await?;
-        info!(
-            "[UDP Onramp] listening on {}:{}",
-            self.config.host, self.config.port
-        );
-        self.socket = Some(socket);
+        self.bind().await?;
         Ok(SourceState::Connected)
     }
     fn id(&self) -> &TremorUrl {
@@ -137,3 +206,53 @@ impl Onramp for Udp {
         "string"
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[async_std::test]
+    async fn multicast() -> Result<()> {
+        let group: Ipv4Addr = "239.255.42.1".parse()?;
+        let multicast = Multicast {
+            group: IpAddr::V4(group),
+            interface: Some("127.0.0.1".to_string()),
+            ttl: Some(3),
+            loopback: Some(true),
+        };
+        let receiver = UdpSocket::bind("0.0.0.0:0").await?;
+        let port = receiver.local_addr()?.port();
+        multicast.join(&receiver)?;
+        assert_eq!(3, receiver.multicast_ttl_v4()?);
+        assert!(receiver.multicast_loop_v4()?);
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await?;
+        SockRef::from(&sender).set_multicast_if_v4(&Ipv4Addr::LOCALHOST)?;
+        sender.send_to(b"snot", (group, port)).await?;
+        let mut buffer = [0; 16];
+        let (n, _) =
+            async_std::future::timeout(Duration::from_secs(5), receiver.recv_from(&mut buffer))
+                .await
+                .map_err(|_| Error::from("Timed out waiting for the datagram"))??;
+        assert_eq!(Some(&b"snot"[..]), buffer.get(..n));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn multicast_hops_v6() -> Result<()> {
+        let multicast = Multicast {
+            group: "ff02::1".parse()?,
+            interface: None,
+            ttl: Some(3),
+            loopback: Some(false),
+        };
+        let socket = UdpSocket::bind("[::]:0").await?;
+        let unicast_hops = SockRef::from(&socket).unicast_hops_v6()?;
+        multicast.join(&socket)?;
+        assert_eq!(3, SockRef::from(&socket).multicast_hops_v6()?);
+        assert_eq!(unicast_hops, SockRef::from(&socket).unicast_hops_v6()?);
+        assert!(!socket.multicast_loop_v6()?);
+        Ok(())
+    }
+}
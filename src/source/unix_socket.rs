@@ -13,13 +13,42 @@
 // limitations under the License.
 use crate::source::prelude::*;
 use async_std::channel::TryRecvError;
-use async_std::os::unix::net::UnixListener;
+use async_std::os::unix::net::{UnixDatagram, UnixListener};
 use smol::stream::StreamExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SocketType {
+    /// a connection oriented `SOCK_STREAM` socket
+    Stream,
+    /// a `SOCK_DGRAM` socket, every datagram is a message
+    Datagram,
+}
+
+impl Default for SocketType {
+    fn default() -> Self {
+        Self::Stream
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Config {
     pub path: String,
+    /// `stream` (default) or `datagram`
+    #[serde(default)]
+    pub socket_type: SocketType,
+    /// octal file permissions of the socket, e.g. "660"
+    pub permissions: Option<String>,
+}
+
+/// Parses octal file permissions like `"0660"`
+pub(crate) fn parse_mode(permissions: &str) -> Result<u32> {
+    u32::from_str_radix(permissions, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("Invalid socket permissions: {}", permissions).into())
 }
 
 impl ConfigImpl for Config {}
@@ -71,6 +100,9 @@ impl onramp::Impl for UnixSocket {
     fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
         if let Some(config) = config {
             let config: Config = Config::new(config)?;
+            if let Some(permissions) = config.permissions.as_ref() {
+                parse_mode(permissions)?;
+            }
             Ok(Box::new(Self {
                 config,
                 onramp_id: id.clone(),
@@ -81,6 +113,57 @@ impl onramp::Impl for UnixSocket {
     }
 }
 
+impl Int {
+    fn set_permissions(&self, path: &std::path::Path) -> Result<()> {
+        if let Some(permissions) = self.config.permissions.as_ref() {
+            let mode = parse_mode(permissions)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    async fn init_datagram(&mut self, path: PathBuf) -> Result<SourceState> {
+        let socket = UnixDatagram::bind(&path).await?;
+        self.set_permissions(&path)?;
+        let (tx, rx) = bounded(crate::QSIZE);
+        let origin_uri = EventOriginUri {
+            uid: self.uid,
+            scheme: "tremor-unix-socket".to_string(),
+            host: String::new(),
+            port: None,
+            path: vec![self.config.path.clone()],
+        };
+        task::spawn(async move {
+            let mut buffer = [0; 65535];
+            loop {
+                match socket.recv(&mut buffer).await {
+                    Ok(n) => {
+                        let reply = SourceReply::Data {
+                            origin_uri: origin_uri.clone(),
+                            // ALLOW: we define n as part of the recv
+                            data: buffer[0..n].to_vec(),
+                            meta: None,
+                            codec_override: None,
+                            stream: 0,
+                        };
+                        if let Err(e) = tx.send(reply).await {
+                            error!("Unix Socket Error: {}", e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Unix socket error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        self.listener = Some(rx);
+
+        Ok(SourceState::Connected)
+    }
+}
+
 #[async_trait::async_trait()]
 impl Source for Int {
     async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
@@ -101,7 +184,11 @@ impl Source for Int {
         if path.exists() {
             std::fs::remove_file(&path)?;
         }
-        let stream = UnixListener::bind(path).await?;
+        if self.config.socket_type == SocketType::Datagram {
+            return self.init_datagram(path).await;
+        }
+        let stream = UnixListener::bind(&path).await?;
+        self.set_permissions(&path)?;
         let mut stream_id = 0;
         let (tx, rx) = bounded(crate::QSIZE);
 
@@ -177,10 +264,85 @@ impl Source for Int {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::onramp::Impl;
-    use crate::source::unix_socket::{Config, Int, UnixSocket};
-    use crate::url::TremorUrl;
+    use async_std::os::unix::net::UnixStream;
     use simd_json::json;
+    use std::time::Duration;
+
+    fn source(path: &std::path::Path, socket_type: &str) -> Result<Int> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "path: {}\nsocket_type: {}",
+            path.display(),
+            socket_type
+        ))?;
+        Ok(Int::from_config(
+            0,
+            TremorUrl::from_onramp_id("unix")?,
+            &config,
+        ))
+    }
+
+    /// Pulls until the source has something to report
+    async fn next(source: &mut Int) -> Result<SourceReply> {
+        for _ in 0..500 {
+            match source.pull_event(0).await? {
+                SourceReply::Empty(_) => task::sleep(Duration::from_millis(10)).await,
+                reply => return Ok(reply),
+            }
+        }
+        Err("Timed out waiting for the source".into())
+    }
+
+    async fn data(source: &mut Int) -> Result<(Vec<u8>, usize)> {
+        match next(source).await? {
+            SourceReply::Data { data, stream, .. } => Ok((data, stream)),
+            _ => Err("Expected data".into()),
+        }
+    }
+
+    #[async_std::test]
+    async fn stream() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("stream.sock");
+        let mut source = source(&path, "stream")?;
+        assert!(matches!(source.init().await?, SourceState::Connected));
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::StartStream(0)
+        ));
+
+        let mut client = UnixStream::connect(&path).await?;
+        client.write_all(b"snot").await?;
+        assert_eq!((b"snot".to_vec(), 0), data(&mut source).await?);
+
+        // the peer going away ends its stream, the next one gets a new stream
+        drop(client);
+        assert!(matches!(
+            next(&mut source).await?,
+            SourceReply::EndStream(0)
+        ));
+        let mut client = UnixStream::connect(&path).await?;
+        client.write_all(b"badger").await?;
+        assert_eq!((b"badger".to_vec(), 1), data(&mut source).await?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn datagram() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("datagram.sock");
+        let mut source = source(&path, "datagram")?;
+        assert!(matches!(source.init().await?, SourceState::Connected));
+
+        // every datagram is a message, whoever sends it
+        for msg in [&b"snot"[..], b"badger"] {
+            let client = UnixDatagram::unbound()?;
+            client.send_to(msg, &path).await?;
+            assert_eq!((msg.to_vec(), 0), data(&mut source).await?);
+        }
+        Ok(())
+    }
 
     #[test]
     pub fn default_codec_is_json() {
@@ -203,9 +365,18 @@ mod tests {
             TremorUrl::from_onramp_id("test").unwrap(),
             &Config {
                 path: "/tmp/test.sock".to_string(),
+                ..Config::default()
             },
         );
 
         assert_eq!("UnixSocket:/tmp/test.sock", format!("{:?}", int));
     }
+
+    #[test]
+    pub fn socket_permissions() {
+        assert_eq!(0o660, parse_mode("660").unwrap());
+        assert_eq!(0o1777, parse_mode("01777").unwrap());
+        assert!(parse_mode("rw-rw----").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
 }
@@ -0,0 +1,288 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(not(tarpaulin_include))]
+
+//! # WebSocket client onramp
+//!
+//! Connects to a `ws://` or `wss://` endpoint and emits every received text
+//! or binary message as an event, `$binary` tells which one it was.
+//!
+//! The configured `subscribe` messages are sent as text messages after every
+//! (re)connect. Lost connections are reestablished with an exponential
+//! backoff between `backoff_ms` and `max_backoff_ms`.
+//!
+//! See [Config](struct.Config.html) for details.
+
+use crate::source::prelude::*;
+use async_channel::{Sender, TryRecvError};
+use async_tungstenite::async_std::{connect_async, ConnectStream};
+use async_tungstenite::tungstenite::client::IntoClientRequest;
+use async_tungstenite::tungstenite::handshake::client::Request;
+use async_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use futures::{SinkExt, StreamExt};
+use halfbrown::HashMap;
+use std::time::Duration;
+use url::Url;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Config {
+    /// `ws://` or `wss://` url to connect to
+    pub url: String,
+    /// additional headers of the handshake request, e.g. for authentication
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// text messages to send after connecting, e.g. to subscribe to a feed
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+    /// initial time to wait before reconnecting, defaults to 1s
+    #[serde(default = "dflt_backoff_ms")]
+    pub backoff_ms: u64,
+    /// maximum time to wait before reconnecting, defaults to 60s
+    #[serde(default = "dflt_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl ConfigImpl for Config {}
+
+fn dflt_backoff_ms() -> u64 {
+    1_000
+}
+
+fn dflt_max_backoff_ms() -> u64 {
+    60_000
+}
+
+pub struct WsClient {
+    pub config: Config,
+    onramp_id: TremorUrl,
+}
+
+impl onramp::Impl for WsClient {
+    fn from_config(id: &TremorUrl, config: &Option<YamlValue>) -> Result<Box<dyn Onramp>> {
+        if let Some(config) = config {
+            let config: Config = Config::new(config)?;
+            let url = Url::parse(&config.url)?;
+            if url.scheme() != "ws" && url.scheme() != "wss" {
+                return Err(format!("Invalid websocket url: {}", config.url).into());
+            }
+            Ok(Box::new(Self {
+                config,
+                onramp_id: id.clone(),
+            }))
+        } else {
+            Err("Missing config for ws-client onramp".into())
+        }
+    }
+}
+
+#[async_trait::async_trait()]
+impl Onramp for WsClient {
+    async fn start(&mut self, config: OnrampConfig<'_>) -> Result<onramp::Addr> {
+        let source = Int {
+            uid: config.onramp_uid,
+            config: self.config.clone(),
+            onramp_id: self.onramp_id.clone(),
+            listener: None,
+        };
+        SourceManager::start(source, config).await
+    }
+
+    fn default_codec(&self) -> &str {
+        "json"
+    }
+}
+
+pub struct Int {
+    uid: u64,
+    config: Config,
+    onramp_id: TremorUrl,
+    listener: Option<Receiver<SourceReply>>,
+}
+
+impl std::fmt::Debug for Int {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WsClient")
+    }
+}
+
+/// The handshake request including the configured headers
+fn request(config: &Config) -> Result<Request> {
+    let mut request = config.url.as_str().into_client_request()?;
+    for (name, value) in &config.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| Error::from(format!("Invalid header name {}: {}", name, e)))?;
+        request
+            .headers_mut()
+            .insert(name, HeaderValue::from_str(value)?);
+    }
+    Ok(request)
+}
+
+/// Reads messages until the connection is closed
+async fn serve(
+    mut ws_stream: WebSocketStream<ConnectStream>,
+    config: &Config,
+    tx: &Sender<SourceReply>,
+    stream: usize,
+    origin_uri: &EventOriginUri,
+) -> Result<()> {
+    for msg in &config.subscribe {
+        ws_stream.send(Message::Text(msg.clone())).await?;
+    }
+    tx.send(SourceReply::StartStream(stream)).await?;
+    let mut res = Ok(());
+    while let Some(msg) = ws_stream.next().await {
+        let (data, binary) = match msg {
+            Ok(Message::Text(t)) => (t.into_bytes(), false),
+            Ok(Message::Binary(data)) => (data, true),
+            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+            Ok(Message::Close(_)) => break,
+            Err(e) => {
+                res = Err(e.into());
+                break;
+            }
+        };
+        tx.send(SourceReply::Data {
+            origin_uri: origin_uri.clone(),
+            data,
+            meta: Some(literal!({ "binary": binary })),
+            codec_override: None,
+            stream,
+        })
+        .await?;
+    }
+    tx.send(SourceReply::EndStream(stream)).await?;
+    res
+}
+
+/// Keeps (re)connecting until the source is stopped
+async fn run(
+    config: Config,
+    tx: Sender<SourceReply>,
+    origin_uri: EventOriginUri,
+    source_id: String,
+) {
+    let min_backoff = Duration::from_millis(config.backoff_ms);
+    let max_backoff = Duration::from_millis(config.max_backoff_ms);
+    let mut backoff = min_backoff;
+    let mut stream = 0;
+    loop {
+        let connection = match request(&config) {
+            Ok(request) => connect_async(request).await.map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        match connection {
+            Ok((ws_stream, _)) => {
+                info!("[Source::{}] Connected to {}", source_id, config.url);
+                backoff = min_backoff;
+                stream += 1;
+                if let Err(e) = serve(ws_stream, &config, &tx, stream, &origin_uri).await {
+                    warn!("[Source::{}] Connection closed: {}", source_id, e);
+                }
+            }
+            Err(e) => warn!(
+                "[Source::{}] Unable to connect to {}: {}",
+                source_id, config.url, e
+            ),
+        }
+        if tx.is_closed() {
+            return;
+        }
+        info!(
+            "[Source::{}] Reconnecting in {}ms",
+            source_id,
+            backoff.as_millis()
+        );
+        task::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+#[async_trait::async_trait()]
+impl Source for Int {
+    fn id(&self) -> &TremorUrl {
+        &self.onramp_id
+    }
+
+    async fn init(&mut self) -> Result<SourceState> {
+        let (tx, rx) = bounded(crate::QSIZE);
+        let url = Url::parse(&self.config.url)?;
+        let origin_uri = EventOriginUri {
+            uid: self.uid,
+            scheme: "tremor-ws-client".to_string(),
+            host: url.host_str().unwrap_or_default().to_string(),
+            port: url.port_or_known_default(),
+            path: url
+                .path_segments()
+                .map(|s| s.map(String::from).collect())
+                .unwrap_or_default(),
+        };
+        task::spawn(run(
+            self.config.clone(),
+            tx,
+            origin_uri,
+            self.onramp_id.to_string(),
+        ));
+        self.listener = Some(rx);
+
+        Ok(SourceState::Connected)
+    }
+
+    async fn pull_event(&mut self, _id: u64) -> Result<SourceReply> {
+        self.listener.as_ref().map_or_else(
+            || Ok(SourceReply::StateChange(SourceState::Disconnected)),
+            |listener| match listener.try_recv() {
+                Ok(r) => Ok(r),
+                Err(TryRecvError::Empty) => Ok(SourceReply::Empty(10)),
+                Err(TryRecvError::Closed) => {
+                    Ok(SourceReply::StateChange(SourceState::Disconnected))
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn handshake_request() -> Result<()> {
+        let mut headers = HashMap::new();
+        headers.insert("Authorization".to_string(), "Bearer snot".to_string());
+        let config = Config {
+            url: "wss://example.com/feed".to_string(),
+            headers,
+            subscribe: vec![],
+            backoff_ms: dflt_backoff_ms(),
+            max_backoff_ms: dflt_max_backoff_ms(),
+        };
+        let req = request(&config)?;
+        assert_eq!("wss://example.com/feed", req.uri().to_string());
+        assert_eq!(
+            Some("Bearer snot"),
+            req.headers()
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+        );
+
+        let mut config = config;
+        config.headers.insert("in valid".to_string(), String::new());
+        assert!(request(&config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright 2020-2021, The Tremor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Aggregates metrics decoded by the `statsd` codec the way a statsd daemon
+//! does and emits them every `interval` as influx style records:
+//!
+//! * counters (`c`) are summed up, scaled by their sample rate, and reported
+//!   as `count` and per second `rate`
+//! * gauges (`g`) report their last `value`, `+`/`-` values change it
+//! * timers (`ms`) and histograms (`h`) report `count`, `min`, `max`, `sum`,
+//!   `mean` and the configured percentiles, e.g. `p90`
+//! * sets (`s`) report the `count` of distinct values
+//!
+//! The statsd type is available as the `type` tag. Events that are not statsd
+//! metrics are sent to the `err` port.
+
+use crate::{op::prelude::*, EventIdGenerator};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use tremor_script::prelude::*;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Config {
+    /// The flush interval in milliseconds, defaults to 10s
+    #[serde(default = "dflt_interval")]
+    pub interval: u64,
+    /// The percentiles reported for timers and histograms
+    #[serde(default = "dflt_percentiles")]
+    pub percentiles: Vec<f64>,
+    /// Only report gauges that got updated since the last flush
+    #[serde(default)]
+    pub delete_gauges: bool,
+}
+
+fn dflt_interval() -> u64 {
+    10_000
+}
+
+fn dflt_percentiles() -> Vec<f64> {
+    vec![50.0, 90.0, 95.0, 99.0]
+}
+
+impl ConfigImpl for Config {}
+
+#[derive(Debug, Clone, Default)]
+struct Timer {
+    values: Vec<f64>,
+    /// number of measurements, scaled by their sample rate
+    count: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct StatsD {
+    pub config: Config,
+    interval_ns: u64,
+    last_flush_ns: u64,
+    counters: BTreeMap<String, f64>,
+    gauges: BTreeMap<String, f64>,
+    timers: BTreeMap<String, Timer>,
+    histograms: BTreeMap<String, Timer>,
+    sets: BTreeMap<String, BTreeSet<String>>,
+    event_id_gen: EventIdGenerator,
+}
+
+op!(StatsDFactory(uid, node) {
+if let Some(map) = &node.config {
+    let config: Config = Config::new(map)?;
+    Ok(Box::new(StatsD::new(uid, config)))
+} else {
+    Ok(Box::new(StatsD::new(uid, Config {
+        interval: dflt_interval(),
+        percentiles: dflt_percentiles(),
+        delete_gauges: false,
+    })))
+}});
+
+fn record(
+    metric: &str,
+    kind: &'static str,
+    fields: Value<'static>,
+    timestamp: u64,
+) -> Value<'static> {
+    literal!({
+        "measurement": metric.to_string(),
+        "tags": {
+            "type": kind
+        },
+        "fields": fields,
+        "timestamp": timestamp
+    })
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn timer_fields(timer: &mut Timer, percentiles: &[f64]) -> Value<'static> {
+    let values = &mut timer.values;
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let n = values.len();
+    let sum: f64 = values.iter().sum();
+    let mut fields = literal!({
+        "count": timer.count,
+        "min": values.first().copied(),
+        "max": values.last().copied(),
+        "sum": sum,
+        "mean": sum / n as f64
+    });
+    for p in percentiles {
+        // nearest rank
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let rank = ((p / 100.0 * n as f64).ceil() as usize).max(1).min(n);
+        fields.try_insert(format!("p{}", p), values.get(rank - 1).copied());
+    }
+    fields
+}
+
+impl StatsD {
+    fn new(uid: u64, config: Config) -> Self {
+        Self {
+            interval_ns: config.interval * 1_000_000,
+            config,
+            last_flush_ns: 0,
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            histograms: BTreeMap::new(),
+            sets: BTreeMap::new(),
+            event_id_gen: EventIdGenerator::new(uid),
+        }
+    }
+
+    /// Adds a metric, returns false if it isn't a valid statsd metric
+    fn add(&mut self, metric: &Value) -> bool {
+        let (name, kind, value) = match (
+            metric.get_str("metric"),
+            metric.get_str("type"),
+            metric.get("value").and_then(|v| v.cast_f64()),
+        ) {
+            (Some(name), Some(kind), Some(value)) => (name.to_string(), kind, value),
+            _ => return false,
+        };
+        let sample_rate = metric
+            .get("sample_rate")
+            .and_then(|v| v.cast_f64())
+            .filter(|rate| *rate > 0.0)
+            .unwrap_or(1.0);
+        match kind {
+            "c" => *self.counters.entry(name).or_default() += value / sample_rate,
+            "g" => {
+                let gauge = self.gauges.entry(name).or_default();
+                // the codec decodes `-3` as `sub` with a value of `3`
+                match metric.get_str("action") {
+                    Some("add") => *gauge += value,
+                    Some("sub") => *gauge -= value,
+                    _ => *gauge = value,
+                }
+            }
+            "ms" | "h" => {
+                let timers = if kind == "ms" {
+                    &mut self.timers
+                } else {
+                    &mut self.histograms
+                };
+                let timer = timers.entry(name).or_default();
+                timer.values.push(value);
+                timer.count += 1.0 / sample_rate;
+            }
+            "s" => {
+                let value = metric.get("value").map(|v| v.encode()).unwrap_or_default();
+                self.sets.entry(name).or_default().insert(value);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Creates the records for all metrics and resets them
+    #[allow(clippy::cast_precision_loss)]
+    fn flush(&mut self, ingest_ns: u64) -> Vec<(Cow<'static, str>, Event)> {
+        let secs = self.config.interval as f64 / 1000.0;
+        let mut records = Vec::new();
+        for (name, count) in std::mem::take(&mut self.counters) {
+            let fields = literal!({
+                "count": count,
+                "rate": count / secs
+            });
+            records.push(record(&name, "c", fields, ingest_ns));
+        }
+        for (name, value) in &self.gauges {
+            records.push(record(name, "g", literal!({ "value": *value }), ingest_ns));
+        }
+        if self.config.delete_gauges {
+            self.gauges.clear();
+        }
+        for (kind, timers) in [("ms", &mut self.timers), ("h", &mut self.histograms)] {
+            for (name, mut timer) in std::mem::take(timers) {
+                let fields = timer_fields(&mut timer, &self.config.percentiles);
+                records.push(record(&name, kind, fields, ingest_ns));
+            }
+        }
+        for (name, set) in std::mem::take(&mut self.sets) {
+            let fields = literal!({ "count": set.len() });
+            records.push(record(&name, "s", fields, ingest_ns));
+        }
+        records
+            .into_iter()
+            .map(|data| {
+                let event = Event {
+                    id: self.event_id_gen.next_id(),
+                    data: data.into(),
+                    ingest_ns,
+                    ..Event::default()
+                };
+                (OUT, event)
+            })
+            .collect()
+    }
+
+    fn maybe_flush(&mut self, ingest_ns: u64) -> Vec<(Cow<'static, str>, Event)> {
+        if self.last_flush_ns == 0 {
+            self.last_flush_ns = ingest_ns;
+        }
+        if ingest_ns.saturating_sub(self.last_flush_ns) >= self.interval_ns {
+            self.last_flush_ns = ingest_ns;
+            self.flush(ingest_ns)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl Operator for StatsD {
+    fn on_event(
+        &mut self,
+        _uid: u64,
+        _port: &str,
+        _state: &mut Value<'static>,
+        event: Event,
+    ) -> Result<EventAndInsights> {
+        let mut valid = true;
+        for metric in event.value_iter() {
+            valid &= self.add(metric);
+        }
+        let mut events = self.maybe_flush(event.ingest_ns);
+        if !valid {
+            events.push((ERR, event));
+        }
+        Ok(events.into())
+    }
+
+    fn handles_signal(&self) -> bool {
+        true
+    }
+
+    fn on_signal(
+        &mut self,
+        _uid: u64,
+        _state: &mut Value<'static>,
+        signal: &mut Event,
+    ) -> Result<EventAndInsights> {
+        Ok(self.maybe_flush(signal.ingest_ns).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(ingest_ns: u64, data: Value<'static>) -> Event {
+        Event {
+            ingest_ns,
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    fn records(r: &EventAndInsights) -> Vec<Value<'static>> {
+        r.events
+            .iter()
+            .map(|(_, e)| e.data.suffix().value().clone_static())
+            .collect()
+    }
+
+    /// metrics as decoded by the `statsd` codec
+    #[test]
+    fn aggregate() -> Result<()> {
+        let mut op = StatsD::new(
+            0,
+            Config {
+                interval: 1000,
+                percentiles: vec![50.0, 90.0],
+                delete_gauges: false,
+            },
+        );
+        let mut state = Value::null();
+        let metrics = vec![
+            // hits:1|c
+            literal!({"metric": "hits", "type": "c", "value": 1}),
+            // hits:2|c|@0.5
+            literal!({"metric": "hits", "type": "c", "value": 2, "sample_rate": 0.5}),
+            // temp:10|g
+            literal!({"metric": "temp", "type": "g", "value": 10}),
+            // temp:-3|g
+            literal!({"metric": "temp", "type": "g", "value": 3, "action": "sub"}),
+            // temp:+1|g
+            literal!({"metric": "temp", "type": "g", "value": 1, "action": "add"}),
+            // users:1|s
+            literal!({"metric": "users", "type": "s", "value": 1}),
+            literal!({"metric": "users", "type": "s", "value": 1}),
+            literal!({"metric": "users", "type": "s", "value": 2}),
+        ];
+        for (ingest_ns, metric) in (1..).zip(metrics) {
+            let r = op.on_event(0, "in", &mut state, event(ingest_ns, metric))?;
+            assert_eq!(0, r.len());
+        }
+        for v in 1..=10 {
+            // req:<v>|ms
+            let metric = literal!({"metric": "req", "type": "ms", "value": v});
+            op.on_event(0, "in", &mut state, event(100, metric))?;
+        }
+
+        let mut signal = event(1_000_000_001, Value::null());
+        let r = op.on_signal(0, &mut state, &mut signal)?;
+        assert_eq!(
+            vec![
+                record(
+                    "hits",
+                    "c",
+                    literal!({"count": 5.0, "rate": 5.0}),
+                    1_000_000_001
+                ),
+                record("temp", "g", literal!({"value": 8.0}), 1_000_000_001),
+                record(
+                    "req",
+                    "ms",
+                    literal!({
+                        "count": 10.0,
+                        "min": 1.0,
+                        "max": 10.0,
+                        "sum": 55.0,
+                        "mean": 5.5,
+                        "p50": 5.0,
+                        "p90": 9.0
+                    }),
+                    1_000_000_001
+                ),
+                record("users", "s", literal!({"count": 2_u64}), 1_000_000_001),
+            ],
+            records(&r)
+        );
+
+        // gauges are kept and keep changing, everything else is reset
+        let metric = literal!({"metric": "temp", "type": "g", "value": 2, "action": "sub"});
+        op.on_event(0, "in", &mut state, event(1_500_000_000, metric))?;
+        let mut signal = event(2_000_000_001, Value::null());
+        let r = op.on_signal(0, &mut state, &mut signal)?;
+        assert_eq!(
+            vec![record("temp", "g", literal!({"value": 6.0}), 2_000_000_001)],
+            records(&r)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn delete_gauges() -> Result<()> {
+        let mut op = StatsD::new(
+            0,
+            Config {
+                interval: 1000,
+                percentiles: dflt_percentiles(),
+                delete_gauges: true,
+            },
+        );
+        let mut state = Value::null();
+        let metric = literal!({"metric": "temp", "type": "g", "value": 10});
+        op.on_event(0, "in", &mut state, event(1, metric))?;
+        let mut signal = event(1_000_000_001, Value::null());
+        let r = op.on_signal(0, &mut state, &mut signal)?;
+        assert_eq!(
+            vec![record(
+                "temp",
+                "g",
+                literal!({"value": 10.0}),
+                1_000_000_001
+            )],
+            records(&r)
+        );
+        // not updated since the last flush
+        let mut signal = event(2_000_000_001, Value::null());
+        let r = op.on_signal(0, &mut state, &mut signal)?;
+        assert_eq!(0, r.len());
+        // a delta starts from zero again
+        let metric = literal!({"metric": "temp", "type": "g", "value": 3, "action": "sub"});
+        op.on_event(0, "in", &mut state, event(2_500_000_000, metric))?;
+        let mut signal = event(3_000_000_001, Value::null());
+        let r = op.on_signal(0, &mut state, &mut signal)?;
+        assert_eq!(
+            vec![record(
+                "temp",
+                "g",
+                literal!({"value": -3.0}),
+                3_000_000_001
+            )],
+            records(&r)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid() -> Result<()> {
+        let mut op = StatsD::new(
+            0,
+            Config {
+                interval: 1000,
+                percentiles: dflt_percentiles(),
+                delete_gauges: false,
+            },
+        );
+        let mut state = Value::null();
+        let r = op.on_event(0, "in", &mut state, event(1, literal!("snot")))?;
+        assert_eq!("err", r.events[0].0);
+        Ok(())
+    }
+}